@@ -0,0 +1,204 @@
+//! `#[derive(VertInfo)]` for Flint; use it through `flint::VertInfo`.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use std::collections::HashMap;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, Lit, Meta, NestedMeta};
+
+/// Implements `flint::vertex::VertInfo` for a struct with named fields, as one binding read per
+/// vertex.
+///
+/// Each field is an attribute, with locations assigned in declaration order. Formats come from
+/// the field type's `flint::vertex::VertexFormat` impl. Fields take these options:
+///
+/// - `#[vertex(location = 3)]` places the field at location 3. Later fields follow on from it.
+/// - `#[vertex(format = "R8G8B8A8_UINT")]` overrides the format with a `vk::Format` constant.
+/// - `#[vertex(skip)]` leaves the field out, e.g. for padding.
+#[proc_macro_derive(VertInfo, attributes(vertex))]
+pub fn derive_vert_info(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+#[derive(Default)]
+struct FieldOpts {
+    location: Option<u32>,
+    format: Option<Ident>,
+    skip: bool,
+}
+
+fn field_opts(attrs: &[syn::Attribute]) -> syn::Result<FieldOpts> {
+    let mut opts = FieldOpts::default();
+    for attr in attrs.iter().filter(|a| a.path.is_ident("vertex")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new_spanned(meta, "expected #[vertex(...)]")),
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("skip") => opts.skip = true,
+                NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.path.is_ident("location") => {
+                    opts.location = Some(match &nv.lit {
+                        Lit::Int(i) => i.base10_parse()?,
+                        lit => return Err(Error::new_spanned(lit, "expected an integer")),
+                    })
+                }
+                NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.path.is_ident("format") => {
+                    opts.format = Some(match &nv.lit {
+                        Lit::Str(s) => {
+                            let mut format = syn::parse_str::<Ident>(&s.value()).map_err(|_| {
+                                Error::new_spanned(s, "expected a vk::Format constant name")
+                            })?;
+                            format.set_span(s.span());
+                            format
+                        }
+                        lit => return Err(Error::new_spanned(lit, "expected a format name")),
+                    })
+                }
+                other => {
+                    return Err(Error::new_spanned(
+                        other,
+                        "expected `location = N`, `format = \"NAME\"` or `skip`",
+                    ))
+                }
+            }
+        }
+    }
+    Ok(opts)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "VertInfo needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                Span::call_site(),
+                "VertInfo can only be derived for structs",
+            ))
+        }
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "VertInfo can't be derived for generic structs",
+        ));
+    }
+
+    let mut attrs = Vec::new();
+    let mut used = HashMap::new();
+    let mut location = 0u32;
+    for field in fields {
+        let opts = field_opts(&field.attrs)?;
+        if opts.skip {
+            continue;
+        }
+        if let Some(l) = opts.location {
+            location = l;
+        }
+        let name = field.ident.as_ref().unwrap();
+        if let Some(other) = used.insert(location, name) {
+            return Err(Error::new_spanned(
+                name,
+                format!("location {} is already used by `{}`", location, other),
+            ));
+        }
+        let ty = &field.ty;
+        let format = match opts.format {
+            Some(format) => quote!(::flint::ash::vk::Format::#format),
+            None => quote!(<#ty as ::flint::vertex::VertexFormat>::FORMAT),
+        };
+        attrs.push(quote! {
+            ::flint::ash::vk::VertexInputAttributeDescription {
+                location: #location,
+                binding: 0,
+                format: #format,
+                offset: ::flint::offset_of!(Self, #name) as u32,
+            }
+        });
+        location += 1;
+    }
+
+    let ident = &input.ident;
+    Ok(quote! {
+        impl ::flint::vertex::VertInfo for #ident {
+            fn bind_descs() -> Vec<::flint::ash::vk::VertexInputBindingDescription> {
+                vec![::flint::ash::vk::VertexInputBindingDescription {
+                    binding: 0,
+                    stride: ::std::mem::size_of::<Self>() as u32,
+                    input_rate: ::flint::ash::vk::VertexInputRate::VERTEX,
+                }]
+            }
+
+            fn attr_descs() -> Vec<::flint::ash::vk::VertexInputAttributeDescription> {
+                vec![#(#attrs),*]
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_str(input: &str) -> syn::Result<String> {
+        expand(syn::parse_str(input).unwrap()).map(|tokens| tokens.to_string())
+    }
+
+    #[test]
+    fn locations_follow_declaration_order() {
+        let out = expand_str(
+            "struct V {
+                pos: [f32; 3],
+                #[vertex(skip)]
+                _pad: f32,
+                #[vertex(location = 4, format = \"R8G8B8A8_UNORM\")]
+                color: u32,
+                uv: [f32; 2],
+            }",
+        )
+        .unwrap();
+        let attr = |location: u32, format: TokenStream2, name: &str| {
+            let name = Ident::new(name, Span::call_site());
+            quote! {
+                ::flint::ash::vk::VertexInputAttributeDescription {
+                    location: #location,
+                    binding: 0,
+                    format: #format,
+                    offset: ::flint::offset_of!(Self, #name) as u32,
+                }
+            }
+            .to_string()
+        };
+        let field_format =
+            |ty: TokenStream2| quote!(<#ty as ::flint::vertex::VertexFormat>::FORMAT);
+        assert!(out.contains(&attr(0, field_format(quote!([f32; 3])), "pos")));
+        assert!(out.contains(&attr(
+            4,
+            quote!(::flint::ash::vk::Format::R8G8B8A8_UNORM),
+            "color"
+        )));
+        assert!(out.contains(&attr(5, field_format(quote!([f32; 2])), "uv")));
+        assert!(!out.contains("_pad"));
+    }
+
+    #[test]
+    fn bad_formats_and_duplicate_locations_are_errors() {
+        assert!(expand_str("struct V { #[vertex(format = \"R8 G8\")] a: u32 }").is_err());
+        assert!(expand_str("struct V { #[vertex(format = \"\")] a: u32 }").is_err());
+        let err = expand_str("struct V { a: u32, #[vertex(location = 0)] b: u32 }").unwrap_err();
+        assert_eq!(err.to_string(), "location 0 is already used by `a`");
+    }
+}
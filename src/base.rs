@@ -1,14 +1,19 @@
+use crate::debug::{DebugName, DebugNamer, DebugToken};
+use crate::sync::FrameSync;
 use crate::*;
-use ash::extensions::khr::Swapchain;
-use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0};
+use ash::version::DeviceV1_0;
 use ash::{vk, Device, Entry, Instance};
 
-use std::default::Default;
 use std::ffi::CString;
 use std::ops::Drop;
+use std::path::PathBuf;
 
+pub mod builder;
+pub mod device;
 pub mod surface;
 pub mod swapchain;
+pub use builder::*;
+pub use device::*;
 pub use surface::*;
 pub use swapchain::*;
 
@@ -17,152 +22,112 @@ pub struct VkData {
     pub instance: Instance,
     pub device: Device,
 
-    pub debug_report_loader: DebugReport,
-    pub debug_call_back: vk::DebugReportCallbackEXT,
+    pub debug: DebugToken,
+    /// Attaches debug-utils names and labels; does nothing when the extension isn't enabled.
+    pub namer: DebugNamer,
 
     pub pdevice: vk::PhysicalDevice,
     pub device_memory_properties: vk::PhysicalDeviceMemoryProperties,
     pub queue_family_index: u32,
     pub present_queue: vk::Queue,
 
-    pub present_complete_semaphore: vk::Semaphore,
-    pub rendering_complete_semaphore: vk::Semaphore,
+    /// How many frames `frame_sync` lets the CPU record ahead of the GPU.
+    pub frames_in_flight: usize,
+
+    pub enabled_layers: Vec<CString>,
+    pub enabled_instance_extensions: Vec<CString>,
+    pub enabled_device_extensions: Vec<CString>,
+    pub enabled_features: vk::PhysicalDeviceFeatures,
+
+    pub device_properties: vk::PhysicalDeviceProperties,
+    /// Everything the device supports, not just what was enabled.
+    pub device_features: vk::PhysicalDeviceFeatures,
+
+    /// Shared by every pipeline made through the `SwapToken`.
+    pub pipeline_cache: vk::PipelineCache,
+    /// Where `save_pipeline_cache` writes to; see `VkDataBuilder::pipeline_cache`.
+    pub pipeline_cache_path: Option<PathBuf>,
 }
 
 impl VkData {
-    pub fn new(name: &str, window: &winit::Window) -> (Self, SurfToken, SwapToken) {
-        unsafe {
-            let entry = Entry::new().unwrap();
-            let app_name = CString::new(name).unwrap();
-
-            let layer_names = [CString::new("VK_LAYER_LUNARG_standard_validation").unwrap()];
-            let layers_names_raw: Vec<*const i8> = layer_names
-                .iter()
-                .map(|raw_name| raw_name.as_ptr())
-                .collect();
-
-            let extension_names_raw = extension_names();
-
-            let appinfo = vk::ApplicationInfo::builder()
-                .application_name(&app_name)
-                .application_version(0)
-                .engine_name(&app_name)
-                .engine_version(0)
-                .api_version(ash::vk_make_version!(1, 1, 100));
-
-            let create_info = vk::InstanceCreateInfo::builder()
-                .application_info(&appinfo)
-                .enabled_layer_names(&layers_names_raw)
-                .enabled_extension_names(&extension_names_raw);
-
-            let instance: Instance = entry
-                .create_instance(&create_info, None)
-                .expect("Instance creation error");
-
-            let debug_info = vk::DebugReportCallbackCreateInfoEXT::builder()
-                .flags(
-                    vk::DebugReportFlagsEXT::ERROR
-                        | vk::DebugReportFlagsEXT::WARNING
-                        | vk::DebugReportFlagsEXT::PERFORMANCE_WARNING
-                        //| vk::DebugReportFlagsEXT::INFORMATION,
-                )
-                .pfn_callback(Some(vulkan_debug_callback));
-
-            let debug_report_loader = DebugReport::new(&entry, &instance);
-            let debug_call_back = debug_report_loader
-                .create_debug_report_callback(&debug_info, None)
-                .unwrap();
-            let pdevices = instance
-                .enumerate_physical_devices()
-                .expect("Physical device error");
-            let surface = SurfToken::new(&entry, &instance, &window);
-            let (pdevice, queue_family_index) = pdevices
-                .iter()
-                .map(|pdevice| {
-                    instance
-                        .get_physical_device_queue_family_properties(*pdevice)
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(index, ref info)| {
-                            let supports_graphic_and_surface =
-                                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                                    && surface.loader.get_physical_device_surface_support(
-                                        *pdevice,
-                                        index as u32,
-                                        surface.surface,
-                                    );
-                            if supports_graphic_and_surface {
-                                Some((*pdevice, index))
-                            } else {
-                                None
-                            }
-                        })
-                        .nth(0)
-                })
-                .filter_map(|v| v)
-                .nth(0)
-                .expect("Couldn't find suitable device.");
-            let queue_family_index = queue_family_index as u32;
-            let device_extension_names_raw = [Swapchain::name().as_ptr()];
-            let features = vk::PhysicalDeviceFeatures {
-                shader_clip_distance: 1,
-                ..Default::default()
-            };
-            let priorities = [1.0];
-
-            let device: Device = {
-                let queue_info = [vk::DeviceQueueCreateInfo::builder()
-                    .queue_family_index(queue_family_index)
-                    .queue_priorities(&priorities)
-                    .build()];
-
-                let device_create_info = vk::DeviceCreateInfo::builder()
-                    .queue_create_infos(&queue_info)
-                    .enabled_extension_names(&device_extension_names_raw)
-                    .enabled_features(&features);
-                instance
-                    .create_device(pdevice, &device_create_info, None)
-                    .unwrap()
-            };
-            let present_queue = device.get_device_queue(queue_family_index as u32, 0);
-
-            let device_memory_properties = instance.get_physical_device_memory_properties(pdevice);
-            let swapchain = SwapToken::new(
-                &instance,
-                device.clone(),
-                &surface,
-                pdevice,
-                queue_family_index,
-                present_queue,
-                &device_memory_properties,
-                window,
-            );
-
-            let semaphore_create_info = vk::SemaphoreCreateInfo::default();
-
-            let present_complete_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-            let rendering_complete_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-            (
-                VkData {
-                    entry,
-                    instance,
-                    device,
-                    queue_family_index,
-                    pdevice,
-                    device_memory_properties,
-                    present_queue,
-                    present_complete_semaphore,
-                    rendering_complete_semaphore,
-                    debug_call_back,
-                    debug_report_loader,
-                },
-                surface,
-                swapchain,
-            )
+    pub fn builder(name: &str) -> VkDataBuilder {
+        VkDataBuilder::new(name)
+    }
+
+    pub fn new(name: &str, window: &winit::Window) -> Result<(Self, SurfToken, SwapToken)> {
+        Self::builder(name).build(window)
+    }
+
+    /// Creates a VkData without a window; no surface or swapchain is made, so render into a
+    /// `RenderTarget` instead.
+    pub fn headless(name: &str) -> Result<Self> {
+        Self::builder(name).build_headless()
+    }
+
+    /// Per-frame command buffers, semaphores and fences for `frames_in_flight` frames on the
+    /// present queue's family. Drop it before the `VkData`.
+    pub fn frame_sync(&self) -> Result<FrameSync> {
+        Ok(FrameSync::new(
+            self.device.clone(),
+            self.queue_family_index,
+            self.frames_in_flight,
+        )?
+        .named(&self.namer, "frame"))
+    }
+
+    /// The first of `preferred` (`DEPTH_FORMATS` will do) usable as a depth attachment, for
+    /// `RenderTarget::new`.
+    pub fn depth_format(&self, preferred: &[vk::Format]) -> Result<vk::Format> {
+        find_depth_format(&self.instance, self.pdevice, preferred)
+    }
+
+    pub fn device_name(&self) -> String {
+        unsafe { CStr::from_ptr(self.device_properties.device_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn limits(&self) -> &vk::PhysicalDeviceLimits {
+        &self.device_properties.limits
+    }
+
+    pub fn layer_enabled(&self, name: &CStr) -> bool {
+        self.enabled_layers.iter().any(|l| l.as_c_str() == name)
+    }
+
+    pub fn instance_extension_enabled(&self, name: &CStr) -> bool {
+        self.enabled_instance_extensions
+            .iter()
+            .any(|e| e.as_c_str() == name)
+    }
+
+    pub fn device_extension_enabled(&self, name: &CStr) -> bool {
+        self.enabled_device_extensions
+            .iter()
+            .any(|e| e.as_c_str() == name)
+    }
+
+    fn init_pipeline_cache(&mut self, path: Option<PathBuf>) -> Result<()> {
+        self.pipeline_cache = crate::shader::load_pipeline_cache(
+            &self.device,
+            &self.device_properties,
+            path.as_deref(),
+        )?;
+        self.pipeline_cache_path = path;
+        Ok(())
+    }
+
+    /// Writes the pipeline cache to `pipeline_cache_path`, if there is one, so the next run can
+    /// skip compiling the same pipelines. Call it after making pipelines, or before exiting.
+    pub fn save_pipeline_cache(&self) -> Result<()> {
+        match &self.pipeline_cache_path {
+            Some(path) => crate::shader::save_pipeline_cache(
+                &self.device,
+                &self.device_properties,
+                self.pipeline_cache,
+                path,
+            ),
+            None => Ok(()),
         }
     }
 }
@@ -173,9 +138,7 @@ impl Drop for VkData {
         unsafe {
             self.device.device_wait_idle().unwrap();
             self.device
-                .destroy_semaphore(self.present_complete_semaphore, None);
-            self.device
-                .destroy_semaphore(self.rendering_complete_semaphore, None);
+                .destroy_pipeline_cache(self.pipeline_cache, None);
             // self.device.free_memory(self.depth_image_memory, None);
             // self.device.destroy_image_view(self.depth_image_view, None);
             // self.device.destroy_image(self.depth_image, None);
@@ -187,8 +150,7 @@ impl Drop for VkData {
             //     .destroy_swapchain(self.swapchain, None);
             self.device.destroy_device(None);
             //self.surface_loader.destroy_surface(self.surface, None);
-            self.debug_report_loader
-                .destroy_debug_report_callback(self.debug_call_back, None);
+            self.debug.destroy();
             self.instance.destroy_instance(None);
             //println!("Dropped VkData");
         }
@@ -0,0 +1,597 @@
+use crate::base::{DeviceCandidate, DeviceSelector, SurfToken, SwapConfig, SwapToken, VkData};
+use crate::debug::{DebugCallback, DebugMessage, DebugNamer, DebugToken};
+use crate::sync::DEFAULT_FRAMES_IN_FLIGHT;
+use crate::*;
+use ash::extensions::ext::{DebugReport, DebugUtils};
+use ash::extensions::khr::Swapchain;
+use ash::extensions::nv::MeshShader;
+use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0, InstanceV1_1};
+use ash::{vk, Device, Entry, Instance};
+
+use std::ffi::CString;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Tried in order when validation is on; the first one installed is enabled.
+const VALIDATION_LAYERS: [&str; 2] = [
+    "VK_LAYER_KHRONOS_validation",
+    "VK_LAYER_LUNARG_standard_validation",
+];
+
+/// Configures instance and device creation for `VkData`.
+///
+/// Layers, extensions, and features are either required, in which case creation fails without
+/// them, or optional, in which case they're enabled only when available. What actually got
+/// enabled is recorded on the resulting `VkData`.
+#[derive(Clone)]
+pub struct VkDataBuilder {
+    name: CString,
+    app_version: u32,
+    engine_version: u32,
+    api_version: u32,
+    validation: bool,
+    layers: Vec<(CString, bool)>,
+    instance_extensions: Vec<(CString, bool)>,
+    device_extensions: Vec<(CString, bool)>,
+    required_features: vk::PhysicalDeviceFeatures,
+    optional_features: vk::PhysicalDeviceFeatures,
+    device: DeviceSelector,
+    debug_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    debug_callback: Option<Arc<DebugCallback>>,
+    frames_in_flight: usize,
+    swap_config: SwapConfig,
+    pipeline_cache: Option<PathBuf>,
+    /// Names given to the builder that can't be passed to Vulkan, reported by `build`.
+    invalid_names: Vec<String>,
+}
+
+struct InstanceParts {
+    entry: Entry,
+    instance: Instance,
+    debug: DebugToken,
+    enabled_layers: Vec<CString>,
+    enabled_instance_extensions: Vec<CString>,
+}
+
+struct DeviceParts {
+    device: Device,
+    pdevice: vk::PhysicalDevice,
+    queue_family_index: u32,
+    present_queue: vk::Queue,
+    device_memory_properties: vk::PhysicalDeviceMemoryProperties,
+    frames_in_flight: usize,
+    enabled_device_extensions: Vec<CString>,
+    enabled_features: vk::PhysicalDeviceFeatures,
+    device_properties: vk::PhysicalDeviceProperties,
+    device_features: vk::PhysicalDeviceFeatures,
+}
+
+impl VkDataBuilder {
+    pub fn new(name: &str) -> Self {
+        let mut res = Self {
+            name: CString::default(),
+            app_version: 0,
+            engine_version: 0,
+            api_version: ash::vk_make_version!(1, 1, 100),
+            validation: cfg!(debug_assertions),
+            layers: Vec::new(),
+            instance_extensions: vec![(DebugUtils::name().to_owned(), false)],
+            device_extensions: Vec::new(),
+            required_features: vk::PhysicalDeviceFeatures {
+                shader_clip_distance: 1,
+                ..Default::default()
+            },
+            optional_features: Default::default(),
+            device: DeviceSelector::from_env(),
+            debug_severity: vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+                | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING,
+            debug_callback: None,
+            frames_in_flight: DEFAULT_FRAMES_IN_FLIGHT,
+            swap_config: Default::default(),
+            pipeline_cache: None,
+            invalid_names: Vec::new(),
+        };
+        res.name = res.c_string(name);
+        res
+    }
+
+    /// `name` as a C string, or an empty one if it contains NUL, which fails `build` later.
+    fn c_string(&mut self, name: &str) -> CString {
+        CString::new(name).unwrap_or_else(|_| {
+            self.invalid_names.push(name.to_string());
+            CString::default()
+        })
+    }
+
+    pub fn app_version(mut self, version: u32) -> Self {
+        self.app_version = version;
+        self
+    }
+
+    pub fn engine_version(mut self, version: u32) -> Self {
+        self.engine_version = version;
+        self
+    }
+
+    pub fn api_version(mut self, version: u32) -> Self {
+        self.api_version = version;
+        self
+    }
+
+    /// Enables whichever standard validation layer is installed, if any. On by default in debug
+    /// builds.
+    pub fn validation(mut self, enable: bool) -> Self {
+        self.validation = enable;
+        self
+    }
+
+    pub fn layer(mut self, name: &str) -> Self {
+        let name = self.c_string(name);
+        self.layers.push((name, true));
+        self
+    }
+
+    pub fn optional_layer(mut self, name: &str) -> Self {
+        let name = self.c_string(name);
+        self.layers.push((name, false));
+        self
+    }
+
+    pub fn instance_extension(mut self, name: &CStr) -> Self {
+        self.instance_extensions.push((name.to_owned(), true));
+        self
+    }
+
+    pub fn optional_instance_extension(mut self, name: &CStr) -> Self {
+        self.instance_extensions.push((name.to_owned(), false));
+        self
+    }
+
+    pub fn device_extension(mut self, name: &CStr) -> Self {
+        self.device_extensions.push((name.to_owned(), true));
+        self
+    }
+
+    pub fn optional_device_extension(mut self, name: &CStr) -> Self {
+        self.device_extensions.push((name.to_owned(), false));
+        self
+    }
+
+    /// Enables `VK_NV_mesh_shader`, with whichever of its task and mesh shader features the
+    /// device supports, when the device has it; check with
+    /// `VkData::device_extension_enabled(MeshShader::name())`. Finding the features needs
+    /// Vulkan 1.1, so they stay off under 1.0.
+    pub fn mesh_shaders(self) -> Self {
+        self.optional_device_extension(MeshShader::name())
+    }
+
+    /// Replaces the required feature set. Devices lacking any of these are never selected.
+    pub fn required_features(mut self, features: vk::PhysicalDeviceFeatures) -> Self {
+        self.required_features = features;
+        self
+    }
+
+    pub fn optional_features(mut self, features: vk::PhysicalDeviceFeatures) -> Self {
+        self.optional_features = features;
+        self
+    }
+
+    /// Overrides the physical device choice. Defaults to `DeviceSelector::from_env()`.
+    pub fn device(mut self, selector: DeviceSelector) -> Self {
+        self.device = selector;
+        self
+    }
+
+    /// Which validation messages are reported at all. Defaults to errors and warnings.
+    pub fn debug_severity(mut self, severity: vk::DebugUtilsMessageSeverityFlagsEXT) -> Self {
+        self.debug_severity = severity;
+        self
+    }
+
+    /// How many frames `VkData::frame_sync` lets the CPU get ahead of the GPU. Defaults to 2; 1
+    /// means no overlap.
+    pub fn frames_in_flight(mut self, frames: usize) -> Self {
+        self.frames_in_flight = std::cmp::max(frames, 1);
+        self
+    }
+
+    /// How `build` makes the swapchain.
+    pub fn swap_config(mut self, config: SwapConfig) -> Self {
+        self.swap_config = config;
+        self
+    }
+
+    /// Seeds the pipeline cache from `path`, and has `VkData::save_pipeline_cache` write it
+    /// back there. Caches from another device or driver are ignored.
+    pub fn pipeline_cache<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.pipeline_cache = Some(path.into());
+        self
+    }
+
+    /// Replaces the default `log` output for validation messages. Panicking in it, e.g. to fail
+    /// a test on validation errors, works through `DebugToken::resume_panic`.
+    pub fn debug_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&DebugMessage) + Send + Sync + 'static,
+    {
+        self.debug_callback = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn build(mut self, window: &winit::Window) -> Result<(VkData, SurfToken, SwapToken)> {
+        for ext in surface_extension_names() {
+            self.instance_extensions.push((ext.to_owned(), true));
+        }
+        if self.swap_config.wants_extended_color_spaces() {
+            self.instance_extensions
+                .push((vk::ExtSwapchainColorspaceFn::name().to_owned(), false));
+        }
+        self.device_extensions
+            .push((Swapchain::name().to_owned(), true));
+        unsafe {
+            let parts = self.create_instance()?;
+            let surface = match SurfToken::new(&parts.entry, &parts.instance, window) {
+                Ok(surface) => surface,
+                Err(e) => {
+                    parts.destroy();
+                    return Err(e);
+                }
+            };
+            let dev = self.create_device(&parts, |pdevice, index, info| {
+                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                    && surface.loader.get_physical_device_surface_support(
+                        pdevice,
+                        index,
+                        surface.surface,
+                    )
+            });
+            let mut vk = match dev {
+                Ok(dev) => assemble(parts, dev),
+                Err(e) => {
+                    drop(surface);
+                    parts.destroy();
+                    return Err(e);
+                }
+            };
+            if let Err(e) = vk.init_pipeline_cache(self.pipeline_cache.clone()) {
+                drop(surface);
+                drop(vk);
+                return Err(e);
+            }
+            let swapchain = SwapToken::new(
+                &vk.instance,
+                vk.device.clone(),
+                &surface,
+                vk.pdevice,
+                vk.queue_family_index,
+                &vk.device_memory_properties,
+                window,
+                vk.namer.clone(),
+                self.swap_config.clone(),
+            );
+            match swapchain {
+                Ok(mut swapchain) => {
+                    swapchain.pipeline_cache = vk.pipeline_cache;
+                    Ok((vk, surface, swapchain))
+                }
+                // The surface has to go before the instance, which dropping vk destroys
+                Err(e) => {
+                    drop(surface);
+                    drop(vk);
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    pub fn build_headless(self) -> Result<VkData> {
+        unsafe {
+            let parts = self.create_instance()?;
+            match self.create_device(&parts, |_, _, info| {
+                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+            }) {
+                Ok(dev) => {
+                    let mut vk = assemble(parts, dev);
+                    vk.init_pipeline_cache(self.pipeline_cache.clone())?;
+                    Ok(vk)
+                }
+                Err(e) => {
+                    parts.destroy();
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    unsafe fn create_instance(&self) -> Result<InstanceParts> {
+        if !self.invalid_names.is_empty() {
+            return Err(Error::Unsupported(format!(
+                "Names containing NUL: {:?}",
+                self.invalid_names
+            )));
+        }
+        let entry = Entry::new()?;
+
+        let available_layers = entry
+            .enumerate_instance_layer_properties()?
+            .iter()
+            .map(|p| CStr::from_ptr(p.layer_name.as_ptr()).to_owned())
+            .collect::<Vec<_>>();
+        let mut layers = self.layers.clone();
+        if self.validation {
+            match VALIDATION_LAYERS
+                .iter()
+                .filter_map(|l| CString::new(*l).ok())
+                .find(|l| available_layers.contains(l))
+            {
+                Some(l) => layers.push((l, false)),
+                None => log::warn!("Validation requested, but no validation layer is installed"),
+            }
+        }
+        let enabled_layers = select("layer", &layers, &available_layers)?;
+
+        let available_extensions = entry
+            .enumerate_instance_extension_properties()?
+            .iter()
+            .map(|p| CStr::from_ptr(p.extension_name.as_ptr()).to_owned())
+            .collect::<Vec<_>>();
+        let mut instance_extensions = self.instance_extensions.clone();
+        // debug-report is only a fallback for when there's no debug-utils
+        if !available_extensions
+            .iter()
+            .any(|e| e.as_c_str() == DebugUtils::name())
+        {
+            instance_extensions.push((DebugReport::name().to_owned(), false));
+        }
+        let enabled_instance_extensions = select(
+            "instance extension",
+            &instance_extensions,
+            &available_extensions,
+        )?;
+
+        let layers_names_raw = raw_names(&enabled_layers);
+        let extension_names_raw = raw_names(&enabled_instance_extensions);
+
+        let appinfo = vk::ApplicationInfo::builder()
+            .application_name(&self.name)
+            .application_version(self.app_version)
+            .engine_name(&self.name)
+            .engine_version(self.engine_version)
+            .api_version(self.api_version);
+
+        let create_info = vk::InstanceCreateInfo::builder()
+            .application_info(&appinfo)
+            .enabled_layer_names(&layers_names_raw)
+            .enabled_extension_names(&extension_names_raw);
+
+        let instance: Instance = entry.create_instance(&create_info, None)?;
+
+        let debug = match DebugToken::new(
+            &entry,
+            &instance,
+            &enabled_instance_extensions,
+            self.debug_severity,
+            self.debug_callback.clone(),
+        ) {
+            Ok(debug) => debug,
+            Err(e) => {
+                instance.destroy_instance(None);
+                return Err(e);
+            }
+        };
+
+        Ok(InstanceParts {
+            entry,
+            instance,
+            debug,
+            enabled_layers,
+            enabled_instance_extensions,
+        })
+    }
+
+    /// Picks a physical device with every required device extension and feature and a queue
+    /// family accepted by `queue_filter`, then creates the logical device on it.
+    unsafe fn create_device<F>(&self, parts: &InstanceParts, queue_filter: F) -> Result<DeviceParts>
+    where
+        F: Fn(vk::PhysicalDevice, u32, &vk::QueueFamilyProperties) -> bool,
+    {
+        let instance = &parts.instance;
+        let required_extensions = self
+            .device_extensions
+            .iter()
+            .filter(|(_, required)| *required)
+            .map(|(name, _)| name.as_c_str())
+            .collect::<Vec<_>>();
+        let candidates = DeviceCandidate::enumerate(instance, queue_filter)?;
+        let chosen = DeviceCandidate::select(
+            &candidates,
+            &self.device,
+            &self.required_features,
+            &required_extensions,
+        )?;
+        log::info!("Selected device {}: {}", chosen.index, chosen.name);
+        let pdevice = chosen.pdevice;
+        // select only returns candidates with a queue family
+        let queue_family_index = chosen.queue_family_index.unwrap();
+
+        let enabled_device_extensions = select(
+            "device extension",
+            &self.device_extensions,
+            &chosen.extensions,
+        )?;
+        let enabled_features = enable_features(
+            &chosen.features,
+            &self.required_features,
+            &self.optional_features,
+        );
+        let device_extension_names_raw = raw_names(&enabled_device_extensions);
+        let priorities = [1.0];
+
+        let device: Device = {
+            let queue_info = [vk::DeviceQueueCreateInfo::builder()
+                .queue_family_index(queue_family_index)
+                .queue_priorities(&priorities)
+                .build()];
+
+            // The extension's features have to be turned on as well, but only the supported ones
+            let mut mesh_features = vk::PhysicalDeviceMeshShaderFeaturesNV::default();
+            let mut device_create_info = vk::DeviceCreateInfo::builder()
+                .queue_create_infos(&queue_info)
+                .enabled_extension_names(&device_extension_names_raw)
+                .enabled_features(&enabled_features);
+            if enabled_device_extensions
+                .iter()
+                .any(|e| e.as_c_str() == MeshShader::name())
+            {
+                let version_1_1 = ash::vk_make_version!(1, 1, 0);
+                if self.api_version >= version_1_1 && chosen.properties.api_version >= version_1_1 {
+                    // ash only wraps the plain struct, so the query is chained by hand
+                    let mut query = vk::PhysicalDeviceFeatures2 {
+                        p_next: &mut mesh_features as *mut _ as *mut std::ffi::c_void,
+                        ..Default::default()
+                    };
+                    instance
+                        .fp_v1_1()
+                        .get_physical_device_features2(pdevice, &mut query);
+                } else {
+                    log::warn!("Mesh shader features need Vulkan 1.1; leaving them off");
+                }
+                device_create_info = device_create_info.push_next(&mut mesh_features);
+            }
+            instance.create_device(pdevice, &device_create_info, None)?
+        };
+        let present_queue = device.get_device_queue(queue_family_index, 0);
+
+        Ok(DeviceParts {
+            device,
+            pdevice,
+            queue_family_index,
+            present_queue,
+            device_memory_properties: chosen.memory_properties,
+            frames_in_flight: self.frames_in_flight,
+            enabled_device_extensions,
+            enabled_features,
+            device_properties: chosen.properties,
+            device_features: chosen.features,
+        })
+    }
+}
+
+fn assemble(parts: InstanceParts, dev: DeviceParts) -> VkData {
+    let InstanceParts {
+        entry,
+        instance,
+        debug,
+        enabled_layers,
+        enabled_instance_extensions,
+    } = parts;
+    let namer = DebugNamer::new(&debug, &dev.device);
+    namer.name(dev.present_queue, "present queue");
+
+    VkData {
+        entry,
+        instance,
+        device: dev.device,
+        queue_family_index: dev.queue_family_index,
+        pdevice: dev.pdevice,
+        device_memory_properties: dev.device_memory_properties,
+        present_queue: dev.present_queue,
+        frames_in_flight: dev.frames_in_flight,
+        debug,
+        namer,
+        enabled_layers,
+        enabled_instance_extensions,
+        enabled_device_extensions: dev.enabled_device_extensions,
+        enabled_features: dev.enabled_features,
+        device_properties: dev.device_properties,
+        device_features: dev.device_features,
+        pipeline_cache: vk::PipelineCache::null(),
+        pipeline_cache_path: None,
+    }
+}
+
+impl InstanceParts {
+    unsafe fn destroy(mut self) {
+        self.debug.destroy();
+        self.instance.destroy_instance(None);
+    }
+}
+
+/// Filters `wanted` down to what's `available`, failing on a missing required entry.
+fn select(
+    kind: &'static str,
+    wanted: &[(CString, bool)],
+    available: &[CString],
+) -> Result<Vec<CString>> {
+    let mut res: Vec<CString> = Vec::new();
+    for (name, required) in wanted {
+        if res.contains(name) {
+            continue;
+        }
+        if available.contains(name) {
+            res.push(name.clone());
+        } else if *required {
+            return Err(Error::Missing {
+                kind,
+                name: name.clone(),
+            });
+        } else {
+            log::warn!("Optional {} not available: {:?}", kind, name);
+        }
+    }
+    Ok(res)
+}
+
+fn raw_names(names: &[CString]) -> Vec<*const i8> {
+    names.iter().map(|n| n.as_ptr()).collect()
+}
+
+// PhysicalDeviceFeatures is nothing but Bool32s, so it's treated as a slice of them for the
+// per-feature comparisons below.
+fn feature_bits(features: &vk::PhysicalDeviceFeatures) -> &[vk::Bool32] {
+    unsafe {
+        std::slice::from_raw_parts(
+            features as *const _ as *const vk::Bool32,
+            std::mem::size_of::<vk::PhysicalDeviceFeatures>() / std::mem::size_of::<vk::Bool32>(),
+        )
+    }
+}
+
+fn feature_bits_mut(features: &mut vk::PhysicalDeviceFeatures) -> &mut [vk::Bool32] {
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            features as *mut _ as *mut vk::Bool32,
+            std::mem::size_of::<vk::PhysicalDeviceFeatures>() / std::mem::size_of::<vk::Bool32>(),
+        )
+    }
+}
+
+/// Whether every feature set in `required` is also set in `available`.
+pub fn supports_features(
+    available: &vk::PhysicalDeviceFeatures,
+    required: &vk::PhysicalDeviceFeatures,
+) -> bool {
+    feature_bits(available)
+        .iter()
+        .zip(feature_bits(required).iter())
+        .all(|(a, r)| *r == vk::FALSE || *a != vk::FALSE)
+}
+
+/// `required`, plus whichever of `optional` are in `available`.
+pub fn enable_features(
+    available: &vk::PhysicalDeviceFeatures,
+    required: &vk::PhysicalDeviceFeatures,
+    optional: &vk::PhysicalDeviceFeatures,
+) -> vk::PhysicalDeviceFeatures {
+    let mut res = *required;
+    for (r, (a, o)) in feature_bits_mut(&mut res).iter_mut().zip(
+        feature_bits(available)
+            .iter()
+            .zip(feature_bits(optional).iter()),
+    ) {
+        if *a != vk::FALSE && *o != vk::FALSE {
+            *r = vk::TRUE;
+        }
+    }
+    res
+}
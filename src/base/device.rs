@@ -0,0 +1,319 @@
+use crate::base::builder::supports_features;
+use crate::{Error, Result};
+use ash::version::InstanceV1_0;
+use ash::{vk, Instance};
+
+use std::ffi::{CStr, CString};
+
+/// Environment variable read by `DeviceSelector::from_env`.
+pub const DEVICE_ENV_VAR: &str = "FLINT_DEVICE";
+
+/// How `VkDataBuilder` chooses among physical devices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// Highest scoring suitable device.
+    Auto,
+    /// Position in `vkEnumeratePhysicalDevices` order.
+    Index(usize),
+    /// Best device whose name contains this, ignoring case.
+    Name(String),
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        DeviceSelector::Auto
+    }
+}
+
+impl DeviceSelector {
+    /// Reads `FLINT_DEVICE`: a number selects by index, anything else by name. Unset or empty
+    /// means `Auto`.
+    pub fn from_env() -> Self {
+        Self::parse(std::env::var(DEVICE_ENV_VAR).ok().as_deref())
+    }
+
+    fn parse(var: Option<&str>) -> Self {
+        match var.map(str::trim) {
+            None | Some("") => DeviceSelector::Auto,
+            Some(s) => match s.parse::<usize>() {
+                Ok(i) => DeviceSelector::Index(i),
+                Err(_) => DeviceSelector::Name(s.to_string()),
+            },
+        }
+    }
+
+    fn matches(&self, candidate: &DeviceCandidate) -> bool {
+        match self {
+            DeviceSelector::Auto => true,
+            DeviceSelector::Index(i) => candidate.index == *i,
+            DeviceSelector::Name(n) => candidate.name.to_lowercase().contains(&n.to_lowercase()),
+        }
+    }
+}
+
+/// Everything Flint knows about a physical device when deciding whether to use it.
+#[derive(Clone)]
+pub struct DeviceCandidate {
+    pub pdevice: vk::PhysicalDevice,
+    pub index: usize,
+    pub name: String,
+    pub properties: vk::PhysicalDeviceProperties,
+    pub features: vk::PhysicalDeviceFeatures,
+    pub memory_properties: vk::PhysicalDeviceMemoryProperties,
+    pub extensions: Vec<CString>,
+    /// First queue family accepted by the filter given to `enumerate`, if any.
+    pub queue_family_index: Option<u32>,
+}
+
+impl std::fmt::Debug for DeviceCandidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DeviceCandidate")
+            .field("index", &self.index)
+            .field("name", &self.name)
+            .field("device_type", &self.properties.device_type)
+            .field("device_local_memory", &self.device_local_memory())
+            .field("queue_family_index", &self.queue_family_index)
+            .finish()
+    }
+}
+
+impl DeviceCandidate {
+    pub unsafe fn enumerate<F>(instance: &Instance, queue_filter: F) -> Result<Vec<Self>>
+    where
+        F: Fn(vk::PhysicalDevice, u32, &vk::QueueFamilyProperties) -> bool,
+    {
+        instance
+            .enumerate_physical_devices()?
+            .into_iter()
+            .enumerate()
+            .map(|(index, pdevice)| {
+                let properties = instance.get_physical_device_properties(pdevice);
+                let queue_family_index = instance
+                    .get_physical_device_queue_family_properties(pdevice)
+                    .iter()
+                    .enumerate()
+                    .find(|(i, info)| queue_filter(pdevice, *i as u32, info))
+                    .map(|(i, _)| i as u32);
+                Ok(DeviceCandidate {
+                    pdevice,
+                    index,
+                    name: CStr::from_ptr(properties.device_name.as_ptr())
+                        .to_string_lossy()
+                        .into_owned(),
+                    properties,
+                    features: instance.get_physical_device_features(pdevice),
+                    memory_properties: instance.get_physical_device_memory_properties(pdevice),
+                    extensions: instance
+                        .enumerate_device_extension_properties(pdevice)?
+                        .iter()
+                        .map(|p| CStr::from_ptr(p.extension_name.as_ptr()).to_owned())
+                        .collect(),
+                    queue_family_index,
+                })
+            })
+            .collect()
+    }
+
+    pub fn limits(&self) -> &vk::PhysicalDeviceLimits {
+        &self.properties.limits
+    }
+
+    pub fn device_local_memory(&self) -> vk::DeviceSize {
+        self.memory_properties.memory_heaps[..self.memory_properties.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum()
+    }
+
+    pub fn has_extension(&self, name: &CStr) -> bool {
+        self.extensions.iter().any(|e| e.as_c_str() == name)
+    }
+
+    /// Whether the device has a usable queue family, the required features, and the required
+    /// extensions.
+    pub fn suitable(
+        &self,
+        required_features: &vk::PhysicalDeviceFeatures,
+        required_extensions: &[&CStr],
+    ) -> bool {
+        self.queue_family_index.is_some()
+            && supports_features(&self.features, required_features)
+            && required_extensions.iter().all(|e| self.has_extension(e))
+    }
+
+    /// Higher is better: device type dominates (discrete > integrated > virtual > cpu), with
+    /// device-local memory as the tie breaker. `None` if the device isn't suitable.
+    pub fn score(
+        &self,
+        required_features: &vk::PhysicalDeviceFeatures,
+        required_extensions: &[&CStr],
+    ) -> Option<u64> {
+        if !self.suitable(required_features, required_extensions) {
+            return None;
+        }
+        let type_score = match self.properties.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        };
+        // Memory in MiB fits comfortably below 1 << 40
+        Some((type_score << 40) + (self.device_local_memory() >> 20))
+    }
+
+    /// Picks from `candidates` according to `selector`. An explicit selection that matches no
+    /// suitable device is an error rather than silently using a different GPU.
+    pub fn select<'a>(
+        candidates: &'a [Self],
+        selector: &DeviceSelector,
+        required_features: &vk::PhysicalDeviceFeatures,
+        required_extensions: &[&CStr],
+    ) -> Result<&'a Self> {
+        let best = candidates
+            .iter()
+            .filter(|c| selector.matches(c))
+            .filter_map(|c| {
+                c.score(required_features, required_extensions)
+                    .map(|score| (score, c))
+            })
+            .max_by_key(|(score, c)| (*score, std::cmp::Reverse(c.index)));
+        match best {
+            Some((_, c)) => Ok(c),
+            None => Err(Error::NoSuitableDevice(format!(
+                "{:?} with extensions {:?}; candidates: {:#?}",
+                selector, required_extensions, candidates
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(
+        index: usize,
+        name: &str,
+        ty: vk::PhysicalDeviceType,
+        mib: u64,
+    ) -> DeviceCandidate {
+        let mut memory_heaps = [vk::MemoryHeap::default(); vk::MAX_MEMORY_HEAPS];
+        memory_heaps[0] = vk::MemoryHeap {
+            size: mib << 20,
+            flags: vk::MemoryHeapFlags::DEVICE_LOCAL,
+        };
+        let memory_properties = vk::PhysicalDeviceMemoryProperties {
+            memory_heap_count: 1,
+            memory_heaps,
+            ..Default::default()
+        };
+        DeviceCandidate {
+            pdevice: vk::PhysicalDevice::null(),
+            index,
+            name: name.to_string(),
+            properties: vk::PhysicalDeviceProperties {
+                device_type: ty,
+                ..Default::default()
+            },
+            features: Default::default(),
+            memory_properties,
+            extensions: Vec::new(),
+            queue_family_index: Some(0),
+        }
+    }
+
+    fn candidates() -> Vec<DeviceCandidate> {
+        vec![
+            candidate(
+                0,
+                "Intel UHD 630",
+                vk::PhysicalDeviceType::INTEGRATED_GPU,
+                8192,
+            ),
+            candidate(
+                1,
+                "NVIDIA GeForce GTX 1070",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                2048,
+            ),
+            candidate(2, "llvmpipe", vk::PhysicalDeviceType::CPU, 16384),
+        ]
+    }
+
+    fn select(selector: DeviceSelector) -> Result<usize> {
+        let candidates = candidates();
+        DeviceCandidate::select(&candidates, &selector, &Default::default(), &[]).map(|c| c.index)
+    }
+
+    #[test]
+    fn auto_prefers_discrete_over_integrated() {
+        assert_eq!(select(DeviceSelector::Auto).unwrap(), 1);
+    }
+
+    #[test]
+    fn memory_breaks_ties() {
+        let mut candidates = candidates();
+        candidates.push(candidate(
+            3,
+            "Radeon",
+            vk::PhysicalDeviceType::DISCRETE_GPU,
+            4096,
+        ));
+        let best =
+            DeviceCandidate::select(&candidates, &DeviceSelector::Auto, &Default::default(), &[]);
+        assert_eq!(best.unwrap().index, 3);
+    }
+
+    #[test]
+    fn selects_by_index() {
+        assert_eq!(select(DeviceSelector::Index(2)).unwrap(), 2);
+        assert!(select(DeviceSelector::Index(3)).is_err());
+    }
+
+    #[test]
+    fn selects_by_name_ignoring_case() {
+        assert_eq!(
+            select(DeviceSelector::Name("intel".to_string())).unwrap(),
+            0
+        );
+        assert!(select(DeviceSelector::Name("Mali".to_string())).is_err());
+    }
+
+    #[test]
+    fn unsuitable_devices_are_skipped() {
+        let mut candidates = candidates();
+        candidates[1].queue_family_index = None;
+        let best =
+            DeviceCandidate::select(&candidates, &DeviceSelector::Auto, &Default::default(), &[]);
+        assert_eq!(best.unwrap().index, 0);
+        let ext = CString::new("VK_KHR_swapchain").unwrap();
+        assert!(DeviceCandidate::select(
+            &candidates,
+            &DeviceSelector::Auto,
+            &Default::default(),
+            &[&ext]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parses_env_values() {
+        assert_eq!(DeviceSelector::parse(None), DeviceSelector::Auto);
+        assert_eq!(DeviceSelector::parse(Some("  ")), DeviceSelector::Auto);
+        assert_eq!(DeviceSelector::parse(Some(" 1 ")), DeviceSelector::Index(1));
+        assert_eq!(
+            DeviceSelector::parse(Some("GeForce")),
+            DeviceSelector::Name("GeForce".to_string())
+        );
+    }
+
+    #[test]
+    fn bad_env_value_selects_nothing() {
+        // Not an index, so it's taken as a name, which no device has
+        let selector = DeviceSelector::parse(Some("-1"));
+        assert_eq!(selector, DeviceSelector::Name("-1".to_string()));
+        assert!(select(selector).is_err());
+    }
+}
@@ -22,9 +22,9 @@ impl SurfToken {
         entry: &E,
         instance: &I,
         window: &winit::Window,
-    ) -> Self {
-        let surface = unsafe { create_surface(entry, instance, window).unwrap() };
+    ) -> Result<Self> {
+        let surface = unsafe { create_surface(entry, instance, window)? };
         let loader = Surface::new(entry, instance);
-        Self { surface, loader }
+        Ok(Self { surface, loader })
     }
 }
@@ -3,95 +3,97 @@ use crate::shader::PushConstant;
 use ash::extensions::khr::Swapchain;
 
 use crate::command::*;
-use crate::renderpass::RenderPassToken;
+use crate::debug::{DebugName, DebugNamer};
+use crate::readback::Readback;
+use crate::renderpass::{RenderPassBuilder, RenderPassToken};
+use crate::rendertarget::make_image;
 use crate::shader::DescPoolToken;
 use crate::shader::PipeToken;
+use crate::shader::PipelineDesc;
 use crate::shader::ShaderArtifact;
 use crate::shader::ShaderStage;
+use crate::sync::DEFAULT_FRAMES_IN_FLIGHT;
 use crate::*;
 use ash::version::DeviceV1_0;
 use ash::{vk, Device, Instance};
+use shaderc::{CompileOptions, Compiler};
 use std::collections::HashMap;
 use std::default::Default;
+use std::path::{Path, PathBuf};
 
 use std::ops::Drop;
 
 fn query_support(
     physical_device: vk::PhysicalDevice,
     surface: &SurfToken,
-) -> (
+) -> Result<(
     vk::SurfaceCapabilitiesKHR,
     Vec<vk::SurfaceFormatKHR>,
     Vec<vk::PresentModeKHR>,
-) {
+)> {
     unsafe {
         let capabilities = surface
             .loader
-            .get_physical_device_surface_capabilities(physical_device, surface.surface)
-            .unwrap();
+            .get_physical_device_surface_capabilities(physical_device, surface.surface)?;
         let formats = surface
             .loader
-            .get_physical_device_surface_formats(physical_device, surface.surface)
-            .unwrap();
+            .get_physical_device_surface_formats(physical_device, surface.surface)?;
         let present_modes = surface
             .loader
-            .get_physical_device_surface_present_modes(physical_device, surface.surface)
-            .unwrap();
+            .get_physical_device_surface_present_modes(physical_device, surface.surface)?;
 
-        (capabilities, formats, present_modes)
+        Ok((capabilities, formats, present_modes))
     }
 }
 
-fn choose_format(available: &[vk::SurfaceFormatKHR]) -> vk::SurfaceFormatKHR {
-    available
-        .iter()
-        .map(|sfmt| match sfmt.format {
-            vk::Format::UNDEFINED => vk::SurfaceFormatKHR {
-                format: vk::Format::B8G8R8_UNORM,
-                color_space: sfmt.color_space,
-            },
-            _ => *sfmt,
-        })
-        .nth(0)
-        .unwrap()
-    // if available.len() == 1 && available[0].format == vk::Format::UNDEFINED {
-    //     return vk::SurfaceFormatKHR {
-    //         format: vk::Format::B8G8R8A8_UNORM,
-    //         color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
-    //     };
-    // }
-    //
-    // for format in available {
-    //     if format.format == vk::Format::B8G8R8A8_UNORM
-    //         && format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
-    //     {
-    //         return format.clone();
-    //     }
-    // }
-    //
-    // available_formats.first().unwrap().clone()
-}
-
-fn choose_mode(available: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
-    let mut res = vk::PresentModeKHR::FIFO;
-
-    for mode in available {
-        if *mode == vk::PresentModeKHR::MAILBOX {
-            return *mode;
-        } else if *mode == vk::PresentModeKHR::IMMEDIATE {
-            res = *mode;
-        }
+/// The first of `preferred` that's `available`, or else whatever the surface lists first.
+fn choose_format(
+    preferred: &[vk::SurfaceFormatKHR],
+    available: &[vk::SurfaceFormatKHR],
+) -> Result<vk::SurfaceFormatKHR> {
+    match available {
+        [] => Err(Error::Unsupported(
+            "Surface without any formats".to_string(),
+        )),
+        // A lone UNDEFINED means any format is fine in that color space
+        [only] if only.format == vk::Format::UNDEFINED => Ok(preferred
+            .iter()
+            .find(|p| p.color_space == only.color_space)
+            .cloned()
+            .unwrap_or(vk::SurfaceFormatKHR {
+                format: vk::Format::B8G8R8A8_SRGB,
+                color_space: only.color_space,
+            })),
+        _ => Ok(preferred
+            .iter()
+            .find(|p| {
+                available
+                    .iter()
+                    .any(|a| a.format == p.format && a.color_space == p.color_space)
+            })
+            .cloned()
+            .unwrap_or(available[0])),
     }
+}
 
-    res
+/// The first of `preferred` that's `available`, or FIFO, which every driver supports.
+fn choose_mode(
+    preferred: &[vk::PresentModeKHR],
+    available: &[vk::PresentModeKHR],
+) -> vk::PresentModeKHR {
+    preferred
+        .iter()
+        .find(|mode| available.contains(mode))
+        .cloned()
+        .unwrap_or(vk::PresentModeKHR::FIFO)
 }
 
 fn choose_extent(
     capabilities: &vk::SurfaceCapabilitiesKHR,
     window: &winit::Window,
-) -> vk::Extent2D {
+) -> Result<vk::Extent2D> {
     if capabilities.current_extent.width != u32::max_value() {
-        capabilities.current_extent
+        Ok(capabilities.current_extent)
     } else {
         fn clamp(num: u32, n: u32, x: u32) -> u32 {
             use std::cmp::{max, min};
@@ -99,13 +101,13 @@ fn choose_extent(
         }
         let window_size = window
             .get_inner_size()
-            .expect("Failed to get the size of Window");
+            .ok_or_else(|| Error::Unsupported("Swapchain for a closed window".to_string()))?;
         // println!(
         //     "\t\tInner Window Size: ({}, {})",
         //     window_size.width, window_size.height
         // );
 
-        vk::Extent2D {
+        Ok(vk::Extent2D {
             width: clamp(
                 window_size.width as u32,
                 capabilities.min_image_extent.width,
@@ -116,62 +118,216 @@ fn choose_extent(
                 capabilities.min_image_extent.height,
                 capabilities.max_image_extent.height,
             ),
+        })
+    }
+}
+
+const SDR_FORMATS: [vk::SurfaceFormatKHR; 2] = [
+    vk::SurfaceFormatKHR {
+        format: vk::Format::B8G8R8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    },
+    vk::SurfaceFormatKHR {
+        format: vk::Format::R8G8B8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    },
+];
+
+const HDR_FORMATS: [vk::SurfaceFormatKHR; 2] = [
+    vk::SurfaceFormatKHR {
+        format: vk::Format::A2B10G10R10_UNORM_PACK32,
+        color_space: vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+    },
+    vk::SurfaceFormatKHR {
+        format: vk::Format::R16G16B16A16_SFLOAT,
+        color_space: vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+    },
+];
+
+/// Preferences for how the swapchain is made. Each list is tried in order, falling back to
+/// something every driver supports.
+#[derive(Debug, Clone)]
+pub struct SwapConfig {
+    /// Defaults to MAILBOX, then IMMEDIATE, then FIFO.
+    pub present_modes: Vec<vk::PresentModeKHR>,
+    /// Defaults to 8-bit sRGB. Color spaces other than SRGB_NONLINEAR need
+    /// VK_EXT_swapchain_colorspace, which `VkDataBuilder` enables when it's asked for here.
+    pub surface_formats: Vec<vk::SurfaceFormatKHR>,
+    /// Defaults to `DEPTH_FORMATS`.
+    pub depth_formats: Vec<vk::Format>,
+    /// MSAA samples per pixel, lowered to what the device supports. Defaults to 1.
+    pub samples: vk::SampleCountFlags,
+}
+
+impl Default for SwapConfig {
+    fn default() -> Self {
+        Self {
+            present_modes: vec![
+                vk::PresentModeKHR::MAILBOX,
+                vk::PresentModeKHR::IMMEDIATE,
+                vk::PresentModeKHR::FIFO,
+            ],
+            surface_formats: SDR_FORMATS.to_vec(),
+            depth_formats: DEPTH_FORMATS.to_vec(),
+            samples: vk::SampleCountFlags::TYPE_1,
         }
     }
 }
 
+impl SwapConfig {
+    /// Waits for vertical blank; never tears, and doesn't render frames that won't be shown.
+    pub fn vsync() -> Self {
+        Self::default().present_modes(vec![vk::PresentModeKHR::FIFO])
+    }
+
+    /// Vsync, except a late frame is shown straight away, tearing, instead of waiting another
+    /// blank.
+    pub fn adaptive_vsync() -> Self {
+        Self::default().present_modes(vec![
+            vk::PresentModeKHR::FIFO_RELAXED,
+            vk::PresentModeKHR::FIFO,
+        ])
+    }
+
+    /// Presents as soon as possible, tearing.
+    pub fn no_vsync() -> Self {
+        Self::default().present_modes(vec![
+            vk::PresentModeKHR::IMMEDIATE,
+            vk::PresentModeKHR::MAILBOX,
+            vk::PresentModeKHR::FIFO,
+        ])
+    }
+
+    pub fn present_modes(mut self, modes: Vec<vk::PresentModeKHR>) -> Self {
+        self.present_modes = modes;
+        self
+    }
+
+    pub fn surface_formats(mut self, formats: Vec<vk::SurfaceFormatKHR>) -> Self {
+        self.surface_formats = formats;
+        self
+    }
+
+    /// Prefers HDR10 (PQ), then scRGB (extended linear sRGB) when the display offers them,
+    /// falling back to sRGB. Check `SwapchainBase::is_hdr` for what was picked; HDR10 expects
+    /// PQ-encoded output and scRGB expects linear values that may exceed 1.0.
+    pub fn hdr(mut self) -> Self {
+        self.surface_formats = HDR_FORMATS
+            .iter()
+            .chain(SDR_FORMATS.iter())
+            .cloned()
+            .collect();
+        self
+    }
+
+    pub fn depth_formats(mut self, formats: Vec<vk::Format>) -> Self {
+        self.depth_formats = formats;
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Prefers depth formats with a stencil component.
+    pub fn stencil(self) -> Self {
+        self.depth_formats(vec![
+            vk::Format::D24_UNORM_S8_UINT,
+            vk::Format::D32_SFLOAT_S8_UINT,
+            vk::Format::D16_UNORM_S8_UINT,
+            vk::Format::D32_SFLOAT,
+            vk::Format::D16_UNORM,
+        ])
+    }
+
+    /// Whether any preferred format needs VK_EXT_swapchain_colorspace.
+    pub fn wants_extended_color_spaces(&self) -> bool {
+        self.surface_formats
+            .iter()
+            .any(|f| f.color_space != vk::ColorSpaceKHR::SRGB_NONLINEAR)
+    }
+}
+
 pub struct SwapchainBase {
     pub loader: Swapchain,
     pub chain: vk::SwapchainKHR,
     pub format: vk::Format,
+    pub color_space: vk::ColorSpaceKHR,
     pub extent: vk::Extent2D,
+    /// What `SwapConfig::present_modes` resolved to on this surface.
+    pub present_mode: vk::PresentModeKHR,
+    /// Includes TRANSFER_SRC, needed for screenshots, when the surface supports it.
+    pub usage: vk::ImageUsageFlags,
     pub imgs: Vec<vk::Image>,
     pub depth_img: vk::Image,
     pub depth_img_fmt: vk::Format,
     pub depth_img_mem: vk::DeviceMemory,
+    /// What `SwapConfig::samples` clamped to. The depth image has this many samples.
+    pub samples: vk::SampleCountFlags,
+    /// The multisampled image rendered to and resolved into the swapchain image; null without
+    /// MSAA.
+    pub color_img: vk::Image,
+    pub color_img_mem: vk::DeviceMemory,
 }
 
 impl SwapchainBase {
-    fn make_views(&self, dev: &Device) -> (Vec<vk::ImageView>, vk::ImageView) {
-        (
-            self.imgs
-                .iter()
-                .map(|&img| {
-                    let create_view_info = vk::ImageViewCreateInfo::builder()
-                        .view_type(vk::ImageViewType::TYPE_2D)
-                        .format(self.format)
-                        .components(vk::ComponentMapping {
-                            r: vk::ComponentSwizzle::R,
-                            g: vk::ComponentSwizzle::G,
-                            b: vk::ComponentSwizzle::B,
-                            a: vk::ComponentSwizzle::A,
-                        })
-                        .subresource_range(vk::ImageSubresourceRange {
-                            aspect_mask: vk::ImageAspectFlags::COLOR,
-                            base_mip_level: 0,
-                            level_count: 1,
-                            base_array_layer: 0,
-                            layer_count: 1,
-                        })
-                        .image(img);
-                    unsafe { dev.create_image_view(&create_view_info, None).unwrap() }
+    /// Pushes a view per swapchain image onto `views` and stores the depth and multisampled
+    /// color views as each is made, so whatever exists at a failure gets cleaned up by the owner.
+    fn make_views(
+        &self,
+        dev: &Device,
+        views: &mut Vec<vk::ImageView>,
+        depth_view: &mut vk::ImageView,
+        color_view: &mut vk::ImageView,
+    ) -> Result<()> {
+        for &img in &self.imgs {
+            let create_view_info = vk::ImageViewCreateInfo::builder()
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(self.format)
+                .components(vk::ComponentMapping {
+                    r: vk::ComponentSwizzle::R,
+                    g: vk::ComponentSwizzle::G,
+                    b: vk::ComponentSwizzle::B,
+                    a: vk::ComponentSwizzle::A,
                 })
-                .collect(),
-            {
-                let info = vk::ImageViewCreateInfo::builder()
-                    .subresource_range(
-                        vk::ImageSubresourceRange::builder()
-                            .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                            .level_count(1)
-                            .layer_count(1)
-                            .build(),
-                    )
-                    .image(self.depth_img)
-                    .format(self.depth_img_fmt)
-                    .view_type(vk::ImageViewType::TYPE_2D);
-                unsafe { dev.create_image_view(&info, None).unwrap() }
-            },
-        )
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image(img);
+            views.push(unsafe { dev.create_image_view(&create_view_info, None)? });
+        }
+        let info = vk::ImageViewCreateInfo::builder()
+            .subresource_range(
+                vk::ImageSubresourceRange::builder()
+                    .aspect_mask(depth_aspect(self.depth_img_fmt))
+                    .level_count(1)
+                    .layer_count(1)
+                    .build(),
+            )
+            .image(self.depth_img)
+            .format(self.depth_img_fmt)
+            .view_type(vk::ImageViewType::TYPE_2D);
+        *depth_view = unsafe { dev.create_image_view(&info, None)? };
+        if self.is_multisampled() {
+            let info = vk::ImageViewCreateInfo::builder()
+                .subresource_range(
+                    vk::ImageSubresourceRange::builder()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .level_count(1)
+                        .layer_count(1)
+                        .build(),
+                )
+                .image(self.color_img)
+                .format(self.format)
+                .view_type(vk::ImageViewType::TYPE_2D);
+            *color_view = unsafe { dev.create_image_view(&info, None)? };
+        }
+        Ok(())
     }
 
     unsafe fn new(
@@ -181,11 +337,23 @@ impl SwapchainBase {
         pdevice: vk::PhysicalDevice,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
         window: &winit::Window,
-    ) -> Self {
-        let (capabilities, formats, present_modes) = query_support(pdevice, surface);
-        let format = choose_format(&formats);
-        let mode = choose_mode(&present_modes);
-        let extent = choose_extent(&capabilities, window);
+        config: &SwapConfig,
+        old_chain: vk::SwapchainKHR,
+    ) -> Result<Self> {
+        let (capabilities, formats, present_modes) = query_support(pdevice, surface)?;
+        let format = choose_format(&config.surface_formats, &formats)?;
+        let mode = choose_mode(&config.present_modes, &present_modes);
+        let extent = choose_extent(&capabilities, window)?;
+        let depth_img_fmt = find_depth_format(instance, pdevice, &config.depth_formats)?;
+        let limits = instance.get_physical_device_properties(pdevice).limits;
+        let samples = usable_samples(&limits, config.samples);
+        if samples != config.samples {
+            log::warn!(
+                "{:?} MSAA isn't supported, using {:?}",
+                config.samples,
+                samples
+            );
+        }
 
         let mut desired_image_count = capabilities.min_image_count + 1;
         if capabilities.max_image_count > 0 && desired_image_count > capabilities.max_image_count {
@@ -200,6 +368,9 @@ impl SwapchainBase {
             capabilities.current_transform
         };
 
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | (capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC);
+
         let swapchain_loader = Swapchain::new(instance, device);
         let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
             .surface(surface.surface)
@@ -207,77 +378,258 @@ impl SwapchainBase {
             .image_color_space(format.color_space)
             .image_format(format.format)
             .image_extent(extent)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(usage)
             .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
             .pre_transform(pre_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
             .present_mode(mode)
             .clipped(true)
+            .old_swapchain(old_chain)
             .image_array_layers(1);
-        let swapchain = swapchain_loader
-            .create_swapchain(&swapchain_create_info, None)
-            .unwrap();
+        let swapchain = swapchain_loader.create_swapchain(&swapchain_create_info, None)?;
+
+        let mut res = Self {
+            chain: swapchain,
+            format: format.format,
+            color_space: format.color_space,
+            extent,
+            present_mode: mode,
+            usage,
+            imgs: Vec::new(),
+            loader: swapchain_loader,
+            depth_img: vk::Image::null(),
+            depth_img_fmt,
+            depth_img_mem: vk::DeviceMemory::null(),
+            samples,
+            color_img: vk::Image::null(),
+            color_img_mem: vk::DeviceMemory::null(),
+        };
+        if let Err(e) = res.init(device, mem_prop) {
+            res.destroy(device);
+            return Err(e);
+        }
+        Ok(res)
+    }
+
+    /// Whether writes to the swapchain images are gamma encoded by the hardware, so shaders
+    /// should output linear color.
+    pub fn is_srgb(&self) -> bool {
+        matches!(
+            self.format,
+            vk::Format::B8G8R8A8_SRGB
+                | vk::Format::R8G8B8A8_SRGB
+                | vk::Format::A8B8G8R8_SRGB_PACK32
+        )
+    }
+
+    pub fn is_multisampled(&self) -> bool {
+        self.samples != vk::SampleCountFlags::TYPE_1
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.color_space,
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT
+                | vk::ColorSpaceKHR::HDR10_HLG_EXT
+                | vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT
+                | vk::ColorSpaceKHR::EXTENDED_SRGB_NONLINEAR_EXT
+                | vk::ColorSpaceKHR::DOLBYVISION_EXT
+        )
+    }
+
+    /// Fetches the swapchain images and makes the depth image, plus the multisampled color
+    /// image if there is one.
+    unsafe fn init(
+        &mut self,
+        device: &Device,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+    ) -> Result<()> {
+        self.imgs = self.loader.get_swapchain_images(self.chain)?;
 
         //println!("Making depth img");
-        let (depth_img, depth_img_fmt, depth_img_mem) = {
-            let fmt = vk::Format::D16_UNORM;
-            let create_info = vk::ImageCreateInfo::builder()
-                .image_type(vk::ImageType::TYPE_2D)
-                .format(fmt)
-                .extent(vk::Extent3D {
-                    width: extent.width,
-                    height: extent.height,
-                    depth: 1,
-                })
-                .mip_levels(1)
-                .array_layers(1)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .tiling(vk::ImageTiling::OPTIMAL)
-                .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE);
-            let depth_img = device.create_image(&create_info, None).unwrap();
-
-            let depth_image_memory_req = device.get_image_memory_requirements(depth_img);
-            let depth_image_memory_index = find_memorytype_index(
-                &depth_image_memory_req,
-                mem_prop,
-                vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            )
-            .expect("Unable to find suitable memory index for depth image.");
+        let create_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(self.depth_img_fmt)
+            .extent(vk::Extent3D {
+                width: self.extent.width,
+                height: self.extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(self.samples)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        self.depth_img = device.create_image(&create_info, None)?;
 
-            let depth_image_allocate_info = vk::MemoryAllocateInfo::builder()
-                .allocation_size(depth_image_memory_req.size)
-                .memory_type_index(depth_image_memory_index);
+        let depth_image_memory_req = device.get_image_memory_requirements(self.depth_img);
+        let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        let depth_image_memory_index =
+            find_memorytype_index(&depth_image_memory_req, mem_prop, flags)
+                .ok_or(Error::NoSuitableMemoryType(flags))?;
 
-            let depth_img_mem = device
-                .allocate_memory(&depth_image_allocate_info, None)
-                .unwrap();
+        let depth_image_allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(depth_image_memory_req.size)
+            .memory_type_index(depth_image_memory_index);
 
-            device
-                .bind_image_memory(depth_img, depth_img_mem, 0)
-                .unwrap();
+        self.depth_img_mem = device.allocate_memory(&depth_image_allocate_info, None)?;
 
-            (depth_img, fmt, depth_img_mem)
-        };
+        device.bind_image_memory(self.depth_img, self.depth_img_mem, 0)?;
         //println!("Done making depth img");
 
-        Self {
-            chain: swapchain,
-            format: format.format,
-            extent,
-            imgs: swapchain_loader.get_swapchain_images(swapchain).unwrap(),
-            loader: swapchain_loader,
-            depth_img,
-            depth_img_fmt,
-            depth_img_mem,
+        if self.is_multisampled() {
+            make_image(
+                device,
+                mem_prop,
+                self.format,
+                self.extent,
+                self.samples,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                &mut self.color_img,
+                &mut self.color_img_mem,
+            )?;
         }
+        Ok(())
     }
 
+    /// Safe to call more than once; handles are nulled as they're destroyed.
     unsafe fn destroy(&mut self, dev: &Device) {
         //println!("Destroying SwapBase");
         self.loader.destroy_swapchain(self.chain, None);
         dev.free_memory(self.depth_img_mem, None);
         dev.destroy_image(self.depth_img, None);
+        dev.free_memory(self.color_img_mem, None);
+        dev.destroy_image(self.color_img, None);
+        self.chain = vk::SwapchainKHR::null();
+        self.depth_img_mem = vk::DeviceMemory::null();
+        self.depth_img = vk::Image::null();
+        self.color_img_mem = vk::DeviceMemory::null();
+        self.color_img = vk::Image::null();
+        self.imgs.clear();
+    }
+}
+
+/// The swapchain's render pass, resolving into the presented image when multisampled.
+fn make_renderpass(dev: &Device, base: &SwapchainBase) -> Result<RenderPassToken> {
+    RenderPassBuilder::forward(
+        base.format,
+        base.depth_img_fmt,
+        base.samples,
+        vk::ImageLayout::PRESENT_SRC_KHR,
+    )
+    .build(dev.clone())
+}
+
+/// Objects replaced by `SwapToken::recreate` or `SwapToken::reload_shaders` that frames still
+/// in flight may be using.
+struct Retired {
+    /// None when only pipelines were replaced.
+    base: Option<SwapchainBase>,
+    img_views: Vec<vk::ImageView>,
+    depth_view: vk::ImageView,
+    color_view: vk::ImageView,
+    framebuffers: Vec<vk::Framebuffer>,
+    renderpass: Option<RenderPassToken>,
+    pipes: Vec<vk::Pipeline>,
+    frames_left: usize,
+}
+
+impl Retired {
+    fn pipes(pipes: Vec<vk::Pipeline>, frames_left: usize) -> Self {
+        Retired {
+            base: None,
+            img_views: Vec::new(),
+            depth_view: vk::ImageView::null(),
+            color_view: vk::ImageView::null(),
+            framebuffers: Vec::new(),
+            renderpass: None,
+            pipes,
+            frames_left,
+        }
+    }
+
+    unsafe fn destroy(mut self, dev: &Device) {
+        for buffer in self.framebuffers.drain(0..) {
+            dev.destroy_framebuffer(buffer, None);
+        }
+        for pipe in self.pipes.drain(0..) {
+            dev.destroy_pipeline(pipe, None);
+        }
+        dev.destroy_image_view(self.depth_view, None);
+        dev.destroy_image_view(self.color_view, None);
+        for view in self.img_views.drain(0..) {
+            dev.destroy_image_view(view, None);
+        }
+        if let Some(base) = &mut self.base {
+            base.destroy(dev);
+        }
+    }
+}
+
+/// The views and framebuffers made for one `SwapchainBase`.
+#[derive(Default)]
+struct Targets {
+    img_views: Vec<vk::ImageView>,
+    depth_view: vk::ImageView,
+    color_view: vk::ImageView,
+    framebuffers: Vec<vk::Framebuffer>,
+}
+
+impl Targets {
+    /// Nothing is kept on failure. Nothing is submitted either: the render pass clears depth
+    /// from UNDEFINED, so there's no layout transition to wait for.
+    unsafe fn new(dev: &Device, base: &SwapchainBase, renderpass: vk::RenderPass) -> Result<Self> {
+        let mut res = Self::default();
+        match res.fill(dev, base, renderpass) {
+            Ok(()) => Ok(res),
+            Err(e) => {
+                res.destroy(dev);
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn fill(
+        &mut self,
+        dev: &Device,
+        base: &SwapchainBase,
+        renderpass: vk::RenderPass,
+    ) -> Result<()> {
+        base.make_views(
+            dev,
+            &mut self.img_views,
+            &mut self.depth_view,
+            &mut self.color_view,
+        )?;
+        for present in &self.img_views {
+            let attachments = if base.is_multisampled() {
+                vec![self.color_view, self.depth_view, *present]
+            } else {
+                vec![*present, self.depth_view]
+            };
+            let frame_buffer_create_info = vk::FramebufferCreateInfo::builder()
+                .render_pass(renderpass)
+                .attachments(&attachments)
+                .width(base.extent.width)
+                .height(base.extent.height)
+                .layers(1);
+
+            self.framebuffers
+                .push(dev.create_framebuffer(&frame_buffer_create_info, None)?);
+        }
+        Ok(())
+    }
+
+    unsafe fn destroy(self, dev: &Device) {
+        for buffer in self.framebuffers {
+            dev.destroy_framebuffer(buffer, None);
+        }
+        dev.destroy_image_view(self.depth_view, None);
+        dev.destroy_image_view(self.color_view, None);
+        for view in self.img_views {
+            dev.destroy_image_view(view, None);
+        }
     }
 }
 
@@ -287,6 +639,8 @@ pub struct SwapToken {
     // pub surface_resolution: vk::Extent2D,
     pub base: SwapchainBase,
     pub depth_view: vk::ImageView,
+    /// View of `base.color_img`; null without MSAA.
+    pub color_view: vk::ImageView,
     pub img_views: Vec<vk::ImageView>,
 
     pub cmd_pool: CmdPool,
@@ -298,6 +652,21 @@ pub struct SwapToken {
     pub scissors: vk::Rect2D,
 
     pub pipes: HashMap<String, PipeToken>,
+
+    /// Applied on creation and on every `recreate`.
+    pub config: SwapConfig,
+
+    /// Names swapchain objects and pipelines made through `make_pipeline`.
+    pub namer: DebugNamer,
+
+    /// How many calls to `collect_retired` the objects replaced by `recreate` are kept alive
+    /// for; set it to the number of frames in flight, `DEFAULT_FRAMES_IN_FLIGHT` by default.
+    /// With 0, `recreate` waits for the device to go idle and destroys them straight away
+    /// instead.
+    pub retire_frames: usize,
+    retired: Vec<Retired>,
+    /// Used by `make_pipeline`; `VkDataBuilder::build` sets it to `VkData::pipeline_cache`.
+    pub pipeline_cache: vk::PipelineCache,
 }
 
 impl Drop for SwapToken {
@@ -319,10 +688,32 @@ impl SwapToken {
             self.dev.destroy_framebuffer(buffer, None)
         }
         self.dev.destroy_image_view(self.depth_view, None);
+        self.depth_view = vk::ImageView::null();
+        self.dev.destroy_image_view(self.color_view, None);
+        self.color_view = vk::ImageView::null();
         for img in self.img_views.drain(0..) {
             self.dev.destroy_image_view(img, None);
         }
         self.base.destroy(&self.dev);
+        for retired in self.retired.drain(0..) {
+            retired.destroy(&self.dev);
+        }
+    }
+
+    /// Destroys whatever `recreate` replaced `retire_frames` calls ago. Call it once per frame,
+    /// after waiting on that frame's fence.
+    pub fn collect_retired(&mut self) {
+        let (done, kept) = self
+            .retired
+            .drain(0..)
+            .partition::<Vec<_>, _>(|r| r.frames_left <= 1);
+        self.retired = kept;
+        for retired in &mut self.retired {
+            retired.frames_left -= 1;
+        }
+        for retired in done {
+            unsafe { retired.destroy(&self.dev) };
+        }
     }
 
     pub fn recreate(
@@ -332,32 +723,234 @@ impl SwapToken {
         surface: &SurfToken,
         window: &winit::Window,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
-        present_queue: vk::Queue,
-    ) {
-        eprintln!("Recreating Swapchain");
+    ) -> Result<()> {
+        log::info!("Recreating Swapchain");
         unsafe {
-            self.dev.device_wait_idle().unwrap();
-            self.clean();
+            // On failure this keeps the old swapchain, which Vulkan may have retired anyway.
+            // Acquiring from a retired swapchain reports ERROR_OUT_OF_DATE_KHR, so the caller
+            // just tries again.
+            let mut base = SwapchainBase::new(
+                instance,
+                &self.dev,
+                surface,
+                pdev,
+                mem_prop,
+                window,
+                &self.config,
+                self.base.chain,
+            )?;
+            // Pipelines use dynamic viewports, so they only depend on the render pass, which
+            // only changes with the formats or sample count
+            let compatible = base.format == self.base.format
+                && base.depth_img_fmt == self.base.depth_img_fmt
+                && base.samples == self.base.samples;
+            // Everything that can fail is made before anything is replaced, so on failure the
+            // old render pass, pipelines, views and framebuffers are all still in place
+            let rebuilt = if compatible {
+                None
+            } else {
+                match self.rebuild_pipes(&base) {
+                    Ok(rebuilt) => Some(rebuilt),
+                    Err(e) => {
+                        base.destroy(&self.dev);
+                        return Err(e);
+                    }
+                }
+            };
+            let renderpass = match &rebuilt {
+                Some((renderpass, _)) => renderpass.renderpass,
+                None => self.renderpass.renderpass,
+            };
+            let targets = match Targets::new(&self.dev, &base, renderpass) {
+                Ok(targets) => targets,
+                Err(e) => {
+                    // The new render pass is dropped after its pipelines
+                    if let Some((_renderpass, new_pipes)) = rebuilt {
+                        for (_, new) in new_pipes {
+                            self.dev.destroy_pipeline(new, None);
+                        }
+                    }
+                    base.destroy(&self.dev);
+                    return Err(e);
+                }
+            };
+            let old_base = std::mem::replace(&mut self.base, base);
+            self.retired.push(Retired {
+                base: Some(old_base),
+                img_views: std::mem::take(&mut self.img_views),
+                depth_view: std::mem::replace(&mut self.depth_view, vk::ImageView::null()),
+                color_view: std::mem::replace(&mut self.color_view, vk::ImageView::null()),
+                framebuffers: std::mem::take(&mut self.framebuffers),
+                renderpass: None,
+                pipes: Vec::new(),
+                frames_left: self.retire_frames,
+            });
+
+            if let Some((renderpass, mut new_pipes)) = rebuilt {
+                let retired = self.retired.last_mut().unwrap();
+                retired.renderpass = Some(std::mem::replace(&mut self.renderpass, renderpass));
+                for (id, pipe) in self.pipes.iter_mut() {
+                    // Still bound in frames in flight; retired instead of destroyed
+                    let new = new_pipes.remove(id).unwrap();
+                    retired.pipes.push(std::mem::replace(&mut pipe.pipe, new));
+                    pipe.set_name(&self.namer, id);
+                }
+            }
+            self.set_targets(targets);
 
-            self.base = SwapchainBase::new(&instance, &self.dev, &surface, pdev, mem_prop, window);
-            let (depth_view, img_views, renderpass, framebuffers, viewport, scissors) =
-                Self::create(
-                    &self.dev,
-                    &self.base,
-                    self.cmd_pool.buffers[0],
-                    present_queue,
-                );
-            self.depth_view = depth_view;
-            self.img_views = img_views;
-            self.renderpass = renderpass;
-            self.framebuffers = framebuffers;
-            self.viewport = viewport;
-            self.scissors = scissors;
-            for (id, pipe) in self.pipes.iter_mut() {
-                eprintln!("Recreating Pipeline: {}", id);
-                pipe.recreate(self.renderpass.renderpass, self.viewport, self.scissors)
+            if self.retire_frames == 0 {
+                self.dev.device_wait_idle()?;
+                for retired in self.retired.drain(0..) {
+                    retired.destroy(&self.dev);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A render pass for `base` and every pipeline remade for it. Nothing is kept on failure.
+    unsafe fn rebuild_pipes(
+        &self,
+        base: &SwapchainBase,
+    ) -> Result<(RenderPassToken, HashMap<String, vk::Pipeline>)> {
+        let renderpass =
+            make_renderpass(&self.dev, base)?.named(&self.namer, "swapchain renderpass");
+        let mut pipes = HashMap::new();
+        for (id, pipe) in &self.pipes {
+            log::info!("Recreating Pipeline: {}", id);
+            match pipe.remake(
+                renderpass.renderpass,
+                self.viewport,
+                self.scissors,
+                base.samples,
+            ) {
+                Ok(new) => {
+                    pipes.insert(id.clone(), new);
+                }
+                Err(e) => {
+                    for (_, new) in pipes {
+                        self.dev.destroy_pipeline(new, None);
+                    }
+                    return Err(e);
+                }
             }
         }
+        Ok((renderpass, pipes))
+    }
+
+    /// Rebuilds every pipeline with a shader compiled from one of `changed`, such as the files
+    /// from `ShaderWatcher::changed`, and returns how many were rebuilt. Call it between frames,
+    /// outside of command recording; the replaced pipelines are retired as in `recreate`. A
+    /// pipeline whose shaders fail to compile or no longer fit its layout is kept as it was, and
+    /// the error is logged.
+    pub fn reload_shaders(
+        &mut self,
+        changed: &[PathBuf],
+        compiler: &mut Compiler,
+        opt: Option<&CompileOptions>,
+    ) -> Result<usize> {
+        fn canonical(path: &Path) -> PathBuf {
+            std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+        }
+        let changed = changed.iter().map(|p| canonical(p)).collect::<Vec<_>>();
+        let mut old = Vec::new();
+        for (id, pipe) in self.pipes.iter_mut() {
+            if !pipe.sources().any(|s| changed.contains(&canonical(s))) {
+                continue;
+            }
+            log::info!("Reloading Pipeline: {}", id);
+            match pipe.reload(
+                compiler,
+                opt,
+                self.renderpass.renderpass,
+                self.viewport,
+                self.scissors,
+                self.base.samples,
+            ) {
+                Ok(pipe_old) => {
+                    old.push(pipe_old);
+                    pipe.set_name(&self.namer, id);
+                }
+                Err(e) => log::error!("Keeping the old {} pipeline: {}", id, e),
+            }
+        }
+        let count = old.len();
+        if count > 0 {
+            self.retired.push(Retired::pipes(old, self.retire_frames));
+            if self.retire_frames == 0 {
+                unsafe {
+                    self.dev.device_wait_idle()?;
+                    for retired in self.retired.drain(0..) {
+                        retired.destroy(&self.dev);
+                    }
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Takes effect on the next `recreate`.
+    pub fn set_present_modes(&mut self, modes: Vec<vk::PresentModeKHR>) {
+        self.config.present_modes = modes;
+    }
+
+    /// Takes effect on the next `recreate`, which rebuilds the render pass and every pipeline.
+    pub fn set_samples(&mut self, samples: vk::SampleCountFlags) {
+        self.config.samples = samples;
+    }
+
+    /// Starts copying swapchain image `image_index` out, once it has been rendered, for
+    /// `Readback::to_image` after `cmd_buf` completes.
+    pub unsafe fn record_screenshot(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: u32,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+    ) -> Result<Readback> {
+        if !self.base.usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+            return Err(Error::Unsupported(
+                "Copying from this surface's images".to_string(),
+            ));
+        }
+        let readback = Readback::new(
+            self.dev.clone(),
+            mem_prop,
+            self.base.extent,
+            self.base.format,
+        )?;
+        readback.record(
+            &self.dev,
+            cmd_buf,
+            self.base.imgs[image_index as usize],
+            vk::ImageLayout::PRESENT_SRC_KHR,
+        );
+        Ok(readback)
+    }
+
+    /// Copies swapchain image `image_index`, as last rendered, to RGBA8. Blocks until the device
+    /// is idle; `Renderer::capture_next_frame` captures without stalling.
+    pub fn screenshot(
+        &self,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        queue: vk::Queue,
+        image_index: u32,
+    ) -> Result<image::RgbaImage> {
+        unsafe {
+            self.dev.device_wait_idle()?;
+            let mut readback = None;
+            crate::command::record_submit_commandbuffer(
+                &self.dev,
+                self.cmd_pool.buffers[0],
+                queue,
+                &[],
+                &[],
+                &[],
+                |_, cmd_buf| {
+                    readback = Some(self.record_screenshot(cmd_buf, image_index, mem_prop))
+                },
+            )?;
+            readback.unwrap()?.to_image()
+        }
     }
 
     pub fn renderpass_begin_info(
@@ -382,63 +975,87 @@ impl SwapToken {
         surface: &SurfToken,
         pdevice: vk::PhysicalDevice,
         queue_fam: u32,
-        present_queue: vk::Queue,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
         window: &winit::Window,
-    ) -> Self {
-        let base =
-            unsafe { SwapchainBase::new(instance, &dev, surface, pdevice, mem_prop, window) };
-
-        let cmd_pool = CmdPool::new(dev.clone(), queue_fam, 2);
-        let (depth_view, img_views, renderpass, framebuffers, viewport, scissors) =
-            Self::create(&dev, &base, cmd_pool.buffers[0], present_queue);
-        Self {
+        namer: DebugNamer,
+        config: SwapConfig,
+    ) -> Result<Self> {
+        let mut base = unsafe {
+            SwapchainBase::new(
+                instance,
+                &dev,
+                surface,
+                pdevice,
+                mem_prop,
+                window,
+                &config,
+                vk::SwapchainKHR::null(),
+            )?
+        };
+        let parts = CmdPool::new(dev.clone(), queue_fam, 2).and_then(|cmd_pool| {
+            let renderpass = make_renderpass(&dev, &base)?;
+            Ok((cmd_pool, renderpass))
+        });
+        let (cmd_pool, renderpass) = match parts {
+            Ok(parts) => parts,
+            Err(e) => {
+                unsafe { base.destroy(&dev) };
+                return Err(e);
+            }
+        };
+        let mut res = Self {
             dev,
             base,
-            depth_view,
-            img_views,
-            cmd_pool,
-            renderpass,
-            framebuffers,
-            viewport,
-            scissors,
+            depth_view: vk::ImageView::null(),
+            color_view: vk::ImageView::null(),
+            img_views: Vec::new(),
+            cmd_pool: cmd_pool.named(&namer, "swapchain commands"),
+            renderpass: renderpass.named(&namer, "swapchain renderpass"),
+            framebuffers: Vec::new(),
+            viewport: Default::default(),
+            scissors: Default::default(),
             pipes: HashMap::new(),
+            namer,
+            config,
+            retire_frames: DEFAULT_FRAMES_IN_FLIGHT,
+            pipeline_cache: vk::PipelineCache::null(),
+            retired: Vec::new(),
+        };
+        // On failure, dropping `res` destroys the swapchain and render pass
+        unsafe {
+            let targets = Targets::new(&res.dev, &res.base, res.renderpass.renderpass)?;
+            res.set_targets(targets);
         }
+        Ok(res)
     }
 
-    fn create(
-        dev: &Device,
-        base: &SwapchainBase,
-        setup_command_buffer: vk::CommandBuffer,
-        present_queue: vk::Queue,
-    ) -> (
-        vk::ImageView,
-        Vec<vk::ImageView>,
-        RenderPassToken,
-        Vec<vk::Framebuffer>,
-        vk::Viewport,
-        vk::Rect2D,
-    ) {
-        let (img_views, depth_view) = base.make_views(&dev);
-        let renderpass = RenderPassToken::new(dev.clone(), base.format);
-        let framebuffers: Vec<vk::Framebuffer> = img_views
-            .iter()
-            .map(|present| {
-                let attachments = [*present, depth_view];
-                let frame_buffer_create_info = vk::FramebufferCreateInfo::builder()
-                    .render_pass(renderpass.renderpass)
-                    .attachments(&attachments)
-                    .width(base.extent.width)
-                    .height(base.extent.height)
-                    .layers(1);
-
-                unsafe {
-                    dev.create_framebuffer(&frame_buffer_create_info, None)
-                        .unwrap()
-                }
-            })
-            .collect();
-        let viewport = vk::Viewport {
+    /// Takes `targets`, made for `self.base`, whose old views and framebuffers must have been
+    /// moved out already, and names them.
+    fn set_targets(&mut self, targets: Targets) {
+        self.img_views = targets.img_views;
+        self.depth_view = targets.depth_view;
+        self.color_view = targets.color_view;
+        self.framebuffers = targets.framebuffers;
+        let base = &self.base;
+        let namer = &self.namer;
+        namer.name(base.chain, "swapchain");
+        namer.name(base.depth_img, "swapchain depth");
+        namer.name(base.depth_img_mem, "swapchain depth memory");
+        namer.name(self.depth_view, "swapchain depth view");
+        if base.is_multisampled() {
+            namer.name(base.color_img, "swapchain msaa color");
+            namer.name(base.color_img_mem, "swapchain msaa color memory");
+            namer.name(self.color_view, "swapchain msaa color view");
+        }
+        for (i, img) in base.imgs.iter().enumerate() {
+            namer.name(*img, &format!("swapchain image {}", i));
+            namer.name(self.img_views[i], &format!("swapchain image view {}", i));
+            namer.name(
+                self.framebuffers[i],
+                &format!("swapchain framebuffer {}", i),
+            );
+        }
+        self.viewport = vk::Viewport {
             x: 0.0,
             y: 0.0,
             width: base.extent.width as _,
@@ -446,56 +1063,10 @@ impl SwapToken {
             min_depth: 0.0,
             max_depth: 1.0,
         };
-        let scissors = vk::Rect2D {
+        self.scissors = vk::Rect2D {
             extent: base.extent,
             ..Default::default()
         };
-
-        unsafe {
-            crate::command::record_submit_commandbuffer(
-                dev,
-                setup_command_buffer,
-                present_queue,
-                &[],
-                &[],
-                &[],
-                |device, setup_command_buffer| {
-                    let layout_transition_barriers = vk::ImageMemoryBarrier::builder()
-                        .image(base.depth_img)
-                        .dst_access_mask(
-                            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
-                                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
-                        )
-                        .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                        .old_layout(vk::ImageLayout::UNDEFINED)
-                        .subresource_range(
-                            vk::ImageSubresourceRange::builder()
-                                .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                                .layer_count(1)
-                                .level_count(1)
-                                .build(),
-                        );
-
-                    device.cmd_pipeline_barrier(
-                        setup_command_buffer,
-                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                        vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
-                        vk::DependencyFlags::empty(),
-                        &[],
-                        &[],
-                        &[layout_transition_barriers.build()],
-                    );
-                },
-            );
-        }
-        (
-            depth_view,
-            img_views,
-            renderpass,
-            framebuffers,
-            viewport,
-            scissors,
-        )
     }
 
     pub fn make_pipeline(
@@ -504,18 +1075,22 @@ impl SwapToken {
         pool: DescPoolToken,
         shaders: HashMap<ShaderStage, ShaderArtifact>,
         push_consts: Vec<&PushConstant>,
-    ) {
-        self.pipes.insert(
-            id,
-            PipeToken::build(
-                self.dev.clone(),
-                pool,
-                push_consts,
-                self.renderpass.renderpass,
-                self.viewport,
-                self.scissors,
-                shaders,
-            ),
-        );
+        desc: PipelineDesc,
+    ) -> Result<()> {
+        let pipe = PipeToken::build(
+            self.dev.clone(),
+            pool,
+            push_consts,
+            self.renderpass.renderpass,
+            self.viewport,
+            self.scissors,
+            self.base.samples,
+            desc,
+            self.pipeline_cache,
+            shaders,
+        )?
+        .named(&self.namer, &id);
+        self.pipes.insert(id, pipe);
+        Ok(())
     }
 }
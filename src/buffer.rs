@@ -1,4 +1,5 @@
-use crate::find_memorytype_index;
+use crate::debug::{DebugName, DebugNamer};
+use crate::{find_memorytype_index, Error, Result};
 use ash::{util::Align, version::DeviceV1_0, vk, vk::DeviceMemory, Device};
 
 use std::fmt::Debug;
@@ -48,6 +49,23 @@ impl Drop for BufToken {
     }
 }
 
+impl DebugName for Buffer {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        use Buffer::*;
+        match self {
+            Raw(t) => t.set_name(namer, name),
+            Struct(s) => s.buf.set_name(namer, name),
+        }
+    }
+}
+
+impl DebugName for BufToken {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.buf, name);
+        namer.name(self.mem, &format!("{} memory", name));
+    }
+}
+
 impl BufToken {
     pub fn buf_info(&self) -> vk::DescriptorBufferInfo {
         vk::DescriptorBufferInfo {
@@ -57,33 +75,53 @@ impl BufToken {
         }
     }
 
-    pub fn map_write<F, D>(&self, w: F)
+    pub fn map_write<F, D>(&self, w: F) -> Result<()>
     where
         F: Fn(Align<D>),
     {
         unsafe {
             w(Align::new(
                 self.dev
-                    .map_memory(self.mem, 0, self.size, vk::MemoryMapFlags::empty())
-                    .unwrap(),
+                    .map_memory(self.mem, 0, self.size, vk::MemoryMapFlags::empty())?,
                 std::mem::align_of::<D>() as u64,
                 self.size,
             ));
             self.dev.unmap_memory(self.mem)
         }
+        Ok(())
     }
 
-    pub fn write<D: Copy>(&self, data: &[D]) {
+    pub fn write<D: Copy>(&self, data: &[D]) -> Result<()> {
         self.map_write(|mut align| align.copy_from_slice(data))
     }
 
+    pub fn read<D: Copy>(&self) -> Result<Vec<D>> {
+        let len = self.size as usize / std::mem::size_of::<D>();
+        unsafe {
+            let ptr = self
+                .dev
+                .map_memory(self.mem, 0, self.size, vk::MemoryMapFlags::empty())?;
+            let res = self
+                .dev
+                .invalidate_mapped_memory_ranges(&[vk::MappedMemoryRange {
+                    memory: self.mem,
+                    offset: 0,
+                    size: vk::WHOLE_SIZE,
+                    ..Default::default()
+                }])
+                .map(|_| std::slice::from_raw_parts(ptr as *const D, len).to_vec());
+            self.dev.unmap_memory(self.mem);
+            Ok(res?)
+        }
+    }
+
     pub fn with_size(
         dev: Device,
         usage: vk::BufferUsageFlags,
         sharing_mode: vk::SharingMode,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
         size: vk::DeviceSize,
-    ) -> Self {
+    ) -> Result<Self> {
         let buf = unsafe {
             dev.create_buffer(
                 &vk::BufferCreateInfo {
@@ -94,13 +132,20 @@ impl BufToken {
                 },
                 None,
             )
-        }
-        .unwrap();
-        let req = unsafe { dev.get_buffer_memory_requirements(buf) };
-        let mem_type_index =
-            find_memorytype_index(&req, &mem_prop, vk::MemoryPropertyFlags::HOST_VISIBLE).unwrap();
-        let mem = unsafe {
-            dev.allocate_memory(
+        }?;
+        // From here on the token owns buf and mem, so an early return cleans them up
+        let mut res = BufToken {
+            dev,
+            size,
+            buf,
+            mem: vk::DeviceMemory::null(),
+        };
+        let req = unsafe { res.dev.get_buffer_memory_requirements(buf) };
+        let flags = vk::MemoryPropertyFlags::HOST_VISIBLE;
+        let mem_type_index = find_memorytype_index(&req, &mem_prop, flags)
+            .ok_or(Error::NoSuitableMemoryType(flags))?;
+        res.mem = unsafe {
+            res.dev.allocate_memory(
                 &vk::MemoryAllocateInfo {
                     allocation_size: req.size,
                     memory_type_index: mem_type_index,
@@ -108,15 +153,10 @@ impl BufToken {
                 },
                 None,
             )
-        }
-        .unwrap();
-        unsafe { dev.bind_buffer_memory(buf, mem, 0) }.unwrap();
-        BufToken {
-            dev,
-            size: req.size,
-            buf,
-            mem,
-        }
+        }?;
+        res.size = req.size;
+        unsafe { res.dev.bind_buffer_memory(buf, res.mem, 0) }?;
+        Ok(res)
     }
 
     pub fn with_len<D>(
@@ -125,7 +165,7 @@ impl BufToken {
         sharing_mode: vk::SharingMode,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
         len: usize,
-    ) -> Self {
+    ) -> Result<Self> {
         Self::with_size(
             dev,
             usage,
@@ -141,9 +181,9 @@ impl BufToken {
         sharing_mode: vk::SharingMode,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
         data: &[D],
-    ) -> Self {
-        let res = Self::with_len::<D>(dev, usage, sharing_mode, mem_prop, data.len());
-        res.write(data);
-        res
+    ) -> Result<Self> {
+        let res = Self::with_len::<D>(dev, usage, sharing_mode, mem_prop, data.len())?;
+        res.write(data)?;
+        Ok(res)
     }
 }
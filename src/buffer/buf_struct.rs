@@ -1,4 +1,5 @@
 use crate::buffer::BufToken;
+use crate::Result;
 
 use ash::{util::Align, version::DeviceV1_0, vk, Device};
 use std::collections::HashMap;
@@ -16,30 +17,28 @@ pub struct BufField {
 }
 
 impl BufStruct {
-    pub fn map_write<F, D>(&self, field: &str, w: F)
+    pub fn map_write<F, D>(&self, field: &str, w: F) -> Result<()>
     where
         F: Fn(Align<D>),
     {
         let field = self.fields[field];
         unsafe {
             w(Align::new(
-                self.buf
-                    .dev
-                    .map_memory(
-                        self.buf.mem,
-                        field.offset,
-                        field.size,
-                        vk::MemoryMapFlags::empty(),
-                    )
-                    .unwrap(),
+                self.buf.dev.map_memory(
+                    self.buf.mem,
+                    field.offset,
+                    field.size,
+                    vk::MemoryMapFlags::empty(),
+                )?,
                 std::mem::align_of::<D>() as u64,
                 field.size,
             ));
             self.buf.dev.unmap_memory(self.buf.mem)
         }
+        Ok(())
     }
 
-    pub fn write<D: Copy>(&self, field: &str, data: &[D]) {
+    pub fn write<D: Copy>(&self, field: &str, data: &[D]) -> Result<()> {
         if std::mem::size_of_val(data) as u64 != self.fields[field].size {
             panic!("Input data size does not match size of field: {}.", field);
         }
@@ -52,7 +51,7 @@ impl BufStruct {
         sharing_mode: vk::SharingMode,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
         field_iter: I,
-    ) -> Self
+    ) -> Result<Self>
     where
         I: Iterator<Item = (String, vk::DeviceSize, vk::DeviceSize)>,
     {
@@ -67,7 +66,7 @@ impl BufStruct {
             }
         }
         let size = far_offset + far_size;
-        let buf = BufToken::with_size(dev, usage, sharing_mode, mem_prop, size);
-        BufStruct { buf, fields }
+        let buf = BufToken::with_size(dev, usage, sharing_mode, mem_prop, size)?;
+        Ok(BufStruct { buf, fields })
     }
 }
@@ -1,3 +1,5 @@
+use crate::debug::{DebugName, DebugNamer};
+use crate::Result;
 use ash::Device;
 use ash::{version::DeviceV1_0, vk};
 
@@ -22,22 +24,36 @@ impl Drop for CmdPool {
     }
 }
 
+impl DebugName for CmdPool {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.pool, name);
+        for (i, buf) in self.buffers.iter().enumerate() {
+            namer.name(*buf, &format!("{}[{}]", name, i));
+        }
+    }
+}
+
 impl CmdPool {
-    pub fn new(dev: Device, queue_fam: u32, buf_count: u32) -> Self {
+    pub fn new(dev: Device, queue_fam: u32, buf_count: u32) -> Result<Self> {
         let pool = unsafe {
             let info = vk::CommandPoolCreateInfo::builder()
                 .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
                 .queue_family_index(queue_fam);
-            dev.create_command_pool(&info, None).unwrap()
+            dev.create_command_pool(&info, None)?
+        };
+        let mut res = Self {
+            dev,
+            pool,
+            buffers: Vec::new(),
         };
-        let buffers = unsafe {
+        res.buffers = unsafe {
             let info = vk::CommandBufferAllocateInfo::builder()
                 .command_buffer_count(buf_count)
                 .command_pool(pool)
                 .level(vk::CommandBufferLevel::PRIMARY);
-            dev.allocate_command_buffers(&info).unwrap()
+            res.dev.allocate_command_buffers(&info)?
         };
-        Self { dev, pool, buffers }
+        Ok(res)
     }
 
     pub fn record<F: FnOnce(&Device, vk::CommandBuffer)>(
@@ -45,18 +61,21 @@ impl CmdPool {
         buffer: usize,
         usage: vk::CommandBufferUsageFlags,
         f: F,
-    ) {
+    ) -> Result<()> {
         let buffer = self.buffers[buffer];
         // self.dev
         //     .reset_command_buffer(buffer, vk::CommandBufferResetFlags::RELEASE_RESOURCES);
         let info = vk::CommandBufferBeginInfo::builder().flags(usage);
         unsafe {
-            self.dev.begin_command_buffer(buffer, &info).unwrap();
+            self.dev.begin_command_buffer(buffer, &info)?;
             f(&self.dev, buffer);
-            self.dev.end_command_buffer(buffer).unwrap();
+            self.dev.end_command_buffer(buffer)?;
         }
+        Ok(())
     }
 
+    /// Submits and blocks until the work is done. Prefer `submit_with_fence` (or `FrameSync`)
+    /// for per-frame work.
     pub fn submit(
         &self,
         queue: vk::Queue,
@@ -64,7 +83,41 @@ impl CmdPool {
         wait_semaphores: &[vk::Semaphore],
         signal_semaphores: &[vk::Semaphore],
         buffers: &[usize],
-    ) {
+    ) -> Result<()> {
+        unsafe {
+            let submit_fence = self
+                .dev
+                .create_fence(&vk::FenceCreateInfo::default(), None)?;
+
+            let res = self
+                .submit_with_fence(
+                    queue,
+                    wait_mask,
+                    wait_semaphores,
+                    signal_semaphores,
+                    buffers,
+                    submit_fence,
+                )
+                .and_then(|_| {
+                    self.dev
+                        .wait_for_fences(&[submit_fence], true, std::u64::MAX)
+                        .map_err(Into::into)
+                });
+            self.dev.destroy_fence(submit_fence, None);
+            res
+        }
+    }
+
+    /// Submits without waiting; `fence` (which may be null) is signaled when the work completes.
+    pub fn submit_with_fence(
+        &self,
+        queue: vk::Queue,
+        wait_mask: &[vk::PipelineStageFlags],
+        wait_semaphores: &[vk::Semaphore],
+        signal_semaphores: &[vk::Semaphore],
+        buffers: &[usize],
+        fence: vk::Fence,
+    ) -> Result<()> {
         let command_buffers = {
             let mut res = Vec::new();
             for i in buffers {
@@ -80,19 +133,10 @@ impl CmdPool {
             .signal_semaphores(signal_semaphores);
 
         unsafe {
-            let submit_fence = self
-                .dev
-                .create_fence(&vk::FenceCreateInfo::default(), None)
-                .unwrap();
-
-            self.dev
-                .queue_submit(queue, &[submit_info.build()], submit_fence)
-                .unwrap();
             self.dev
-                .wait_for_fences(&[submit_fence], true, std::u64::MAX)
-                .unwrap();
-            self.dev.destroy_fence(submit_fence, None);
+                .queue_submit(queue, &[submit_info.build()], fence)?;
         }
+        Ok(())
     }
 }
 
@@ -104,29 +148,21 @@ pub fn record_submit_commandbuffer<D: DeviceV1_0, F: FnOnce(&D, vk::CommandBuffe
     wait_semaphores: &[vk::Semaphore],
     signal_semaphores: &[vk::Semaphore],
     f: F,
-) {
+) -> Result<()> {
     unsafe {
-        device
-            .reset_command_buffer(
-                command_buffer,
-                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
-            )
-            .expect("Reset command buffer failed.");
+        device.reset_command_buffer(
+            command_buffer,
+            vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+        )?;
 
         let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
-        device
-            .begin_command_buffer(command_buffer, &command_buffer_begin_info)
-            .expect("Begin commandbuffer");
+        device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
         f(device, command_buffer);
-        device
-            .end_command_buffer(command_buffer)
-            .expect("End commandbuffer");
+        device.end_command_buffer(command_buffer)?;
 
-        let submit_fence = device
-            .create_fence(&vk::FenceCreateInfo::default(), None)
-            .expect("Create fence failed.");
+        let submit_fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
 
         let command_buffers = vec![command_buffer];
 
@@ -136,12 +172,10 @@ pub fn record_submit_commandbuffer<D: DeviceV1_0, F: FnOnce(&D, vk::CommandBuffe
             .command_buffers(&command_buffers)
             .signal_semaphores(signal_semaphores);
 
-        device
+        let res = device
             .queue_submit(submit_queue, &[submit_info.build()], submit_fence)
-            .expect("queue submit failed.");
-        device
-            .wait_for_fences(&[submit_fence], true, std::u64::MAX)
-            .expect("Wait for fence failed.");
+            .and_then(|_| device.wait_for_fences(&[submit_fence], true, std::u64::MAX));
         device.destroy_fence(submit_fence, None);
+        Ok(res?)
     }
 }
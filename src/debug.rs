@@ -0,0 +1,456 @@
+use crate::Result;
+use ash::extensions::ext::{DebugReport, DebugUtils};
+use ash::vk::Handle;
+use ash::{vk, Device, Entry, Instance};
+
+use std::any::Any;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+/// Receives every validation message in place of the default `log` output. It's called from
+/// the driver, which a panic can't unwind through, so a panic is caught there and raised again
+/// by `DebugToken::resume_panic`; `Renderer::frame` does that every frame.
+pub type DebugCallback = dyn Fn(&DebugMessage) + Send + Sync;
+
+/// A validation/driver message, normalized across debug-utils and debug-report.
+#[derive(Debug, Clone)]
+pub struct DebugMessage<'a> {
+    pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub ty: vk::DebugUtilsMessageTypeFlagsEXT,
+    pub id_name: Option<&'a str>,
+    pub id_number: i32,
+    pub message: &'a str,
+    /// Type, handle, and debug name (if one was set) of each object involved.
+    pub objects: Vec<(vk::ObjectType, u64, Option<&'a str>)>,
+    pub queue_labels: Vec<&'a str>,
+    pub cmd_buf_labels: Vec<&'a str>,
+}
+
+impl<'a> DebugMessage<'a> {
+    pub fn is_error(&self) -> bool {
+        self.severity
+            .contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR)
+    }
+
+    pub fn level(&self) -> log::Level {
+        use vk::DebugUtilsMessageSeverityFlagsEXT as S;
+        if self.severity.contains(S::ERROR) {
+            log::Level::Error
+        } else if self.severity.contains(S::WARNING) {
+            log::Level::Warn
+        } else if self.severity.contains(S::INFO) {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        }
+    }
+}
+
+impl<'a> std::fmt::Display for DebugMessage<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use vk::DebugUtilsMessageTypeFlagsEXT as T;
+        let ty = if self.ty.contains(T::VALIDATION) {
+            "validation"
+        } else if self.ty.contains(T::PERFORMANCE) {
+            "performance"
+        } else {
+            "general"
+        };
+        write!(f, "[{}] ", ty)?;
+        if let Some(id) = self.id_name {
+            write!(f, "{}: ", id)?;
+        }
+        write!(f, "{}", self.message)?;
+        for (ty, handle, name) in &self.objects {
+            match name {
+                Some(name) => write!(f, "\n    object {:?} {:#x} \"{}\"", ty, handle, name)?,
+                None => write!(f, "\n    object {:?} {:#x}", ty, handle)?,
+            }
+        }
+        for label in &self.queue_labels {
+            write!(f, "\n    queue label \"{}\"", label)?;
+        }
+        for label in &self.cmd_buf_labels {
+            write!(f, "\n    command buffer label \"{}\"", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// The default output: every message goes to `log` under the `flint::vulkan` target.
+pub fn log_message(msg: &DebugMessage) {
+    log::log!(target: "flint::vulkan", msg.level(), "{}", msg);
+}
+
+enum Messenger {
+    Utils(DebugUtils, vk::DebugUtilsMessengerEXT),
+    Report(DebugReport, vk::DebugReportCallbackEXT),
+    None,
+}
+
+/// What the driver's callbacks get as user data.
+struct CallbackState {
+    callback: Option<Arc<DebugCallback>>,
+    /// The first panic out of `callback` not yet resumed.
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl CallbackState {
+    fn dispatch(&self, msg: &DebugMessage) {
+        match &self.callback {
+            Some(cb) => {
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cb(msg))) {
+                    let mut panic = self.panic.lock().unwrap_or_else(|e| e.into_inner());
+                    panic.get_or_insert(payload);
+                }
+            }
+            None => log_message(msg),
+        }
+    }
+
+    fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        self.panic.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+/// Owns the debug messenger, or the debug-report callback when debug-utils isn't available.
+pub struct DebugToken {
+    messenger: Messenger,
+    // Boxed so the pointer handed to the driver stays put
+    state: Box<CallbackState>,
+}
+
+impl DebugToken {
+    /// Installs on whichever of debug-utils and debug-report is in `enabled_extensions`,
+    /// preferring debug-utils.
+    pub unsafe fn new(
+        entry: &Entry,
+        instance: &Instance,
+        enabled_extensions: &[CString],
+        severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+        callback: Option<Arc<DebugCallback>>,
+    ) -> Result<Self> {
+        let state = Box::new(CallbackState {
+            callback,
+            panic: Mutex::new(None),
+        });
+        let user_data = &*state as *const CallbackState as *mut c_void;
+        let enabled = |name: &CStr| enabled_extensions.iter().any(|e| e.as_c_str() == name);
+        let messenger = if enabled(DebugUtils::name()) {
+            let info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
+                .message_severity(severity)
+                .message_type(
+                    vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                        | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                        | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+                )
+                .pfn_user_callback(Some(debug_utils_callback))
+                .user_data(user_data);
+            let loader = DebugUtils::new(entry, instance);
+            let messenger = loader.create_debug_utils_messenger(&info, None)?;
+            Messenger::Utils(loader, messenger)
+        } else if enabled(DebugReport::name()) {
+            let info = vk::DebugReportCallbackCreateInfoEXT::builder()
+                .flags(report_flags(severity))
+                .pfn_callback(Some(debug_report_callback))
+                .user_data(user_data);
+            let loader = DebugReport::new(entry, instance);
+            let report = loader.create_debug_report_callback(&info, None)?;
+            Messenger::Report(loader, report)
+        } else {
+            Messenger::None
+        };
+        Ok(Self { messenger, state })
+    }
+
+    /// The payload of a panic in the `DebugCallback` since the last call, if there was one.
+    pub fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        self.state.take_panic()
+    }
+
+    /// Raises a panic from the `DebugCallback` again, on the caller's thread, if there was one
+    /// since the last call.
+    pub fn resume_panic(&self) {
+        if let Some(payload) = self.take_panic() {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    pub fn uses_debug_utils(&self) -> bool {
+        self.utils().is_some()
+    }
+
+    pub fn utils(&self) -> Option<&DebugUtils> {
+        match &self.messenger {
+            Messenger::Utils(loader, _) => Some(loader),
+            _ => None,
+        }
+    }
+
+    /// Must be called before the instance is destroyed.
+    pub unsafe fn destroy(&mut self) {
+        match std::mem::replace(&mut self.messenger, Messenger::None) {
+            Messenger::Utils(loader, messenger) => {
+                loader.destroy_debug_utils_messenger(messenger, None)
+            }
+            Messenger::Report(loader, report) => loader.destroy_debug_report_callback(report, None),
+            Messenger::None => (),
+        }
+    }
+}
+
+/// Applies debug-utils object names and command buffer labels. Every method is a no-op when
+/// debug-utils isn't enabled, so it's always safe to call.
+#[derive(Clone)]
+pub struct DebugNamer {
+    loader: Option<DebugUtils>,
+    device: vk::Device,
+}
+
+impl DebugNamer {
+    pub fn new(debug: &DebugToken, device: &Device) -> Self {
+        Self {
+            loader: debug.utils().cloned(),
+            device: device.handle(),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            loader: None,
+            device: vk::Device::null(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.loader.is_some()
+    }
+
+    pub fn name<H: Handle>(&self, handle: H, name: &str) {
+        if let Some(loader) = &self.loader {
+            // Names with NUL can't be passed on; they're only a debugging aid, so skip them
+            let name = match CString::new(name) {
+                Ok(name) => name,
+                Err(_) => return,
+            };
+            let info = vk::DebugUtilsObjectNameInfoEXT::builder()
+                .object_type(H::TYPE)
+                .object_handle(handle.as_raw())
+                .object_name(&name);
+            // Names are a debugging aid; failing to set one isn't worth an error
+            let _ = unsafe { loader.debug_utils_set_object_name(self.device, &info) };
+        }
+    }
+
+    pub fn begin_label(&self, buf: vk::CommandBuffer, name: &str, color: [f32; 4]) {
+        if let Some(loader) = &self.loader {
+            let name = match CString::new(name) {
+                Ok(name) => name,
+                Err(_) => return,
+            };
+            let label = vk::DebugUtilsLabelEXT::builder()
+                .label_name(&name)
+                .color(color);
+            unsafe { loader.cmd_begin_debug_utils_label(buf, &label) };
+        }
+    }
+
+    pub fn end_label(&self, buf: vk::CommandBuffer) {
+        if let Some(loader) = &self.loader {
+            unsafe { loader.cmd_end_debug_utils_label(buf) };
+        }
+    }
+
+    pub fn insert_label(&self, buf: vk::CommandBuffer, name: &str, color: [f32; 4]) {
+        if let Some(loader) = &self.loader {
+            let name = match CString::new(name) {
+                Ok(name) => name,
+                Err(_) => return,
+            };
+            let label = vk::DebugUtilsLabelEXT::builder()
+                .label_name(&name)
+                .color(color);
+            unsafe { loader.cmd_insert_debug_utils_label(buf, &label) };
+        }
+    }
+
+    /// Wraps whatever `f` records into `buf` in a label, e.g. inside a `CmdPool::record`
+    /// closure.
+    pub fn scope<R, F: FnOnce() -> R>(&self, buf: vk::CommandBuffer, name: &str, f: F) -> R {
+        self.begin_label(buf, name, [0.0; 4]);
+        let res = f();
+        self.end_label(buf);
+        res
+    }
+}
+
+/// Flint objects that can carry a debug name, shown in validation messages and captures.
+pub trait DebugName {
+    fn set_name(&self, namer: &DebugNamer, name: &str);
+
+    fn named(self, namer: &DebugNamer, name: &str) -> Self
+    where
+        Self: Sized,
+    {
+        self.set_name(namer, name);
+        self
+    }
+}
+
+fn report_flags(severity: vk::DebugUtilsMessageSeverityFlagsEXT) -> vk::DebugReportFlagsEXT {
+    use vk::DebugReportFlagsEXT as R;
+    use vk::DebugUtilsMessageSeverityFlagsEXT as S;
+    let mut res = R::empty();
+    if severity.contains(S::ERROR) {
+        res |= R::ERROR;
+    }
+    if severity.contains(S::WARNING) {
+        res |= R::WARNING | R::PERFORMANCE_WARNING;
+    }
+    if severity.contains(S::INFO) {
+        res |= R::INFORMATION;
+    }
+    if severity.contains(S::VERBOSE) {
+        res |= R::DEBUG;
+    }
+    res
+}
+
+unsafe fn opt_str<'a>(p: *const c_char) -> Option<&'a str> {
+    if p.is_null() {
+        None
+    } else {
+        CStr::from_ptr(p).to_str().ok()
+    }
+}
+
+unsafe fn labels<'a>(p: *const vk::DebugUtilsLabelEXT, count: u32) -> Vec<&'a str> {
+    if p.is_null() {
+        return Vec::new();
+    }
+    std::slice::from_raw_parts(p, count as usize)
+        .iter()
+        .filter_map(|l| opt_str(l.p_label_name))
+        .collect()
+}
+
+unsafe fn dispatch(user_data: *mut c_void, msg: &DebugMessage) {
+    match (user_data as *const CallbackState).as_ref() {
+        Some(state) => state.dispatch(msg),
+        None => log_message(msg),
+    }
+}
+
+unsafe extern "system" fn debug_utils_callback(
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    ty: vk::DebugUtilsMessageTypeFlagsEXT,
+    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void,
+) -> vk::Bool32 {
+    let data = &*data;
+    let objects = if data.p_objects.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(data.p_objects, data.object_count as usize)
+            .iter()
+            .map(|o| (o.object_type, o.object_handle, opt_str(o.p_object_name)))
+            .collect()
+    };
+    let msg = DebugMessage {
+        severity,
+        ty,
+        id_name: opt_str(data.p_message_id_name),
+        id_number: data.message_id_number,
+        message: opt_str(data.p_message).unwrap_or(""),
+        objects,
+        queue_labels: labels(data.p_queue_labels, data.queue_label_count),
+        cmd_buf_labels: labels(data.p_cmd_buf_labels, data.cmd_buf_label_count),
+    };
+    dispatch(user_data, &msg);
+    vk::FALSE
+}
+
+unsafe extern "system" fn debug_report_callback(
+    flags: vk::DebugReportFlagsEXT,
+    _: vk::DebugReportObjectTypeEXT,
+    object: u64,
+    _: usize,
+    message_code: i32,
+    p_layer_prefix: *const c_char,
+    p_message: *const c_char,
+    user_data: *mut c_void,
+) -> u32 {
+    use vk::DebugReportFlagsEXT as R;
+    use vk::DebugUtilsMessageSeverityFlagsEXT as S;
+    let severity = if flags.contains(R::ERROR) {
+        S::ERROR
+    } else if flags.intersects(R::WARNING | R::PERFORMANCE_WARNING) {
+        S::WARNING
+    } else if flags.contains(R::INFORMATION) {
+        S::INFO
+    } else {
+        S::VERBOSE
+    };
+    let ty = if flags.contains(R::PERFORMANCE_WARNING) {
+        vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
+    } else {
+        vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+    };
+    let msg = DebugMessage {
+        severity,
+        ty,
+        id_name: opt_str(p_layer_prefix),
+        id_number: message_code,
+        message: opt_str(p_message).unwrap_or(""),
+        objects: vec![(vk::ObjectType::UNKNOWN, object, None)],
+        queue_labels: Vec::new(),
+        cmd_buf_labels: Vec::new(),
+    };
+    dispatch(user_data, &msg);
+    vk::FALSE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> DebugMessage<'static> {
+        DebugMessage {
+            severity: vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            ty: vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+            id_name: None,
+            id_number: 0,
+            message: "test",
+            objects: Vec::new(),
+            queue_labels: Vec::new(),
+            cmd_buf_labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn callback_panics_are_kept_for_later() {
+        let callback: Arc<DebugCallback> = Arc::new(|msg: &DebugMessage| {
+            if msg.is_error() {
+                panic!("validation error: {}", msg.message)
+            }
+        });
+        let state = CallbackState {
+            callback: Some(callback),
+            panic: Mutex::new(None),
+        };
+        let user_data = &state as *const CallbackState as *mut c_void;
+        unsafe {
+            dispatch(user_data, &message());
+            dispatch(user_data, &message());
+        }
+        let payload = state.take_panic().expect("panic should be stored");
+        assert_eq!(
+            payload.downcast_ref::<String>().map(String::as_str),
+            Some("validation error: test")
+        );
+        // Only the first is kept
+        assert!(state.take_panic().is_none());
+    }
+}
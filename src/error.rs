@@ -0,0 +1,129 @@
+use ash::vk;
+use std::ffi::CString;
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A Vulkan call returned an error code.
+    Vk(vk::Result),
+    /// The Vulkan loader, or one of its entry points, couldn't be loaded.
+    Loading(String),
+    /// A required layer or extension isn't available.
+    Missing {
+        kind: &'static str,
+        name: CString,
+    },
+    /// No physical device has everything that was asked for.
+    NoSuitableDevice(String),
+    /// No memory type accepts the resource with these properties.
+    NoSuitableMemoryType(vk::MemoryPropertyFlags),
+    /// shaderc rejected a shader; `diagnostics` is its output.
+    ShaderCompile {
+        name: String,
+        diagnostics: String,
+    },
+    /// SPIR-V reflection failed.
+    Reflection(spirv_cross::ErrorCode),
+    Io(std::io::Error),
+    Image(image::ImageError),
+    /// A model file couldn't be parsed.
+    Model(String),
+    /// Valid input that Flint doesn't handle yet.
+    Unsupported(String),
+    /// A render graph that can't be compiled.
+    Graph(String),
+    /// A vertex shader's inputs don't match the pipeline's vertex layout; one entry per problem.
+    VertexInputs(Vec<String>),
+    /// The shader file watcher couldn't be set up.
+    Watch(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Vk(e) => write!(f, "Vulkan error: {}", e),
+            Loading(e) => write!(f, "Couldn't load Vulkan: {}", e),
+            Missing { kind, name } => write!(f, "Required {} not available: {:?}", kind, name),
+            NoSuitableDevice(e) => write!(f, "No suitable device: {}", e),
+            NoSuitableMemoryType(flags) => write!(f, "No suitable memory type for {:?}", flags),
+            ShaderCompile { name, diagnostics } => {
+                write!(f, "Failed to compile {}:\n{}", name, diagnostics)
+            }
+            Reflection(e) => write!(f, "SPIR-V reflection failed: {:?}", e),
+            Io(e) => write!(f, "{}", e),
+            Image(e) => write!(f, "{}", e),
+            Model(e) => write!(f, "Couldn't load model: {}", e),
+            Unsupported(e) => write!(f, "Not supported: {}", e),
+            Graph(e) => write!(f, "Invalid render graph: {}", e),
+            VertexInputs(e) => write!(
+                f,
+                "Vertex layout doesn't match the vertex shader: {}",
+                e.join("; ")
+            ),
+            Watch(e) => write!(f, "Couldn't watch shader sources: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Vk(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<vk::Result> for Error {
+    fn from(e: vk::Result) -> Self {
+        Error::Vk(e)
+    }
+}
+
+impl From<ash::LoadingError> for Error {
+    fn from(e: ash::LoadingError) -> Self {
+        Error::Loading(e.to_string())
+    }
+}
+
+impl From<ash::InstanceError> for Error {
+    fn from(e: ash::InstanceError) -> Self {
+        match e {
+            ash::InstanceError::VkError(e) => Error::Vk(e),
+            ash::InstanceError::LoadError(e) => Error::Loading(e.join("; ")),
+        }
+    }
+}
+
+impl From<spirv_cross::ErrorCode> for Error {
+    fn from(e: spirv_cross::ErrorCode) -> Self {
+        Error::Reflection(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Error::Image(e)
+    }
+}
+
+#[cfg(feature = "hot-reload")]
+impl From<notify::Error> for Error {
+    fn from(e: notify::Error) -> Self {
+        match e {
+            notify::Error::Io(e) => Error::Io(e),
+            e => Error::Watch(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,1335 @@
+//! Declares a frame as passes that read and write images and buffers, and works out the order,
+//! barriers, render passes, framebuffers and transient images from that.
+//!
+//! ```ignore
+//! let mut graph = RenderGraph::new();
+//! let shadow = graph.transient("shadow map", ImageDesc { format: depth_fmt, extent: SHADOW, samples });
+//! let depth = graph.transient("depth", ImageDesc { format: depth_fmt, extent, samples });
+//! let out = graph.import_swapchain(&swap)?;
+//! let shadow_pass = graph.pass("shadow").depth(shadow, LoadOp::Clear(one)).id();
+//! let main_pass = graph
+//!     .pass("main")
+//!     .sampled(shadow, vk::PipelineStageFlags::FRAGMENT_SHADER)
+//!     .color(out, LoadOp::Clear(black))
+//!     .depth(depth, LoadOp::Clear(one))
+//!     .id();
+//! let graph = graph.compile(dev, &mem_prop)?;
+//! // per frame
+//! graph.execute(cmd_buf, image_index as usize, |ctx| match ctx.pass { ... });
+//! ```
+
+use crate::base::SwapToken;
+use crate::debug::{DebugName, DebugNamer};
+use crate::renderpass::{color_attachment, depth_attachment, RenderPassBuilder, RenderPassToken};
+use crate::rendertarget::{make_image, make_view};
+use crate::*;
+use ash::{version::DeviceV1_0, vk, Device};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BufferId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PassId(usize);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageDesc {
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub samples: vk::SampleCountFlags,
+}
+
+fn same_extent(a: vk::Extent2D, b: vk::Extent2D) -> bool {
+    a.width == b.width && a.height == b.height
+}
+
+impl PartialEq for ImageDesc {
+    fn eq(&self, other: &Self) -> bool {
+        self.format == other.format
+            && same_extent(self.extent, other.extent)
+            && self.samples == other.samples
+    }
+}
+
+/// What happens to an attachment's contents when a pass starts.
+#[derive(Clone, Copy)]
+pub enum LoadOp {
+    Clear(vk::ClearValue),
+    Load,
+    DontCare,
+}
+
+#[derive(Clone, Copy)]
+enum ImageUse {
+    Color(LoadOp),
+    Depth(LoadOp),
+    Sampled(vk::PipelineStageFlags),
+    TransferSrc,
+    TransferDst,
+}
+
+/// The state an image has to be in for a use.
+#[derive(Debug, Clone, Copy)]
+struct ImageState {
+    layout: vk::ImageLayout,
+    stages: vk::PipelineStageFlags,
+    access: vk::AccessFlags,
+}
+
+impl ImageUse {
+    fn reads(self) -> bool {
+        match self {
+            ImageUse::Color(LoadOp::Load) | ImageUse::Depth(LoadOp::Load) => true,
+            ImageUse::Color(_) | ImageUse::Depth(_) | ImageUse::TransferDst => false,
+            ImageUse::Sampled(_) | ImageUse::TransferSrc => true,
+        }
+    }
+
+    fn writes(self) -> bool {
+        match self {
+            ImageUse::Color(_) | ImageUse::Depth(_) | ImageUse::TransferDst => true,
+            ImageUse::Sampled(_) | ImageUse::TransferSrc => false,
+        }
+    }
+
+    fn usage(self) -> vk::ImageUsageFlags {
+        match self {
+            ImageUse::Color(_) => vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            ImageUse::Depth(_) => vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            ImageUse::Sampled(_) => vk::ImageUsageFlags::SAMPLED,
+            ImageUse::TransferSrc => vk::ImageUsageFlags::TRANSFER_SRC,
+            ImageUse::TransferDst => vk::ImageUsageFlags::TRANSFER_DST,
+        }
+    }
+
+    fn state(self) -> ImageState {
+        match self {
+            ImageUse::Color(_) => ImageState {
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                stages: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                access: vk::AccessFlags::COLOR_ATTACHMENT_READ
+                    | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            },
+            ImageUse::Depth(_) => ImageState {
+                layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                stages: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                access: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            },
+            ImageUse::Sampled(stages) => ImageState {
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                stages,
+                access: vk::AccessFlags::SHADER_READ,
+            },
+            ImageUse::TransferSrc => ImageState {
+                layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                stages: vk::PipelineStageFlags::TRANSFER,
+                access: vk::AccessFlags::TRANSFER_READ,
+            },
+            ImageUse::TransferDst => ImageState {
+                layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                stages: vk::PipelineStageFlags::TRANSFER,
+                access: vk::AccessFlags::TRANSFER_WRITE,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BufferUse {
+    buffer: BufferId,
+    stages: vk::PipelineStageFlags,
+    access: vk::AccessFlags,
+    writes: bool,
+}
+
+struct Imported {
+    images: Vec<vk::Image>,
+    views: Vec<vk::ImageView>,
+    initial_layout: vk::ImageLayout,
+    final_layout: vk::ImageLayout,
+}
+
+struct ImageNode {
+    name: String,
+    desc: ImageDesc,
+    /// None for transient images, which the graph makes itself.
+    imported: Option<Imported>,
+}
+
+struct PassNode {
+    name: String,
+    images: Vec<(ImageId, ImageUse)>,
+    buffers: Vec<BufferUse>,
+    side_effects: bool,
+}
+
+/// The passes of a frame and the resources they use. Build it, then `compile` it.
+#[derive(Default)]
+pub struct RenderGraph {
+    images: Vec<ImageNode>,
+    buffers: Vec<(String, vk::Buffer)>,
+    passes: Vec<PassNode>,
+}
+
+/// Adds uses to a pass; see `RenderGraph::pass`.
+pub struct PassBuilder<'a> {
+    graph: &'a mut RenderGraph,
+    pass: usize,
+}
+
+impl<'a> PassBuilder<'a> {
+    fn image(self, image: ImageId, usage: ImageUse) -> Self {
+        self.graph.passes[self.pass].images.push((image, usage));
+        self
+    }
+
+    fn buffer(
+        self,
+        buffer: BufferId,
+        stages: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+        writes: bool,
+    ) -> Self {
+        self.graph.passes[self.pass].buffers.push(BufferUse {
+            buffer,
+            stages,
+            access,
+            writes,
+        });
+        self
+    }
+
+    /// Color attachments are numbered in the order they're added, for the fragment shader's
+    /// outputs.
+    pub fn color(self, image: ImageId, load: LoadOp) -> Self {
+        self.image(image, ImageUse::Color(load))
+    }
+
+    pub fn depth(self, image: ImageId, load: LoadOp) -> Self {
+        self.image(image, ImageUse::Depth(load))
+    }
+
+    /// Read through a sampler in `stages`.
+    pub fn sampled(self, image: ImageId, stages: vk::PipelineStageFlags) -> Self {
+        self.image(image, ImageUse::Sampled(stages))
+    }
+
+    pub fn transfer_src(self, image: ImageId) -> Self {
+        self.image(image, ImageUse::TransferSrc)
+    }
+
+    pub fn transfer_dst(self, image: ImageId) -> Self {
+        self.image(image, ImageUse::TransferDst)
+    }
+
+    pub fn read_buffer(
+        self,
+        buffer: BufferId,
+        stages: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+    ) -> Self {
+        self.buffer(buffer, stages, access, false)
+    }
+
+    pub fn write_buffer(
+        self,
+        buffer: BufferId,
+        stages: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+    ) -> Self {
+        self.buffer(buffer, stages, access, true)
+    }
+
+    /// Keeps the pass even when nothing reads what it writes.
+    pub fn side_effects(self) -> Self {
+        self.graph.passes[self.pass].side_effects = true;
+        self
+    }
+
+    pub fn id(&self) -> PassId {
+        PassId(self.pass)
+    }
+}
+
+impl RenderGraph {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// An image that only lives within the frame. The graph makes it, and may share it with
+    /// other transient images of the same description whose uses don't overlap.
+    pub fn transient(&mut self, name: &str, desc: ImageDesc) -> ImageId {
+        self.images.push(ImageNode {
+            name: name.to_string(),
+            desc,
+            imported: None,
+        });
+        ImageId(self.images.len() - 1)
+    }
+
+    /// An image made elsewhere. With more than one image, `execute`'s `variant` picks which is
+    /// used that frame. It's expected in `initial_layout` at the start of the frame and is left
+    /// in `final_layout`.
+    ///
+    /// Fails unless there's at least one image and a view for each.
+    pub fn import(
+        &mut self,
+        name: &str,
+        desc: ImageDesc,
+        images: Vec<vk::Image>,
+        views: Vec<vk::ImageView>,
+        initial_layout: vk::ImageLayout,
+        final_layout: vk::ImageLayout,
+    ) -> Result<ImageId> {
+        if images.is_empty() {
+            return Err(Error::Graph(format!(
+                "Imported image {} has no images",
+                name
+            )));
+        }
+        if images.len() != views.len() {
+            return Err(Error::Graph(format!(
+                "Imported image {} needs one view per image, but has {} images and {} views",
+                name,
+                images.len(),
+                views.len()
+            )));
+        }
+        self.images.push(ImageNode {
+            name: name.to_string(),
+            desc,
+            imported: Some(Imported {
+                images,
+                views,
+                initial_layout,
+                final_layout,
+            }),
+        });
+        Ok(ImageId(self.images.len() - 1))
+    }
+
+    /// The swapchain's images, ready to present after the frame; pass the acquired image index
+    /// as `execute`'s `variant`. Their previous contents are discarded.
+    pub fn import_swapchain(&mut self, swap: &SwapToken) -> Result<ImageId> {
+        self.import(
+            "swapchain",
+            ImageDesc {
+                format: swap.base.format,
+                extent: swap.base.extent,
+                samples: vk::SampleCountFlags::TYPE_1,
+            },
+            swap.base.imgs.clone(),
+            swap.img_views.clone(),
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+        )
+    }
+
+    pub fn import_buffer(&mut self, name: &str, buffer: vk::Buffer) -> BufferId {
+        self.buffers.push((name.to_string(), buffer));
+        BufferId(self.buffers.len() - 1)
+    }
+
+    pub fn pass(&mut self, name: &str) -> PassBuilder<'_> {
+        self.passes.push(PassNode {
+            name: name.to_string(),
+            images: Vec::new(),
+            buffers: Vec::new(),
+            side_effects: false,
+        });
+        PassBuilder {
+            pass: self.passes.len() - 1,
+            graph: self,
+        }
+    }
+
+    /// Orders passes so each runs after whatever wrote what it reads. Passes are otherwise kept
+    /// in declaration order, and a pass reading a transient image before any earlier pass wrote
+    /// it waits for the first later pass that does.
+    fn sort(&self) -> Result<Vec<usize>> {
+        let mut edges = HashSet::new();
+        let mut accesses: Vec<Vec<(usize, bool, bool)>> =
+            vec![Vec::new(); self.images.len() + self.buffers.len()];
+        for (p, pass) in self.passes.iter().enumerate() {
+            for (image, usage) in &pass.images {
+                accesses[image.0].push((p, usage.reads(), usage.writes()));
+            }
+            for usage in &pass.buffers {
+                accesses[self.images.len() + usage.buffer.0].push((p, !usage.writes, usage.writes));
+            }
+        }
+        for (r, list) in accesses.iter().enumerate() {
+            let transient = r < self.images.len() && self.images[r].imported.is_none();
+            let mut last_writer = None;
+            let mut readers = Vec::new();
+            let mut pending = Vec::new();
+            for &(p, reads, writes) in list {
+                if reads {
+                    match last_writer {
+                        Some(w) => {
+                            edges.insert((w, p));
+                            readers.push(p);
+                        }
+                        None if transient => pending.push(p),
+                        None => readers.push(p),
+                    }
+                }
+                if writes {
+                    if let Some(w) = last_writer {
+                        edges.insert((w, p));
+                    }
+                    for &reader in &readers {
+                        edges.insert((reader, p));
+                    }
+                    for reader in pending.drain(0..) {
+                        edges.insert((p, reader));
+                    }
+                    readers.clear();
+                    last_writer = Some(p);
+                }
+            }
+            if let Some(&p) = pending.first() {
+                return Err(Error::Graph(format!(
+                    "{} reads {} but no pass writes it",
+                    self.passes[p].name, self.images[r].name
+                )));
+            }
+        }
+
+        let mut indegree = vec![0; self.passes.len()];
+        for &(from, to) in &edges {
+            if from != to {
+                indegree[to] += 1;
+            }
+        }
+        let mut ready = (0..self.passes.len())
+            .filter(|&p| indegree[p] == 0)
+            .map(Reverse)
+            .collect::<BinaryHeap<_>>();
+        let mut order = Vec::with_capacity(self.passes.len());
+        while let Some(Reverse(p)) = ready.pop() {
+            order.push(p);
+            for &(from, to) in &edges {
+                if from == p && to != p {
+                    indegree[to] -= 1;
+                    if indegree[to] == 0 {
+                        ready.push(Reverse(to));
+                    }
+                }
+            }
+        }
+        if order.len() != self.passes.len() {
+            return Err(Error::Graph(
+                "Passes depend on each other in a cycle".to_string(),
+            ));
+        }
+        Ok(order)
+    }
+
+    /// Drops passes whose output nobody uses: ones without side effects that write no buffer,
+    /// imported image, or image a kept pass reads.
+    fn cull(&self, order: Vec<usize>) -> Vec<usize> {
+        let mut wanted = self
+            .images
+            .iter()
+            .enumerate()
+            .filter(|(_, image)| image.imported.is_some())
+            .map(|(i, _)| i)
+            .collect::<HashSet<_>>();
+        let mut kept = Vec::new();
+        for &p in order.iter().rev() {
+            let pass = &self.passes[p];
+            let needed = pass.side_effects
+                || pass.buffers.iter().any(|b| b.writes)
+                || pass
+                    .images
+                    .iter()
+                    .any(|(image, usage)| usage.writes() && wanted.contains(&image.0));
+            if needed {
+                for (image, usage) in &pass.images {
+                    if usage.reads() {
+                        wanted.insert(image.0);
+                    }
+                }
+                kept.push(p);
+            } else {
+                log::debug!("Render graph: culling unused pass {}", pass.name);
+            }
+        }
+        kept.reverse();
+        kept
+    }
+
+    /// Usage and lifetime, in positions of `order`, of each image. Unused images start at
+    /// `usize::max_value()`.
+    fn lifetimes(&self, order: &[usize]) -> Lifetimes {
+        let mut res = Lifetimes {
+            usage: vec![vk::ImageUsageFlags::empty(); self.images.len()],
+            first: vec![usize::max_value(); self.images.len()],
+            last: vec![0; self.images.len()],
+        };
+        for (i, &p) in order.iter().enumerate() {
+            for (image, u) in &self.passes[p].images {
+                res.usage[image.0] |= u.usage();
+                res.first[image.0] = res.first[image.0].min(i);
+                res.last[image.0] = res.last[image.0].max(i);
+            }
+        }
+        res
+    }
+
+    /// Transient images share physical images when their descriptions match and their uses
+    /// don't overlap. Gives each image's physical image, and the physical images to make.
+    fn alias(&self, lifetimes: &Lifetimes) -> (Vec<Option<usize>>, Vec<PhysicalPlan>) {
+        let Lifetimes { usage, first, last } = lifetimes;
+        let mut by_first = (0..self.images.len())
+            .filter(|&i| self.images[i].imported.is_none() && first[i] != usize::max_value())
+            .collect::<Vec<_>>();
+        by_first.sort_by_key(|&i| first[i]);
+        let mut assignment = vec![None; self.images.len()];
+        let mut physical_plan: Vec<PhysicalPlan> = Vec::new();
+        for i in by_first {
+            let desc = self.images[i].desc;
+            let slot = physical_plan
+                .iter()
+                .position(|(d, _, free_after, _)| *d == desc && *free_after < first[i]);
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    physical_plan.push((desc, vk::ImageUsageFlags::empty(), 0, Vec::new()));
+                    physical_plan.len() - 1
+                }
+            };
+            let plan = &mut physical_plan[slot];
+            plan.1 |= usage[i];
+            plan.2 = last[i];
+            plan.3.push(self.images[i].name.clone());
+            assignment[i] = Some(slot);
+        }
+        (assignment, physical_plan)
+    }
+
+    pub fn compile(
+        &self,
+        dev: Device,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+    ) -> Result<CompiledGraph> {
+        let order = self.cull(self.sort()?);
+        let lifetimes = self.lifetimes(&order);
+        let (assignment, physical_plan) = self.alias(&lifetimes);
+
+        let mut res = CompiledGraph {
+            dev,
+            images: Vec::new(),
+            physical: Vec::new(),
+            passes: Vec::new(),
+            finish: Barriers::default(),
+        };
+        for (desc, usage, _, names) in physical_plan {
+            res.physical.push(Physical {
+                image: vk::Image::null(),
+                mem: vk::DeviceMemory::null(),
+                view: vk::ImageView::null(),
+                sampled_view: vk::ImageView::null(),
+                names,
+            });
+            let physical = res.physical.last_mut().unwrap();
+            unsafe {
+                make_image(
+                    &res.dev,
+                    mem_prop,
+                    desc.format,
+                    desc.extent,
+                    desc.samples,
+                    usage,
+                    &mut physical.image,
+                    &mut physical.mem,
+                )?;
+                physical.view =
+                    make_view(&res.dev, physical.image, desc.format, aspect(desc.format))?;
+                // Samplers read one aspect at a time
+                let sampled = sampled_aspect(desc.format);
+                physical.sampled_view = if usage.contains(vk::ImageUsageFlags::SAMPLED)
+                    && sampled != aspect(desc.format)
+                {
+                    make_view(&res.dev, physical.image, desc.format, sampled)?
+                } else {
+                    physical.view
+                };
+            }
+        }
+        res.images = self
+            .images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| match (&image.imported, assignment[i]) {
+                (Some(imported), _) => GraphImage::Imported {
+                    images: imported.images.clone(),
+                    views: imported.views.clone(),
+                },
+                (None, Some(slot)) => GraphImage::Physical(slot),
+                (None, None) => GraphImage::Unused,
+            })
+            .collect();
+
+        // Barriers, from each image's state at the end of the previous pass that used it
+        let mut image_states: Vec<Option<(ImageState, bool)>> = self
+            .images
+            .iter()
+            .map(|image| {
+                image.imported.as_ref().map(|imported| {
+                    (
+                        ImageState {
+                            layout: imported.initial_layout,
+                            stages: vk::PipelineStageFlags::ALL_COMMANDS,
+                            access: vk::AccessFlags::MEMORY_WRITE,
+                        },
+                        true,
+                    )
+                })
+            })
+            .collect();
+        let mut buffer_states: Vec<Option<BufferUse>> = vec![None; self.buffers.len()];
+        // Before the first image using a physical image, the memory was last used at the end of
+        // the previous frame
+        let mut physical_states = vec![None; res.physical.len()];
+        for &p in &order {
+            for &(image, u) in &self.passes[p].images {
+                if let Some(slot) = assignment[image.0] {
+                    physical_states[slot] = Some((u.state(), u.writes()));
+                }
+            }
+        }
+        // Whether each image's first use has been handled, so a pass listing a transient more
+        // than once only starts it from UNDEFINED once
+        let mut seen = vec![false; self.images.len()];
+        for (i, &p) in order.iter().enumerate() {
+            let pass = &self.passes[p];
+            let mut barriers = Barriers::default();
+            for &(image, u) in &pass.images {
+                let next = u.state();
+                // The first use of a transient doesn't care what was there, but has to wait for
+                // whichever image used the memory before it
+                let slot = assignment[image.0];
+                let prev = match slot {
+                    Some(slot) if !seen[image.0] => physical_states[slot].map(|(state, writes)| {
+                        (
+                            ImageState {
+                                layout: vk::ImageLayout::UNDEFINED,
+                                ..state
+                            },
+                            writes,
+                        )
+                    }),
+                    _ => image_states[image.0],
+                };
+                seen[image.0] = true;
+                // Imported images start in their initial state and every transient has a slot
+                let (prev, prev_writes) = prev.unwrap();
+                if prev.layout != next.layout || prev_writes || u.writes() {
+                    barriers.image(image, &self.images[image.0].desc, prev, next, prev_writes);
+                }
+                // A pass using an image more than once leaves it in its last use's state
+                image_states[image.0] = Some((next, u.writes()));
+                if let Some(slot) = slot {
+                    physical_states[slot] = image_states[image.0];
+                }
+            }
+            for &u in &pass.buffers {
+                if let Some(prev) = buffer_states[u.buffer.0] {
+                    if prev.writes || u.writes {
+                        barriers.buffer(self.buffers[u.buffer.0].1, prev, u);
+                    }
+                }
+                buffer_states[u.buffer.0] = Some(u);
+            }
+            res.passes.push(CompiledPass {
+                id: PassId(p),
+                name: pass.name.clone(),
+                barriers,
+                renderpass: None,
+                framebuffers: Vec::new(),
+                clear_values: Vec::new(),
+                extent: vk::Extent2D::default(),
+            });
+            self.make_renderpass(&mut res, &order, i, p)?;
+        }
+        for (i, image) in self.images.iter().enumerate() {
+            if let (Some(imported), Some((state, writes))) = (&image.imported, image_states[i]) {
+                if state.layout != imported.final_layout || writes {
+                    res.finish.image(
+                        ImageId(i),
+                        &image.desc,
+                        state,
+                        ImageState {
+                            layout: imported.final_layout,
+                            stages: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                            access: vk::AccessFlags::empty(),
+                        },
+                        writes,
+                    );
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Makes the render pass and framebuffers for the pass at `position` in `order`, if it has
+    /// attachments.
+    fn make_renderpass(
+        &self,
+        res: &mut CompiledGraph,
+        order: &[usize],
+        position: usize,
+        p: usize,
+    ) -> Result<()> {
+        let pass = &self.passes[p];
+        let colors = pass.images.iter().filter_map(|&(image, u)| match u {
+            ImageUse::Color(load) => Some((image, load)),
+            _ => None,
+        });
+        let depth = pass
+            .images
+            .iter()
+            .filter_map(|&(image, u)| match u {
+                ImageUse::Depth(load) => Some((image, load)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if depth.len() > 1 {
+            return Err(Error::Graph(format!(
+                "{} has more than one depth attachment",
+                pass.name
+            )));
+        }
+        let attachments = colors
+            .map(|a| (a, false))
+            .chain(depth.into_iter().map(|a| (a, true)))
+            .collect::<Vec<_>>();
+        if attachments.is_empty() {
+            return Ok(());
+        }
+        let ((first, _), _) = attachments[0];
+        let extent = self.images[first.0].desc.extent;
+        if attachments
+            .iter()
+            .any(|((image, _), _)| !same_extent(self.images[image.0].desc.extent, extent))
+        {
+            return Err(Error::Graph(format!(
+                "{}'s attachments aren't all the same size",
+                pass.name
+            )));
+        }
+
+        // Stored if anything later reads it or it outlives the frame
+        let needed_later = |image: ImageId| {
+            self.images[image.0].imported.is_some()
+                || order[position + 1..].iter().any(|&later| {
+                    self.passes[later]
+                        .images
+                        .iter()
+                        .any(|&(i, u)| i == image && u.reads())
+                })
+        };
+        let mut builder = RenderPassBuilder::new();
+        let mut subpass = crate::renderpass::SubpassDesc::new();
+        let mut clear_values = Vec::new();
+        for (index, &((image, load), is_depth)) in attachments.iter().enumerate() {
+            let desc = self.images[image.0].desc;
+            let (op, clear) = match load {
+                LoadOp::Clear(value) => (vk::AttachmentLoadOp::CLEAR, value),
+                LoadOp::Load => (vk::AttachmentLoadOp::LOAD, vk::ClearValue::default()),
+                LoadOp::DontCare => (vk::AttachmentLoadOp::DONT_CARE, vk::ClearValue::default()),
+            };
+            let store = if needed_later(image) {
+                vk::AttachmentStoreOp::STORE
+            } else {
+                vk::AttachmentStoreOp::DONT_CARE
+            };
+            // Layouts are handled by the graph's barriers, so attachments stay in one layout
+            let attachment = if is_depth {
+                let mut a = depth_attachment(desc.format, desc.samples, op);
+                a.initial_layout = vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL;
+                a.store_op = store;
+                if has_stencil(desc.format) {
+                    a.stencil_store_op = store;
+                }
+                subpass = subpass.depth(index as u32);
+                a
+            } else {
+                let layout = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+                let mut a = color_attachment(desc.format, desc.samples, op, layout);
+                a.initial_layout = layout;
+                a.store_op = store;
+                subpass = subpass.color(index as u32);
+                a
+            };
+            builder = builder.attachment(attachment);
+            clear_values.push(clear);
+        }
+        // The graph's barriers cover everything outside the pass
+        let renderpass = builder
+            .subpass(subpass)
+            .dependency(vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::TOP_OF_PIPE,
+                dst_stage_mask: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                ..Default::default()
+            })
+            .build(res.dev.clone())?;
+
+        let variants = attachments
+            .iter()
+            .filter_map(|((image, _), _)| self.images[image.0].imported.as_ref())
+            .map(|imported| imported.views.len())
+            .max()
+            .unwrap_or(1);
+        let compiled = res.passes.last_mut().unwrap();
+        compiled.extent = extent;
+        compiled.clear_values = clear_values;
+        let rp = renderpass.renderpass;
+        compiled.renderpass = Some(renderpass);
+        for variant in 0..variants {
+            let views = attachments
+                .iter()
+                .map(|((image, _), _)| res.images[image.0].view(&res.physical, variant))
+                .collect::<Vec<_>>();
+            let info = vk::FramebufferCreateInfo::builder()
+                .render_pass(rp)
+                .attachments(&views)
+                .width(extent.width)
+                .height(extent.height)
+                .layers(1);
+            let framebuffer = unsafe { res.dev.create_framebuffer(&info, None)? };
+            res.passes
+                .last_mut()
+                .unwrap()
+                .framebuffers
+                .push(framebuffer);
+        }
+        Ok(())
+    }
+}
+
+fn aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    if is_depth_format(format) {
+        depth_aspect(format)
+    } else {
+        vk::ImageAspectFlags::COLOR
+    }
+}
+
+/// The one aspect a sampler reads: depth from combined depth/stencil formats, otherwise the only
+/// one there is.
+fn sampled_aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    let aspect = aspect(format);
+    if aspect.contains(vk::ImageAspectFlags::DEPTH) {
+        vk::ImageAspectFlags::DEPTH
+    } else {
+        aspect
+    }
+}
+
+enum GraphImage {
+    Physical(usize),
+    Imported {
+        images: Vec<vk::Image>,
+        views: Vec<vk::ImageView>,
+    },
+    /// Culled along with every pass using it.
+    Unused,
+}
+
+impl GraphImage {
+    fn image(&self, physical: &[Physical], variant: usize) -> vk::Image {
+        match self {
+            GraphImage::Physical(i) => physical[*i].image,
+            GraphImage::Imported { images, .. } => images[variant % images.len()],
+            GraphImage::Unused => vk::Image::null(),
+        }
+    }
+
+    fn view(&self, physical: &[Physical], variant: usize) -> vk::ImageView {
+        match self {
+            GraphImage::Physical(i) => physical[*i].view,
+            GraphImage::Imported { views, .. } => views[variant % views.len()],
+            GraphImage::Unused => vk::ImageView::null(),
+        }
+    }
+}
+
+/// Description, usage, position of the last use so far, and names of the images sharing a
+/// physical image.
+type PhysicalPlan = (ImageDesc, vk::ImageUsageFlags, usize, Vec<String>);
+
+struct Lifetimes {
+    usage: Vec<vk::ImageUsageFlags>,
+    first: Vec<usize>,
+    last: Vec<usize>,
+}
+
+struct Physical {
+    image: vk::Image,
+    mem: vk::DeviceMemory,
+    view: vk::ImageView,
+    /// Depth only for combined depth/stencil formats; otherwise `view`.
+    sampled_view: vk::ImageView,
+    /// The transient images sharing it.
+    names: Vec<String>,
+}
+
+struct ImageBarrier {
+    image: ImageId,
+    aspect: vk::ImageAspectFlags,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+}
+
+#[derive(Default)]
+struct Barriers {
+    src_stages: vk::PipelineStageFlags,
+    dst_stages: vk::PipelineStageFlags,
+    images: Vec<ImageBarrier>,
+    buffers: Vec<vk::BufferMemoryBarrier>,
+}
+
+impl Barriers {
+    /// Only writes need making visible; after a read, ordering the stages is enough.
+    fn image(
+        &mut self,
+        image: ImageId,
+        desc: &ImageDesc,
+        prev: ImageState,
+        next: ImageState,
+        prev_writes: bool,
+    ) {
+        self.src_stages |= prev.stages;
+        self.dst_stages |= next.stages;
+        self.images.push(ImageBarrier {
+            image,
+            aspect: aspect(desc.format),
+            old_layout: prev.layout,
+            new_layout: next.layout,
+            src_access: if prev_writes {
+                prev.access
+            } else {
+                vk::AccessFlags::empty()
+            },
+            dst_access: next.access,
+        });
+    }
+
+    fn buffer(&mut self, buffer: vk::Buffer, prev: BufferUse, next: BufferUse) {
+        self.src_stages |= prev.stages;
+        self.dst_stages |= next.stages;
+        self.buffers.push(
+            vk::BufferMemoryBarrier::builder()
+                .buffer(buffer)
+                .src_access_mask(if prev.writes {
+                    prev.access
+                } else {
+                    vk::AccessFlags::empty()
+                })
+                .dst_access_mask(next.access)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .size(vk::WHOLE_SIZE)
+                .build(),
+        );
+    }
+
+    unsafe fn record(
+        &self,
+        dev: &Device,
+        cmd_buf: vk::CommandBuffer,
+        images: &[GraphImage],
+        physical: &[Physical],
+        variant: usize,
+    ) {
+        if self.images.is_empty() && self.buffers.is_empty() {
+            return;
+        }
+        let image_barriers = self
+            .images
+            .iter()
+            .map(|b| {
+                vk::ImageMemoryBarrier::builder()
+                    .image(images[b.image.0].image(physical, variant))
+                    .old_layout(b.old_layout)
+                    .new_layout(b.new_layout)
+                    .src_access_mask(b.src_access)
+                    .dst_access_mask(b.dst_access)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .subresource_range(
+                        vk::ImageSubresourceRange::builder()
+                            .aspect_mask(b.aspect)
+                            .level_count(vk::REMAINING_MIP_LEVELS)
+                            .layer_count(vk::REMAINING_ARRAY_LAYERS)
+                            .build(),
+                    )
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        dev.cmd_pipeline_barrier(
+            cmd_buf,
+            self.src_stages,
+            self.dst_stages,
+            vk::DependencyFlags::empty(),
+            &[],
+            &self.buffers,
+            &image_barriers,
+        );
+    }
+}
+
+struct CompiledPass {
+    id: PassId,
+    name: String,
+    barriers: Barriers,
+    renderpass: Option<RenderPassToken>,
+    /// One per variant of the imported attachments.
+    framebuffers: Vec<vk::Framebuffer>,
+    clear_values: Vec<vk::ClearValue>,
+    extent: vk::Extent2D,
+}
+
+/// What a pass records with in `CompiledGraph::execute`.
+pub struct PassCtx<'a> {
+    pub dev: &'a Device,
+    pub cmd_buf: vk::CommandBuffer,
+    pub pass: PassId,
+    pub name: &'a str,
+    /// Already begun, with the viewport and scissor set to `extent`; null for passes without
+    /// attachments.
+    pub renderpass: vk::RenderPass,
+    pub extent: vk::Extent2D,
+}
+
+/// A `RenderGraph` with its transient images, render passes and framebuffers made and its
+/// barriers worked out.
+pub struct CompiledGraph {
+    dev: Device,
+    images: Vec<GraphImage>,
+    physical: Vec<Physical>,
+    /// In execution order; culled passes are left out.
+    passes: Vec<CompiledPass>,
+    /// Puts imported images in their final layouts.
+    finish: Barriers,
+}
+
+impl Drop for CompiledGraph {
+    fn drop(&mut self) {
+        eprintln!("Dropping CompiledGraph");
+        unsafe {
+            for pass in &mut self.passes {
+                for framebuffer in pass.framebuffers.drain(0..) {
+                    self.dev.destroy_framebuffer(framebuffer, None);
+                }
+            }
+            for physical in &self.physical {
+                if physical.sampled_view != physical.view {
+                    self.dev.destroy_image_view(physical.sampled_view, None);
+                }
+                self.dev.destroy_image_view(physical.view, None);
+                self.dev.destroy_image(physical.image, None);
+                self.dev.free_memory(physical.mem, None);
+            }
+        }
+    }
+}
+
+impl DebugName for CompiledGraph {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        for physical in &self.physical {
+            let names = physical.names.join(", ");
+            namer.name(physical.image, &format!("{} {}", name, names));
+            namer.name(physical.view, &format!("{} {} view", name, names));
+            if physical.sampled_view != physical.view {
+                namer.name(
+                    physical.sampled_view,
+                    &format!("{} {} sampled view", name, names),
+                );
+            }
+            namer.name(physical.mem, &format!("{} {} memory", name, names));
+        }
+        for pass in &self.passes {
+            if let Some(renderpass) = &pass.renderpass {
+                renderpass.set_name(namer, &format!("{} {}", name, pass.name));
+            }
+            for (i, framebuffer) in pass.framebuffers.iter().enumerate() {
+                namer.name(
+                    *framebuffer,
+                    &format!("{} {} framebuffer {}", name, pass.name, i),
+                );
+            }
+        }
+    }
+}
+
+impl CompiledGraph {
+    /// The passes that survived culling, in the order they run.
+    pub fn order(&self) -> Vec<PassId> {
+        self.passes.iter().map(|p| p.id).collect()
+    }
+
+    /// For building the pass's pipelines. Null for passes without attachments or that were
+    /// culled.
+    pub fn renderpass(&self, pass: PassId) -> vk::RenderPass {
+        self.passes
+            .iter()
+            .find(|p| p.id == pass)
+            .and_then(|p| p.renderpass.as_ref())
+            .map_or(vk::RenderPass::null(), |r| r.renderpass)
+    }
+
+    /// For descriptor sets that sample the image. Imported images give their first view.
+    pub fn view(&self, image: ImageId) -> vk::ImageView {
+        match self.images[image.0] {
+            GraphImage::Physical(i) => self.physical[i].sampled_view,
+            ref image => image.view(&self.physical, 0),
+        }
+    }
+
+    pub fn image(&self, image: ImageId) -> vk::Image {
+        self.images[image.0].image(&self.physical, 0)
+    }
+
+    /// Records every pass into `cmd_buf` with its barriers, calling `record` inside each.
+    /// `variant` picks among the images of multi-image imports, e.g. the swapchain image index.
+    pub fn execute<F: FnMut(&PassCtx)>(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        variant: usize,
+        mut record: F,
+    ) {
+        unsafe {
+            for pass in &self.passes {
+                pass.barriers
+                    .record(&self.dev, cmd_buf, &self.images, &self.physical, variant);
+                let renderpass = pass
+                    .renderpass
+                    .as_ref()
+                    .map_or(vk::RenderPass::null(), |r| r.renderpass);
+                if let Some(rp) = &pass.renderpass {
+                    let info = vk::RenderPassBeginInfo::builder()
+                        .render_pass(rp.renderpass)
+                        .framebuffer(pass.framebuffers[variant % pass.framebuffers.len()])
+                        .render_area(vk::Rect2D {
+                            offset: vk::Offset2D { x: 0, y: 0 },
+                            extent: pass.extent,
+                        })
+                        .clear_values(&pass.clear_values);
+                    self.dev
+                        .cmd_begin_render_pass(cmd_buf, &info, vk::SubpassContents::INLINE);
+                    self.dev.cmd_set_viewport(
+                        cmd_buf,
+                        0,
+                        &[vk::Viewport {
+                            x: 0.0,
+                            y: 0.0,
+                            width: pass.extent.width as _,
+                            height: pass.extent.height as _,
+                            min_depth: 0.0,
+                            max_depth: 1.0,
+                        }],
+                    );
+                    self.dev.cmd_set_scissor(
+                        cmd_buf,
+                        0,
+                        &[vk::Rect2D {
+                            extent: pass.extent,
+                            ..Default::default()
+                        }],
+                    );
+                }
+                record(&PassCtx {
+                    dev: &self.dev,
+                    cmd_buf,
+                    pass: pass.id,
+                    name: &pass.name,
+                    renderpass,
+                    extent: pass.extent,
+                });
+                if pass.renderpass.is_some() {
+                    self.dev.cmd_end_render_pass(cmd_buf);
+                }
+            }
+            self.finish
+                .record(&self.dev, cmd_buf, &self.images, &self.physical, variant);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(format: vk::Format) -> ImageDesc {
+        ImageDesc {
+            format,
+            extent: vk::Extent2D {
+                width: 64,
+                height: 64,
+            },
+            samples: vk::SampleCountFlags::TYPE_1,
+        }
+    }
+
+    fn output(graph: &mut RenderGraph) -> ImageId {
+        graph
+            .import(
+                "out",
+                desc(vk::Format::B8G8R8A8_SRGB),
+                vec![vk::Image::null(); 2],
+                vec![vk::ImageView::null(); 2],
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn import_needs_a_view_per_image() {
+        assert!(RenderGraph::new()
+            .import(
+                "out",
+                desc(vk::Format::B8G8R8A8_SRGB),
+                vec![vk::Image::null(); 2],
+                vec![vk::ImageView::null()],
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn sampled_aspect_is_one_that_exists() {
+        assert_eq!(
+            sampled_aspect(vk::Format::S8_UINT),
+            vk::ImageAspectFlags::STENCIL
+        );
+        assert_eq!(
+            sampled_aspect(vk::Format::D24_UNORM_S8_UINT),
+            vk::ImageAspectFlags::DEPTH
+        );
+        assert_eq!(
+            sampled_aspect(vk::Format::D32_SFLOAT),
+            vk::ImageAspectFlags::DEPTH
+        );
+        assert_eq!(
+            sampled_aspect(vk::Format::B8G8R8A8_SRGB),
+            vk::ImageAspectFlags::COLOR
+        );
+    }
+
+    const FRAG: vk::PipelineStageFlags = vk::PipelineStageFlags::FRAGMENT_SHADER;
+
+    #[test]
+    fn sort_puts_writers_first() {
+        let mut graph = RenderGraph::new();
+        let gbuffer = graph.transient("gbuffer", desc(vk::Format::R8G8B8A8_UNORM));
+        let out = output(&mut graph);
+        let lighting = graph
+            .pass("lighting")
+            .sampled(gbuffer, FRAG)
+            .color(out, LoadOp::DontCare)
+            .id();
+        let geometry = graph.pass("geometry").color(gbuffer, LoadOp::DontCare).id();
+        let ui = graph.pass("ui").color(out, LoadOp::Load).id();
+        assert_eq!(graph.sort().unwrap(), vec![geometry.0, lighting.0, ui.0]);
+    }
+
+    #[test]
+    fn sort_rejects_cycles_and_missing_writers() {
+        let mut graph = RenderGraph::new();
+        let a = graph.transient("a", desc(vk::Format::R8G8B8A8_UNORM));
+        let b = graph.transient("b", desc(vk::Format::R8G8B8A8_UNORM));
+        graph
+            .pass("first")
+            .sampled(a, FRAG)
+            .color(b, LoadOp::DontCare);
+        graph
+            .pass("second")
+            .sampled(b, FRAG)
+            .color(a, LoadOp::DontCare);
+        assert!(graph.sort().is_err());
+
+        let mut graph = RenderGraph::new();
+        let a = graph.transient("a", desc(vk::Format::R8G8B8A8_UNORM));
+        let out = output(&mut graph);
+        graph
+            .pass("first")
+            .sampled(a, FRAG)
+            .color(out, LoadOp::DontCare);
+        assert!(graph.sort().is_err());
+    }
+
+    #[test]
+    fn cull_keeps_what_reaches_outputs() {
+        let mut graph = RenderGraph::new();
+        let used = graph.transient("used", desc(vk::Format::R8G8B8A8_UNORM));
+        let unused = graph.transient("unused", desc(vk::Format::R8G8B8A8_UNORM));
+        let out = output(&mut graph);
+        let buffer = graph.import_buffer("buffer", vk::Buffer::null());
+        let producer = graph.pass("producer").color(used, LoadOp::DontCare).id();
+        graph.pass("dead").color(unused, LoadOp::DontCare);
+        let consumer = graph
+            .pass("consumer")
+            .sampled(used, FRAG)
+            .color(out, LoadOp::DontCare)
+            .id();
+        let compute = graph
+            .pass("compute")
+            .write_buffer(
+                buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+            )
+            .id();
+        let query = graph.pass("query").side_effects().id();
+        let order = graph.sort().unwrap();
+        assert_eq!(
+            graph.cull(order),
+            vec![producer.0, consumer.0, compute.0, query.0]
+        );
+    }
+
+    #[test]
+    fn alias_shares_disjoint_transients() {
+        let mut graph = RenderGraph::new();
+        let color = desc(vk::Format::R8G8B8A8_UNORM);
+        let a = graph.transient("a", color);
+        let b = graph.transient("b", color);
+        let c = graph.transient("c", color);
+        let depth = graph.transient("depth", desc(vk::Format::D32_SFLOAT));
+        let out = output(&mut graph);
+        graph.pass("a").color(a, LoadOp::DontCare);
+        graph.pass("b").sampled(a, FRAG).color(b, LoadOp::DontCare);
+        graph.pass("c").sampled(b, FRAG).color(c, LoadOp::DontCare);
+        graph
+            .pass("out")
+            .sampled(c, FRAG)
+            .color(out, LoadOp::DontCare)
+            .depth(depth, LoadOp::DontCare);
+        let order = graph.cull(graph.sort().unwrap());
+        let (assignment, physical) = graph.alias(&graph.lifetimes(&order));
+        assert_eq!(assignment, vec![Some(0), Some(1), Some(0), Some(2), None]);
+        assert_eq!(physical.len(), 3);
+        assert_eq!(physical[0].3, vec!["a".to_string(), "c".to_string()]);
+        assert_eq!(
+            physical[0].1,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED
+        );
+        assert_eq!(physical[2].1, vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT);
+    }
+}
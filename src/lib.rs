@@ -1,6 +1,8 @@
 pub extern crate ash;
 pub extern crate shaderc;
 pub extern crate winit;
+// Lets `#[derive(VertInfo)]` refer to `::flint` inside this crate too
+extern crate self as flint;
 
 #[cfg(target_os = "macos")]
 use cocoa::appkit::{NSView, NSWindow};
@@ -11,9 +13,9 @@ use metal::CoreAnimationLayer;
 #[cfg(target_os = "macos")]
 use objc::runtime::YES;
 
+use ash::extensions::khr::Surface;
 #[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
 use ash::extensions::khr::XlibSurface;
-use ash::extensions::{ext::DebugReport, khr::Surface};
 
 #[cfg(target_os = "windows")]
 use ash::extensions::khr::Win32Surface;
@@ -24,44 +26,62 @@ use ash::vk;
 
 use std::ffi::CStr;
 
-use std::os::raw::{c_char, c_void};
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+use std::os::raw::c_void;
 
-// Simple offset_of macro akin to C++ offsetof
+// Simple offset_of macro akin to C++ offsetof. The field's address is taken without creating a
+// reference, so the uninitialized value is never read.
 #[macro_export]
 macro_rules! offset_of {
     ($base:path, $field:ident) => {{
+        let b = ::std::mem::MaybeUninit::<$base>::uninit();
+        let base = b.as_ptr();
         #[allow(unused_unsafe)]
-        unsafe {
-            let b: $base = mem::uninitialized();
-            (&b.$field as *const _ as isize) - (&b as *const _ as isize)
-        }
+        let field = unsafe { ::std::ptr::addr_of!((*base).$field) };
+        (field as *const u8 as isize) - (base as *const u8 as isize)
     }};
 }
 
 pub mod base;
 pub mod buffer;
 pub mod command;
+pub mod debug;
+pub mod error;
+pub mod graph;
+pub mod readback;
+pub mod renderer;
 pub mod renderpass;
+pub mod rendertarget;
 pub mod sampler;
 pub mod shader;
+pub mod sync;
 pub mod texture;
 pub mod vertex;
 
+pub use error::{Error, Result};
+pub use flint_derive::VertInfo;
+
 #[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
 unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
     entry: &E,
     instance: &I,
     window: &winit::Window,
-) -> Result<vk::SurfaceKHR, vk::Result> {
+) -> Result<vk::SurfaceKHR> {
     use winit::os::unix::WindowExt;
-    let x11_display = window.get_xlib_display().unwrap();
-    let x11_window = window.get_xlib_window().unwrap();
+    let (x11_display, x11_window) = match (window.get_xlib_display(), window.get_xlib_window()) {
+        (Some(display), Some(window)) => (display, window),
+        _ => {
+            return Err(Error::Unsupported(
+                "Window isn't backed by Xlib".to_string(),
+            ))
+        }
+    };
     let x11_create_info = vk::XlibSurfaceCreateInfoKHR::builder()
         .window(x11_window)
         .dpy(x11_display as *mut vk::Display);
 
     let xlib_surface_loader = XlibSurface::new(entry, instance);
-    xlib_surface_loader.create_xlib_surface(&x11_create_info, None)
+    Ok(xlib_surface_loader.create_xlib_surface(&x11_create_info, None)?)
 }
 
 #[cfg(target_os = "macos")]
@@ -69,7 +89,7 @@ unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
     entry: &E,
     instance: &I,
     window: &winit::Window,
-) -> Result<vk::SurfaceKHR, vk::Result> {
+) -> Result<vk::SurfaceKHR> {
     use std::ptr;
     use winit::os::macos::WindowExt;
 
@@ -95,7 +115,7 @@ unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
     };
 
     let macos_surface_loader = MacOSSurface::new(entry, instance);
-    macos_surface_loader.create_mac_os_surface_mvk(&create_info, None)
+    Ok(macos_surface_loader.create_mac_os_surface_mvk(&create_info, None)?)
 }
 
 #[cfg(target_os = "windows")]
@@ -103,7 +123,7 @@ unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
     entry: &E,
     instance: &I,
     window: &winit::Window,
-) -> Result<vk::SurfaceKHR, vk::Result> {
+) -> Result<vk::SurfaceKHR> {
     use std::ptr;
     use winapi::shared::windef::HWND;
     use winapi::um::libloaderapi::GetModuleHandleW;
@@ -119,48 +139,22 @@ unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
         hwnd: hwnd as *const c_void,
     };
     let win32_surface_loader = Win32Surface::new(entry, instance);
-    win32_surface_loader.create_win32_surface(&win32_create_info, None)
+    Ok(win32_surface_loader.create_win32_surface(&win32_create_info, None)?)
 }
 
 #[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
-fn extension_names() -> Vec<*const i8> {
-    vec![
-        Surface::name().as_ptr(),
-        XlibSurface::name().as_ptr(),
-        DebugReport::name().as_ptr(),
-    ]
+fn surface_extension_names() -> Vec<&'static CStr> {
+    vec![Surface::name(), XlibSurface::name()]
 }
 
 #[cfg(target_os = "macos")]
-fn extension_names() -> Vec<*const i8> {
-    vec![
-        Surface::name().as_ptr(),
-        MacOSSurface::name().as_ptr(),
-        DebugReport::name().as_ptr(),
-    ]
+fn surface_extension_names() -> Vec<&'static CStr> {
+    vec![Surface::name(), MacOSSurface::name()]
 }
 
 #[cfg(all(windows))]
-fn extension_names() -> Vec<*const i8> {
-    vec![
-        Surface::name().as_ptr(),
-        Win32Surface::name().as_ptr(),
-        DebugReport::name().as_ptr(),
-    ]
-}
-
-unsafe extern "system" fn vulkan_debug_callback(
-    _: vk::DebugReportFlagsEXT,
-    _: vk::DebugReportObjectTypeEXT,
-    _: u64,
-    _: usize,
-    _: i32,
-    _: *const c_char,
-    p_message: *const c_char,
-    _: *mut c_void,
-) -> u32 {
-    println!("{:?}", CStr::from_ptr(p_message));
-    vk::FALSE
+fn surface_extension_names() -> Vec<&'static CStr> {
+    vec![Surface::name(), Win32Surface::name()]
 }
 
 pub fn find_memorytype_index(
@@ -197,3 +191,108 @@ pub fn find_memorytype_index_f<F: Fn(vk::MemoryPropertyFlags, vk::MemoryProperty
     }
     None
 }
+
+/// Depth formats in order of preference: precision first, then stencil.
+pub const DEPTH_FORMATS: [vk::Format; 4] = [
+    vk::Format::D32_SFLOAT,
+    vk::Format::D24_UNORM_S8_UINT,
+    vk::Format::D32_SFLOAT_S8_UINT,
+    vk::Format::D16_UNORM,
+];
+
+/// The first of `preferred` the device can use as an optimally tiled depth attachment.
+pub fn find_depth_format<I: InstanceV1_0>(
+    instance: &I,
+    pdevice: vk::PhysicalDevice,
+    preferred: &[vk::Format],
+) -> Result<vk::Format> {
+    preferred
+        .iter()
+        .cloned()
+        .find(|&format| {
+            let props = unsafe { instance.get_physical_device_format_properties(pdevice, format) };
+            props
+                .optimal_tiling_features
+                .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+        })
+        .ok_or_else(|| Error::Unsupported(format!("None of the depth formats {:?}", preferred)))
+}
+
+/// Whether `format` has depth or stencil, rather than color, components.
+pub fn is_depth_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D16_UNORM
+            | vk::Format::X8_D24_UNORM_PACK32
+            | vk::Format::D32_SFLOAT
+            | vk::Format::S8_UINT
+            | vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+pub fn has_stencil(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+            | vk::Format::S8_UINT
+    )
+}
+
+/// The aspects an image of a depth `format` has, for views and barriers.
+pub fn depth_aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    if format == vk::Format::S8_UINT {
+        vk::ImageAspectFlags::STENCIL
+    } else if has_stencil(format) {
+        vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+    } else {
+        vk::ImageAspectFlags::DEPTH
+    }
+}
+
+/// The highest sample count no greater than `requested` that both color and depth framebuffer
+/// attachments support.
+pub fn usable_samples(
+    limits: &vk::PhysicalDeviceLimits,
+    requested: vk::SampleCountFlags,
+) -> vk::SampleCountFlags {
+    let supported = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+    [
+        vk::SampleCountFlags::TYPE_64,
+        vk::SampleCountFlags::TYPE_32,
+        vk::SampleCountFlags::TYPE_16,
+        vk::SampleCountFlags::TYPE_8,
+        vk::SampleCountFlags::TYPE_4,
+        vk::SampleCountFlags::TYPE_2,
+    ]
+    .iter()
+    .cloned()
+    .find(|&count| count.as_raw() <= requested.as_raw() && supported.contains(count))
+    .unwrap_or(vk::SampleCountFlags::TYPE_1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth_aspects_match_components() {
+        assert_eq!(
+            depth_aspect(vk::Format::D32_SFLOAT),
+            vk::ImageAspectFlags::DEPTH
+        );
+        assert_eq!(
+            depth_aspect(vk::Format::D24_UNORM_S8_UINT),
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        );
+        assert_eq!(
+            depth_aspect(vk::Format::S8_UINT),
+            vk::ImageAspectFlags::STENCIL
+        );
+        assert!(!has_stencil(vk::Format::D32_SFLOAT));
+        assert!(has_stencil(vk::Format::S8_UINT));
+    }
+}
@@ -0,0 +1,279 @@
+use crate::buffer::BufToken;
+use crate::*;
+use ash::{version::DeviceV1_0, vk, Device};
+use std::path::Path;
+
+/// A host-visible buffer that a color image gets copied into, and the conversion from the
+/// image's format to RGBA8.
+pub struct Readback {
+    pub buf: BufToken,
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+}
+
+/// Bytes per texel of the color formats `Readback` can convert.
+fn texel_size(format: vk::Format) -> Option<u32> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A2B10G10R10_UNORM_PACK32 => Some(4),
+        vk::Format::R16G16B16A16_SFLOAT => Some(8),
+        _ => None,
+    }
+}
+
+impl Readback {
+    pub fn new(
+        dev: Device,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> Result<Self> {
+        let texel = texel_size(format)
+            .ok_or_else(|| Error::Unsupported(format!("Reading back {:?} images", format)))?;
+        Ok(Self {
+            buf: BufToken::with_size(
+                dev,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                vk::SharingMode::EXCLUSIVE,
+                mem_prop,
+                (extent.width * extent.height * texel) as _,
+            )?,
+            extent,
+            format,
+        })
+    }
+
+    /// Records copying `image`, which is in `layout` after color attachment writes, into the
+    /// buffer, leaving it in `layout` again. The image needs TRANSFER_SRC usage.
+    pub unsafe fn record(
+        &self,
+        dev: &Device,
+        cmd_buf: vk::CommandBuffer,
+        image: vk::Image,
+        layout: vk::ImageLayout,
+    ) {
+        let range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .layer_count(1)
+            .level_count(1)
+            .build();
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .image(image)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(layout)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .subresource_range(range);
+        dev.cmd_pipeline_barrier(
+            cmd_buf,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[to_transfer.build()],
+        );
+        let region = vk::BufferImageCopy::builder()
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .layer_count(1)
+                    .build(),
+            )
+            .image_extent(vk::Extent3D {
+                width: self.extent.width,
+                height: self.extent.height,
+                depth: 1,
+            });
+        dev.cmd_copy_image_to_buffer(
+            cmd_buf,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            self.buf.buf,
+            &[region.build()],
+        );
+        let back = vk::ImageMemoryBarrier::builder()
+            .image(image)
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(layout)
+            .subresource_range(range);
+        let host = vk::BufferMemoryBarrier::builder()
+            .buffer(self.buf.buf)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .size(vk::WHOLE_SIZE);
+        dev.cmd_pipeline_barrier(
+            cmd_buf,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[host.build()],
+            &[back.build()],
+        );
+    }
+
+    /// The raw texels, once the recorded copy has completed.
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        let len =
+            (self.extent.width * self.extent.height * texel_size(self.format).unwrap()) as usize;
+        let mut res = self.buf.read::<u8>()?;
+        res.truncate(len);
+        Ok(res)
+    }
+
+    /// The copied image as RGBA8, once the recorded copy has completed.
+    ///
+    /// sRGB formats already hold gamma-encoded bytes and are passed through, as are UNORM
+    /// formats. 10-bit formats are truncated to 8 bits without undoing any HDR transfer
+    /// function, and half-float (linear, possibly scRGB) values are clamped and sRGB encoded.
+    pub fn to_image(&self) -> Result<image::RgbaImage> {
+        let rgba = to_rgba8(self.format, self.bytes()?);
+        Ok(image::RgbaImage::from_raw(self.extent.width, self.extent.height, rgba).unwrap())
+    }
+}
+
+/// Converts texels of `format`, one that `texel_size` knows, to RGBA8 as described on
+/// `Readback::to_image`.
+fn to_rgba8(format: vk::Format, raw: Vec<u8>) -> Vec<u8> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => raw,
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => raw
+            .chunks(4)
+            .flat_map(|t| vec![t[2], t[1], t[0], t[3]])
+            .collect(),
+        vk::Format::A2B10G10R10_UNORM_PACK32 => raw
+            .chunks(4)
+            .flat_map(|t| {
+                let t = u32::from_le_bytes([t[0], t[1], t[2], t[3]]);
+                vec![
+                    (t >> 2 & 0xff) as u8,
+                    (t >> 12 & 0xff) as u8,
+                    (t >> 22 & 0xff) as u8,
+                    (t >> 30) as u8 * 85,
+                ]
+            })
+            .collect(),
+        vk::Format::R16G16B16A16_SFLOAT => raw
+            .chunks(8)
+            .flat_map(|t| {
+                let c = |i: usize| f16_to_f32(u16::from_le_bytes([t[i * 2], t[i * 2 + 1]]));
+                vec![
+                    encode_srgb(c(0)),
+                    encode_srgb(c(1)),
+                    encode_srgb(c(2)),
+                    (c(3).clamp(0.0, 1.0) * 255.0).round() as u8,
+                ]
+            })
+            .collect(),
+        // Rejected by new
+        _ => unreachable!(),
+    }
+}
+
+/// Writes `img` as a PNG, whatever the extension of `path`.
+pub fn save_png<P: AsRef<Path>>(img: &image::RgbaImage, path: P) -> Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    image::png::PNGEncoder::new(file).encode(
+        img,
+        img.width(),
+        img.height(),
+        image::ColorType::RGBA(8),
+    )?;
+    Ok(())
+}
+
+fn encode_srgb(linear: f32) -> u8 {
+    let c = linear.clamp(0.0, 1.0);
+    let c = if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = i32::from(h >> 10 & 0x1f);
+    let mantissa = f32::from(h & 0x3ff);
+    sign * match exp {
+        0 => mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exp - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bgra_is_swizzled() {
+        let raw = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(
+            to_rgba8(vk::Format::B8G8R8A8_SRGB, raw.clone()),
+            vec![3, 2, 1, 4, 7, 6, 5, 8]
+        );
+        assert_eq!(to_rgba8(vk::Format::R8G8B8A8_UNORM, raw.clone()), raw);
+    }
+
+    #[test]
+    fn a2b10g10r10_is_unpacked() {
+        let texel: u32 = 1023 | 4 << 10 | 512 << 20 | 3 << 30;
+        assert_eq!(
+            to_rgba8(
+                vk::Format::A2B10G10R10_UNORM_PACK32,
+                texel.to_le_bytes().to_vec()
+            ),
+            vec![255, 1, 128, 255]
+        );
+        let texel: u32 = 1 << 30;
+        assert_eq!(
+            to_rgba8(
+                vk::Format::A2B10G10R10_UNORM_PACK32,
+                texel.to_le_bytes().to_vec()
+            ),
+            vec![0, 0, 0, 85]
+        );
+    }
+
+    #[test]
+    fn f16_bit_patterns() {
+        assert_eq!(f16_to_f32(0x0000), 0.0);
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0x3800), 0.5);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        // Smallest subnormal
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(f16_to_f32(0xfc00), -f32::INFINITY);
+        assert!(f16_to_f32(0x7e00).is_nan());
+    }
+
+    #[test]
+    fn half_floats_are_srgb_encoded() {
+        // 1.0, 0.5, 0.0 and alpha 2.0, which clamps
+        let raw = [0x3c00u16, 0x3800, 0x0000, 0x4000]
+            .iter()
+            .flat_map(|h| h.to_le_bytes().to_vec())
+            .collect();
+        assert_eq!(
+            to_rgba8(vk::Format::R16G16B16A16_SFLOAT, raw),
+            vec![255, 188, 0, 255]
+        );
+    }
+
+    #[test]
+    fn srgb_encoding_clamps() {
+        assert_eq!(encode_srgb(-1.0), 0);
+        assert_eq!(encode_srgb(0.001), 3);
+        assert_eq!(encode_srgb(4.0), 255);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::base::{SurfToken, SwapToken, VkData, VkDataBuilder};
+use crate::readback::Readback;
+use crate::sync::FrameSync;
+use crate::Result;
+use ash::{version::DeviceV1_0, vk, Device};
+use std::panic::{self, AssertUnwindSafe};
+
+/// What a `Renderer::frame` closure records with.
+pub struct FrameCtx<'a> {
+    pub dev: &'a Device,
+    pub swap: &'a SwapToken,
+    pub cmd_buf: vk::CommandBuffer,
+    /// Swapchain image being rendered to.
+    pub image_index: u32,
+    pub extent: vk::Extent2D,
+    /// `FrameSync` slot, for picking per-frame resources.
+    pub frame_index: usize,
+}
+
+impl<'a> FrameCtx<'a> {
+    /// Begins the swapchain render pass on this frame's image and sets the dynamic viewport and
+    /// scissor to cover it.
+    pub fn begin_renderpass(&self, clear_values: &[vk::ClearValue]) {
+        let info = self
+            .swap
+            .renderpass_begin_info(self.image_index, clear_values);
+        unsafe {
+            self.dev
+                .cmd_begin_render_pass(self.cmd_buf, &info, vk::SubpassContents::INLINE);
+            self.dev
+                .cmd_set_viewport(self.cmd_buf, 0, &[self.swap.viewport]);
+            self.dev
+                .cmd_set_scissor(self.cmd_buf, 0, &[self.swap.scissors]);
+        }
+    }
+
+    pub fn end_renderpass(&self) {
+        unsafe { self.dev.cmd_end_render_pass(self.cmd_buf) };
+    }
+}
+
+/// Owns everything needed to draw to a window and runs the acquire, record, submit, present
+/// sequence, recreating the swapchain when it goes out of date.
+// Field order is drop order: the swapchain and frames go before the surface and the device.
+pub struct Renderer {
+    pub swap: SwapToken,
+    pub frames: FrameSync,
+    pub surface: SurfToken,
+    pub vk: VkData,
+    /// Per swapchain image, the fence of the last frame that rendered to it, or null.
+    images_in_flight: Vec<vk::Fence>,
+    needs_recreate: bool,
+    capture_requested: bool,
+    capture: Option<image::RgbaImage>,
+}
+
+impl Renderer {
+    pub fn new(name: &str, window: &winit::Window) -> Result<Self> {
+        Self::with_builder(VkData::builder(name), window)
+    }
+
+    pub fn with_builder(builder: VkDataBuilder, window: &winit::Window) -> Result<Self> {
+        let (vk, surface, swap) = builder.build(window)?;
+        Self::from_parts(vk, surface, swap)
+    }
+
+    pub fn from_parts(vk: VkData, surface: SurfToken, mut swap: SwapToken) -> Result<Self> {
+        let frames = vk.frame_sync()?;
+        // Swapchain objects replaced on resize are freed once no frame can still be using them
+        swap.retire_frames = frames.frames_in_flight();
+        Ok(Self {
+            frames,
+            swap,
+            surface,
+            vk,
+            images_in_flight: Vec::new(),
+            needs_recreate: false,
+            capture_requested: false,
+            capture: None,
+        })
+    }
+
+    /// Switches present mode preferences, recreating the swapchain before the next frame.
+    pub fn set_present_modes(&mut self, modes: Vec<vk::PresentModeKHR>) {
+        self.swap.set_present_modes(modes);
+        self.needs_recreate = true;
+    }
+
+    /// Switches the MSAA sample count, rebuilding the swapchain, render pass and pipelines
+    /// before the next frame.
+    pub fn set_samples(&mut self, samples: vk::SampleCountFlags) {
+        self.swap.set_samples(samples);
+        self.needs_recreate = true;
+    }
+
+    /// Copies out the next frame rendered, for `take_capture`. That frame waits for the GPU
+    /// to finish before returning.
+    pub fn capture_next_frame(&mut self) {
+        self.capture_requested = true;
+    }
+
+    /// The frame captured after `capture_next_frame`, as RGBA8.
+    pub fn take_capture(&mut self) -> Option<image::RgbaImage> {
+        self.capture.take()
+    }
+
+    /// Forces the swapchain to be recreated before the next frame, e.g. on a window resize.
+    pub fn resized(&mut self) {
+        self.needs_recreate = true;
+    }
+
+    /// Renders one frame with whatever `f` records. Returns `false` without calling `f` when
+    /// there's nothing to draw to, such as while the window is minimized. A panic in the
+    /// `DebugCallback` since the last frame is raised again here.
+    pub fn frame<F>(&mut self, window: &winit::Window, f: F) -> Result<bool>
+    where
+        F: FnOnce(&FrameCtx),
+    {
+        self.vk.debug.resume_panic();
+        self.frames.wait()?;
+        self.swap.collect_retired();
+
+        let image_index = match self.acquire(window)? {
+            Some(i) => i,
+            None => return Ok(false),
+        };
+
+        let frame = *self.frames.current();
+        // With fewer images than frames in flight, an earlier frame may still be drawing to
+        // this one
+        if self.images_in_flight.len() != self.swap.img_views.len() {
+            self.images_in_flight = vec![vk::Fence::null(); self.swap.img_views.len()];
+        }
+        let image_fence = self.images_in_flight[image_index as usize];
+        if image_fence != vk::Fence::null() && image_fence != frame.in_flight {
+            unsafe {
+                self.vk
+                    .device
+                    .wait_for_fences(&[image_fence], true, std::u64::MAX)?
+            };
+        }
+        self.images_in_flight[image_index as usize] = frame.in_flight;
+
+        let mut readback: Option<Result<Readback>> = None;
+        let recorded = {
+            let ctx = FrameCtx {
+                dev: &self.vk.device,
+                swap: &self.swap,
+                cmd_buf: frame.cmd_buf,
+                image_index,
+                extent: self.swap.base.extent,
+                frame_index: self.frames.index(),
+            };
+            let capture = self.capture_requested;
+            let mem_prop = &self.vk.device_memory_properties;
+            let frames = &self.frames;
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                frames.record(|_, cmd_buf| {
+                    f(&ctx);
+                    if capture {
+                        readback = Some(unsafe {
+                            ctx.swap.record_screenshot(cmd_buf, image_index, mem_prop)
+                        });
+                    }
+                })
+            }))
+        };
+        match recorded {
+            Ok(Ok(())) => (),
+            failed => {
+                // The image stays acquired without being presented; a new swapchain releases it
+                self.needs_recreate = true;
+                self.images_in_flight[image_index as usize] = vk::Fence::null();
+                self.frames.abandon(self.vk.present_queue)?;
+                match failed {
+                    Err(payload) => panic::resume_unwind(payload),
+                    Ok(res) => res?,
+                }
+            }
+        }
+        if let Err(e) = self.frames.submit(
+            self.vk.present_queue,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        ) {
+            // submit has abandoned the slot; the image is left acquired as above
+            self.needs_recreate = true;
+            self.images_in_flight[image_index as usize] = vk::Fence::null();
+            return Err(e);
+        }
+        // A failed capture is reported after presenting, so the frame still goes out
+        let captured = match readback {
+            Some(readback) => {
+                self.capture_requested = false;
+                self.frames
+                    .wait()
+                    .and_then(|_| readback?.to_image())
+                    .map(|img| self.capture = Some(img))
+            }
+            None => Ok(()),
+        };
+
+        let wait_semaphores = [frame.render_finished];
+        let swapchains = [self.swap.base.chain];
+        let image_indices = [image_index];
+        let present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(&wait_semaphores)
+            .swapchains(&swapchains)
+            .image_indices(&image_indices);
+        let present = unsafe {
+            self.swap
+                .base
+                .loader
+                .queue_present(self.vk.present_queue, &present_info)
+        };
+        self.frames.advance();
+        match present {
+            Ok(false) => (),
+            Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.needs_recreate = true,
+            Err(e) => return Err(e.into()),
+        }
+        captured.map(|_| true)
+    }
+
+    /// Acquires the next swapchain image, recreating the swapchain first if needed. `None`
+    /// means the window can't be drawn to right now.
+    fn acquire(&mut self, window: &winit::Window) -> Result<Option<u32>> {
+        // One retry: a swapchain that's out of date straight after recreation is given up on
+        // until the next frame
+        for _ in 0..2 {
+            if self.needs_recreate && !self.recreate(window)? {
+                return Ok(None);
+            }
+            let acquired = unsafe {
+                self.swap.base.loader.acquire_next_image(
+                    self.swap.base.chain,
+                    std::u64::MAX,
+                    self.frames.current().image_available,
+                    vk::Fence::null(),
+                )
+            };
+            match acquired {
+                Ok((index, suboptimal)) => {
+                    // Suboptimal still presents fine; recreate after this frame
+                    self.needs_recreate |= suboptimal;
+                    return Ok(Some(index));
+                }
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.needs_recreate = true,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns `false`, leaving the recreation pending, if the window has no area.
+    fn recreate(&mut self, window: &winit::Window) -> Result<bool> {
+        match window.get_inner_size() {
+            Some(size) if size.width >= 1.0 && size.height >= 1.0 => (),
+            _ => return Ok(false),
+        }
+        self.swap.recreate(
+            self.vk.pdevice,
+            &self.vk.instance,
+            &self.surface,
+            window,
+            &self.vk.device_memory_properties,
+        )?;
+        self.needs_recreate = false;
+        Ok(true)
+    }
+}
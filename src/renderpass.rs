@@ -1,8 +1,12 @@
+use crate::debug::{DebugName, DebugNamer};
+use crate::{Error, Result};
 use ash::{version::DeviceV1_0, vk, vk::Format, Device};
 
 pub struct RenderPassToken {
     dev: Device,
     pub renderpass: vk::RenderPass,
+    /// As declared, in framebuffer attachment order.
+    pub attachments: Vec<vk::AttachmentDescription>,
 }
 
 impl Drop for RenderPassToken {
@@ -13,62 +17,352 @@ impl Drop for RenderPassToken {
     }
 }
 
+impl DebugName for RenderPassToken {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.renderpass, name);
+    }
+}
+
 impl RenderPassToken {
     unsafe fn clean(&self) {
         self.dev.destroy_render_pass(self.renderpass, None);
     }
 
-    pub fn new(dev: Device, format: Format) -> Self {
-        let renderpass_attachments = [
-            vk::AttachmentDescription {
+    pub fn builder() -> RenderPassBuilder {
+        RenderPassBuilder::new()
+    }
+
+    pub fn new(dev: Device, format: Format, depth_format: Format) -> Result<Self> {
+        Self::with_final_layout(dev, format, depth_format, vk::ImageLayout::PRESENT_SRC_KHR)
+    }
+
+    /// Same pass as `new`, but the color attachment ends in `final_layout` rather than being
+    /// ready for presentation.
+    pub fn with_final_layout(
+        dev: Device,
+        format: Format,
+        depth_format: Format,
+        final_layout: vk::ImageLayout,
+    ) -> Result<Self> {
+        Self::multisampled(
+            dev,
+            format,
+            depth_format,
+            vk::SampleCountFlags::TYPE_1,
+            final_layout,
+        )
+    }
+
+    /// See `RenderPassBuilder::forward`.
+    pub fn multisampled(
+        dev: Device,
+        format: Format,
+        depth_format: Format,
+        samples: vk::SampleCountFlags,
+        final_layout: vk::ImageLayout,
+    ) -> Result<Self> {
+        RenderPassBuilder::forward(format, depth_format, samples, final_layout).build(dev)
+    }
+}
+
+/// A cleared or loaded color attachment that's stored and ends in `final_layout`. Loading
+/// expects the image to already be in `final_layout`; set `initial_layout` otherwise.
+pub fn color_attachment(
+    format: Format,
+    samples: vk::SampleCountFlags,
+    load_op: vk::AttachmentLoadOp,
+    final_layout: vk::ImageLayout,
+) -> vk::AttachmentDescription {
+    vk::AttachmentDescription {
+        format,
+        samples,
+        load_op,
+        store_op: vk::AttachmentStoreOp::STORE,
+        stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+        stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+        initial_layout: match load_op {
+            vk::AttachmentLoadOp::LOAD => final_layout,
+            _ => vk::ImageLayout::UNDEFINED,
+        },
+        final_layout,
+        ..Default::default()
+    }
+}
+
+/// A depth (and stencil, if `format` has it) attachment that ends in
+/// DEPTH_STENCIL_ATTACHMENT_OPTIMAL. Its contents are cleared or loaded but not stored; set
+/// `store_op` to read it afterwards. Loading expects the image to already be in that layout,
+/// while anything else starts from UNDEFINED, so a fresh image needs no transition.
+pub fn depth_attachment(
+    format: Format,
+    samples: vk::SampleCountFlags,
+    load_op: vk::AttachmentLoadOp,
+) -> vk::AttachmentDescription {
+    let stencil = crate::has_stencil(format);
+    vk::AttachmentDescription {
+        format,
+        samples,
+        load_op,
+        store_op: vk::AttachmentStoreOp::DONT_CARE,
+        stencil_load_op: if stencil {
+            load_op
+        } else {
+            vk::AttachmentLoadOp::DONT_CARE
+        },
+        stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+        initial_layout: match load_op {
+            vk::AttachmentLoadOp::LOAD => vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            _ => vk::ImageLayout::UNDEFINED,
+        },
+        final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        ..Default::default()
+    }
+}
+
+/// Which attachments, by index, a subpass uses and how.
+#[derive(Debug, Clone, Default)]
+pub struct SubpassDesc {
+    pub colors: Vec<u32>,
+    /// Read in the fragment shader as `subpassInput`s.
+    pub inputs: Vec<u32>,
+    /// One per color attachment, in the same order; `vk::ATTACHMENT_UNUSED` skips one.
+    pub resolves: Vec<u32>,
+    pub depth: Option<u32>,
+    /// Not used here, but their contents must survive the subpass.
+    pub preserves: Vec<u32>,
+}
+
+impl SubpassDesc {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn color(mut self, attachment: u32) -> Self {
+        self.colors.push(attachment);
+        self
+    }
+
+    pub fn input(mut self, attachment: u32) -> Self {
+        self.inputs.push(attachment);
+        self
+    }
+
+    pub fn resolve(mut self, attachment: u32) -> Self {
+        self.resolves.push(attachment);
+        self
+    }
+
+    pub fn depth(mut self, attachment: u32) -> Self {
+        self.depth = Some(attachment);
+        self
+    }
+
+    pub fn preserve(mut self, attachment: u32) -> Self {
+        self.preserves.push(attachment);
+        self
+    }
+}
+
+/// Declares a render pass's attachments, graphics subpasses, and dependencies.
+///
+/// References are laid out as their use implies: COLOR_ATTACHMENT_OPTIMAL for color and
+/// resolve, DEPTH_STENCIL_ATTACHMENT_OPTIMAL for depth, and SHADER_READ_ONLY_OPTIMAL (or the
+/// depth read-only layout) for inputs.
+#[derive(Debug, Clone, Default)]
+pub struct RenderPassBuilder {
+    pub attachments: Vec<vk::AttachmentDescription>,
+    pub subpasses: Vec<SubpassDesc>,
+    pub dependencies: Vec<vk::SubpassDependency>,
+}
+
+impl RenderPassBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// One subpass drawing to color attachment 0 with depth attachment 1, both cleared. With
+    /// more than one sample those two are multisampled and resolve into attachment 2, which has
+    /// `format` and ends in `final_layout`; only attachments 0 and 1 need clear values.
+    pub fn forward(
+        format: Format,
+        depth_format: Format,
+        samples: vk::SampleCountFlags,
+        final_layout: vk::ImageLayout,
+    ) -> Self {
+        let clear = vk::AttachmentLoadOp::CLEAR;
+        if samples == vk::SampleCountFlags::TYPE_1 {
+            Self::new()
+                .attachment(color_attachment(format, samples, clear, final_layout))
+                .attachment(depth_attachment(depth_format, samples, clear))
+                .subpass(SubpassDesc::new().color(0).depth(1))
+        } else {
+            // The multisampled color is only needed until it's resolved
+            let mut color = color_attachment(
                 format,
-                samples: vk::SampleCountFlags::TYPE_1,
-                load_op: vk::AttachmentLoadOp::CLEAR,
-                store_op: vk::AttachmentStoreOp::STORE,
-                final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
-                ..Default::default()
-            },
-            vk::AttachmentDescription {
-                format: vk::Format::D16_UNORM,
-                samples: vk::SampleCountFlags::TYPE_1,
-                load_op: vk::AttachmentLoadOp::CLEAR,
-                initial_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
-                final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
-                ..Default::default()
-            },
-        ];
-        let color_attachment_refs = [vk::AttachmentReference {
-            attachment: 0,
-            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-        }];
-        let depth_attachment_ref = vk::AttachmentReference {
-            attachment: 1,
-            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                samples,
+                clear,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            );
+            color.store_op = vk::AttachmentStoreOp::DONT_CARE;
+            Self::new()
+                .attachment(color)
+                .attachment(depth_attachment(depth_format, samples, clear))
+                .attachment(color_attachment(
+                    format,
+                    vk::SampleCountFlags::TYPE_1,
+                    vk::AttachmentLoadOp::DONT_CARE,
+                    final_layout,
+                ))
+                .subpass(SubpassDesc::new().color(0).depth(1).resolve(2))
+        }
+    }
+
+    /// Attachments are numbered in the order they're added.
+    pub fn attachment(mut self, desc: vk::AttachmentDescription) -> Self {
+        self.attachments.push(desc);
+        self
+    }
+
+    pub fn subpass(mut self, desc: SubpassDesc) -> Self {
+        self.subpasses.push(desc);
+        self
+    }
+
+    /// Without any, the pass gets a single dependency making the first subpass's color and
+    /// depth writes wait for earlier ones, enough for a pass that's the only one in a frame,
+    /// even with frames in flight sharing depth and MSAA images.
+    pub fn dependency(mut self, dep: vk::SubpassDependency) -> Self {
+        self.dependencies.push(dep);
+        self
+    }
+
+    fn reference(
+        &self,
+        attachment: u32,
+        layout: vk::ImageLayout,
+    ) -> Result<vk::AttachmentReference> {
+        if attachment != vk::ATTACHMENT_UNUSED && attachment as usize >= self.attachments.len() {
+            return Err(Error::Unsupported(format!(
+                "Subpass referring to attachment {} of {}",
+                attachment,
+                self.attachments.len()
+            )));
+        }
+        Ok(vk::AttachmentReference { attachment, layout })
+    }
+
+    fn input_reference(&self, attachment: u32) -> Result<vk::AttachmentReference> {
+        // Out of range indices are reported by `reference`
+        let depth = self
+            .attachments
+            .get(attachment as usize)
+            .filter(|a| crate::is_depth_format(a.format))
+            .is_some();
+        let layout = if depth {
+            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+        } else {
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
         };
-        let dependencies = [vk::SubpassDependency {
+        self.reference(attachment, layout)
+    }
+
+    pub fn build(&self, dev: Device) -> Result<RenderPassToken> {
+        struct Refs {
+            colors: Vec<vk::AttachmentReference>,
+            inputs: Vec<vk::AttachmentReference>,
+            resolves: Vec<vk::AttachmentReference>,
+            depth: Option<vk::AttachmentReference>,
+        }
+        let refs = self
+            .subpasses
+            .iter()
+            .map(|sub| {
+                if !sub.resolves.is_empty() && sub.resolves.len() != sub.colors.len() {
+                    return Err(Error::Unsupported(format!(
+                        "Subpass with {} resolve attachments for {} color attachments",
+                        sub.resolves.len(),
+                        sub.colors.len()
+                    )));
+                }
+                let color = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+                Ok(Refs {
+                    colors: sub
+                        .colors
+                        .iter()
+                        .map(|&a| self.reference(a, color))
+                        .collect::<Result<_>>()?,
+                    inputs: sub
+                        .inputs
+                        .iter()
+                        .map(|&a| self.input_reference(a))
+                        .collect::<Result<_>>()?,
+                    resolves: sub
+                        .resolves
+                        .iter()
+                        .map(|&a| self.reference(a, color))
+                        .collect::<Result<_>>()?,
+                    depth: match sub.depth {
+                        Some(a) => Some(
+                            self.reference(a, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)?,
+                        ),
+                        None => None,
+                    },
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let subpasses = self
+            .subpasses
+            .iter()
+            .zip(refs.iter())
+            .map(|(sub, refs)| {
+                let mut desc = vk::SubpassDescription::builder()
+                    .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                    .color_attachments(&refs.colors)
+                    .input_attachments(&refs.inputs)
+                    .preserve_attachments(&sub.preserves);
+                if !refs.resolves.is_empty() {
+                    desc = desc.resolve_attachments(&refs.resolves);
+                }
+                if let Some(depth) = &refs.depth {
+                    desc = desc.depth_stencil_attachment(depth);
+                }
+                desc.build()
+            })
+            .collect::<Vec<_>>();
+
+        // The previous frame's attachment writes have to finish before this one's clears and
+        // writes, as the depth and MSAA images aren't per frame
+        let attachment_stages = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+        let default_dependencies = [vk::SubpassDependency {
             src_subpass: vk::SUBPASS_EXTERNAL,
-            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            src_stage_mask: attachment_stages,
+            src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
             dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ
-                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
-            dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            dst_stage_mask: attachment_stages,
             ..Default::default()
         }];
-
-        let subpasses = [vk::SubpassDescription::builder()
-            .color_attachments(&color_attachment_refs)
-            .depth_stencil_attachment(&depth_attachment_ref)
-            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-            .build()];
+        let dependencies = if self.dependencies.is_empty() {
+            &default_dependencies[..]
+        } else {
+            &self.dependencies[..]
+        };
 
         let renderpass_create_info = vk::RenderPassCreateInfo::builder()
-            .attachments(&renderpass_attachments)
+            .attachments(&self.attachments)
             .subpasses(&subpasses)
-            .dependencies(&dependencies);
+            .dependencies(dependencies);
 
-        let renderpass = unsafe {
-            dev.create_render_pass(&renderpass_create_info, None)
-                .unwrap()
-        };
-        Self { dev, renderpass }
+        let renderpass = unsafe { dev.create_render_pass(&renderpass_create_info, None)? };
+        Ok(RenderPassToken {
+            dev,
+            renderpass,
+            attachments: self.attachments.clone(),
+        })
     }
 }
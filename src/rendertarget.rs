@@ -0,0 +1,365 @@
+use crate::debug::{DebugName, DebugNamer};
+use crate::readback::Readback;
+use crate::renderpass::{
+    color_attachment, depth_attachment, RenderPassBuilder, RenderPassToken, SubpassDesc,
+};
+use crate::*;
+use ash::{version::DeviceV1_0, vk, Device};
+
+/// An offscreen color target, with an optional depth buffer, that has its own render pass and
+/// framebuffer. Use it to render without a swapchain, or to render to a texture.
+///
+/// After the render pass, the color image is in SHADER_READ_ONLY_OPTIMAL and can be bound with
+/// `tex_info`. Record the pass before any pass that samples it. It's undefined until the pass
+/// has run once.
+pub struct RenderTarget {
+    dev: Device,
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    pub color_img: vk::Image,
+    pub color_mem: vk::DeviceMemory,
+    pub color_view: vk::ImageView,
+    /// These are null when there's no depth buffer.
+    pub depth_img: vk::Image,
+    pub depth_fmt: Option<vk::Format>,
+    pub depth_mem: vk::DeviceMemory,
+    pub depth_view: vk::ImageView,
+    pub renderpass: RenderPassToken,
+    pub framebuffer: vk::Framebuffer,
+    pub viewport: vk::Viewport,
+    pub scissors: vk::Rect2D,
+}
+
+impl Drop for RenderTarget {
+    fn drop(&mut self) {
+        eprintln!("Dropping RenderTarget");
+        unsafe {
+            self.dev.destroy_framebuffer(self.framebuffer, None);
+            self.dev.destroy_image_view(self.color_view, None);
+            self.dev.destroy_image_view(self.depth_view, None);
+            self.dev.destroy_image(self.color_img, None);
+            self.dev.destroy_image(self.depth_img, None);
+            self.dev.free_memory(self.color_mem, None);
+            self.dev.free_memory(self.depth_mem, None);
+        }
+    }
+}
+
+impl DebugName for RenderTarget {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.color_img, &format!("{} color", name));
+        namer.name(self.color_view, &format!("{} color view", name));
+        namer.name(self.color_mem, &format!("{} color memory", name));
+        if self.depth_fmt.is_some() {
+            namer.name(self.depth_img, &format!("{} depth", name));
+            namer.name(self.depth_view, &format!("{} depth view", name));
+            namer.name(self.depth_mem, &format!("{} depth memory", name));
+        }
+        namer.name(self.framebuffer, &format!("{} framebuffer", name));
+        self.renderpass
+            .set_name(namer, &format!("{} renderpass", name));
+    }
+}
+
+/// Creates an image and its memory, storing each handle as soon as it exists so the caller's
+/// `Drop` can clean up after a failure.
+pub(crate) unsafe fn make_image(
+    dev: &Device,
+    mem_prop: &vk::PhysicalDeviceMemoryProperties,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    samples: vk::SampleCountFlags,
+    usage: vk::ImageUsageFlags,
+    img: &mut vk::Image,
+    mem: &mut vk::DeviceMemory,
+) -> Result<()> {
+    let create_info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(samples)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    *img = dev.create_image(&create_info, None)?;
+
+    let req = dev.get_image_memory_requirements(*img);
+    let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+    let index =
+        find_memorytype_index(&req, mem_prop, flags).ok_or(Error::NoSuitableMemoryType(flags))?;
+    *mem = dev.allocate_memory(
+        &vk::MemoryAllocateInfo::builder()
+            .allocation_size(req.size)
+            .memory_type_index(index),
+        None,
+    )?;
+    dev.bind_image_memory(*img, *mem, 0)?;
+    Ok(())
+}
+
+pub(crate) unsafe fn make_view(
+    dev: &Device,
+    img: vk::Image,
+    format: vk::Format,
+    aspect_mask: vk::ImageAspectFlags,
+) -> Result<vk::ImageView> {
+    let info = vk::ImageViewCreateInfo::builder()
+        .subresource_range(
+            vk::ImageSubresourceRange::builder()
+                .aspect_mask(aspect_mask)
+                .level_count(1)
+                .layer_count(1)
+                .build(),
+        )
+        .image(img)
+        .format(format)
+        .view_type(vk::ImageViewType::TYPE_2D);
+    Ok(dev.create_image_view(&info, None)?)
+}
+
+/// One subpass clearing and drawing to color attachment 0, and depth attachment 1 if there is
+/// one. The color attachment ends up ready to sample.
+fn make_renderpass(
+    dev: Device,
+    format: vk::Format,
+    depth_fmt: Option<vk::Format>,
+) -> Result<RenderPassToken> {
+    let clear = vk::AttachmentLoadOp::CLEAR;
+    let samples = vk::SampleCountFlags::TYPE_1;
+    let mut builder = RenderPassBuilder::new().attachment(color_attachment(
+        format,
+        samples,
+        clear,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    ));
+    let mut subpass = SubpassDesc::new().color(0);
+    if let Some(depth_fmt) = depth_fmt {
+        builder = builder.attachment(depth_attachment(depth_fmt, samples, clear));
+        subpass = subpass.depth(1);
+    }
+    let depth_stages =
+        vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+    builder
+        .subpass(subpass)
+        // Don't overwrite the image while last frame's passes may still be sampling it, or clear
+        // depth while the last render may still be writing it
+        .dependency(vk::SubpassDependency {
+            src_subpass: vk::SUBPASS_EXTERNAL,
+            dst_subpass: 0,
+            src_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER
+                | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | depth_stages,
+            src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | depth_stages,
+            dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ
+                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            ..Default::default()
+        })
+        // and make what's drawn visible to later passes' shaders
+        .dependency(vk::SubpassDependency {
+            src_subpass: 0,
+            dst_subpass: vk::SUBPASS_EXTERNAL,
+            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+            dst_access_mask: vk::AccessFlags::SHADER_READ,
+            ..Default::default()
+        })
+        .build(dev)
+}
+
+impl RenderTarget {
+    pub fn new(
+        dev: Device,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        depth_fmt: vk::Format,
+    ) -> Result<Self> {
+        Self::with_depth(dev, mem_prop, extent, format, Some(depth_fmt))
+    }
+
+    /// A target without a depth buffer, e.g. for post-processing.
+    pub fn color_only(
+        dev: Device,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> Result<Self> {
+        Self::with_depth(dev, mem_prop, extent, format, None)
+    }
+
+    pub fn with_depth(
+        dev: Device,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        depth_fmt: Option<vk::Format>,
+    ) -> Result<Self> {
+        let renderpass = make_renderpass(dev.clone(), format, depth_fmt)?;
+        let mut res = Self {
+            dev,
+            extent,
+            format,
+            color_img: vk::Image::null(),
+            color_mem: vk::DeviceMemory::null(),
+            color_view: vk::ImageView::null(),
+            depth_img: vk::Image::null(),
+            depth_fmt,
+            depth_mem: vk::DeviceMemory::null(),
+            depth_view: vk::ImageView::null(),
+            renderpass,
+            framebuffer: vk::Framebuffer::null(),
+            viewport: vk::Viewport {
+                x: 0.0,
+                y: 0.0,
+                width: extent.width as _,
+                height: extent.height as _,
+                min_depth: 0.0,
+                max_depth: 1.0,
+            },
+            scissors: vk::Rect2D {
+                extent,
+                ..Default::default()
+            },
+        };
+        let dev = &res.dev;
+
+        unsafe {
+            make_image(
+                dev,
+                mem_prop,
+                format,
+                extent,
+                vk::SampleCountFlags::TYPE_1,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
+                &mut res.color_img,
+                &mut res.color_mem,
+            )?;
+            res.color_view = make_view(dev, res.color_img, format, vk::ImageAspectFlags::COLOR)?;
+        }
+        let depth_fmt = match depth_fmt {
+            Some(depth_fmt) => depth_fmt,
+            None => {
+                res.framebuffer = res.make_framebuffer()?;
+                return Ok(res);
+            }
+        };
+        unsafe {
+            make_image(
+                dev,
+                mem_prop,
+                depth_fmt,
+                extent,
+                vk::SampleCountFlags::TYPE_1,
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                &mut res.depth_img,
+                &mut res.depth_mem,
+            )?;
+            res.depth_view = make_view(dev, res.depth_img, depth_fmt, depth_aspect(depth_fmt))?;
+        }
+
+        // The render pass clears depth from UNDEFINED, so there's no transition to do here
+        res.framebuffer = res.make_framebuffer()?;
+
+        Ok(res)
+    }
+
+    fn make_framebuffer(&self) -> Result<vk::Framebuffer> {
+        let mut attachments = vec![self.color_view];
+        if self.depth_fmt.is_some() {
+            attachments.push(self.depth_view);
+        }
+        let info = vk::FramebufferCreateInfo::builder()
+            .render_pass(self.renderpass.renderpass)
+            .attachments(&attachments)
+            .width(self.extent.width)
+            .height(self.extent.height)
+            .layers(1);
+        Ok(unsafe { self.dev.create_framebuffer(&info, None)? })
+    }
+
+    /// For binding the color image with `DescWriteInfo::Img`, like `Texture::tex_info`.
+    pub fn tex_info(&self, sampler: vk::Sampler) -> vk::DescriptorImageInfo {
+        vk::DescriptorImageInfo {
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            image_view: self.color_view,
+            sampler,
+        }
+    }
+
+    pub fn renderpass_begin_info(
+        &self,
+        clear_values: &[vk::ClearValue],
+    ) -> vk::RenderPassBeginInfo {
+        vk::RenderPassBeginInfo::builder()
+            .render_pass(self.renderpass.renderpass)
+            .framebuffer(self.framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.extent,
+            })
+            .clear_values(clear_values)
+            .build()
+    }
+
+    /// Copies the color attachment into host memory, in `format`. The render pass must have run
+    /// at least once.
+    pub fn read_back(
+        &self,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        cmd_buf: vk::CommandBuffer,
+        queue: vk::Queue,
+    ) -> Result<Vec<u8>> {
+        self.copy_out(mem_prop, cmd_buf, queue)?.bytes()
+    }
+
+    /// Like `read_back`, converted to RGBA8.
+    pub fn screenshot(
+        &self,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        cmd_buf: vk::CommandBuffer,
+        queue: vk::Queue,
+    ) -> Result<image::RgbaImage> {
+        self.copy_out(mem_prop, cmd_buf, queue)?.to_image()
+    }
+
+    fn copy_out(
+        &self,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        cmd_buf: vk::CommandBuffer,
+        queue: vk::Queue,
+    ) -> Result<Readback> {
+        let readback = Readback::new(self.dev.clone(), mem_prop, self.extent, self.format)?;
+        unsafe {
+            crate::command::record_submit_commandbuffer(
+                &self.dev,
+                cmd_buf,
+                queue,
+                &[],
+                &[],
+                &[],
+                |device, cmd_buf| {
+                    readback.record(
+                        device,
+                        cmd_buf,
+                        self.color_img,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    )
+                },
+            )?;
+        }
+        Ok(readback)
+    }
+}
@@ -1,3 +1,5 @@
+use crate::debug::{DebugName, DebugNamer};
+use crate::Result;
 use ash::{version::DeviceV1_0, vk, Device};
 
 pub struct SamplerToken {
@@ -14,8 +16,14 @@ impl Drop for SamplerToken {
     }
 }
 
+impl DebugName for SamplerToken {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.sampler, name);
+    }
+}
+
 impl SamplerToken {
-    pub fn new(dev: Device) -> Self {
+    pub fn new(dev: Device) -> Result<Self> {
         let sampler_info = vk::SamplerCreateInfo {
             mag_filter: vk::Filter::NEAREST,
             min_filter: vk::Filter::NEAREST,
@@ -28,9 +36,9 @@ impl SamplerToken {
             compare_op: vk::CompareOp::NEVER,
             ..Default::default()
         };
-        Self {
-            sampler: unsafe { dev.create_sampler(&sampler_info, None).unwrap() },
+        Ok(Self {
+            sampler: unsafe { dev.create_sampler(&sampler_info, None)? },
             dev,
-        }
+        })
     }
 }
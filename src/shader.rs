@@ -1,3 +1,5 @@
+use crate::debug::{DebugName, DebugNamer};
+use crate::{Error, Result};
 use ash::{
     version::DeviceV1_0,
     vk::{PipelineShaderStageCreateInfo, ShaderModule, ShaderModuleCreateInfo, ShaderStageFlags},
@@ -6,20 +8,41 @@ use ash::{
 use shaderc::*;
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::CString;
-use std::iter::FromIterator;
+use std::path::PathBuf;
+use std::str::FromStr;
 
+mod cache;
+mod compute;
 mod descriptor;
 mod pipeline;
+mod reflect;
+#[cfg(feature = "hot-reload")]
+mod watch;
 
+pub use cache::*;
+pub use compute::*;
 pub use descriptor::*;
 pub use pipeline::*;
+pub use reflect::*;
+#[cfg(feature = "hot-reload")]
+pub use watch::*;
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
 pub enum ShaderStage {
     Vert,
     Geom,
     Frag,
+    Comp,
+    /// Tessellation control.
+    Tesc,
+    /// Tessellation evaluation.
+    Tese,
+    /// Needs `VK_NV_mesh_shader`.
+    Task,
+    /// Needs `VK_NV_mesh_shader`.
+    Mesh,
 }
 
 impl Ord for ShaderStage {
@@ -31,50 +54,54 @@ impl Ord for ShaderStage {
 impl PartialOrd for ShaderStage {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         use ShaderStage::*;
-        Some(match self {
-            Vert => {
-                if let Vert = other {
-                    Ordering::Equal
-                } else {
-                    Ordering::Less
-                }
-            }
-            Frag => match other {
-                Vert => Ordering::Greater,
-                Frag => Ordering::Equal,
-                Geom => Ordering::Less,
-            },
-            Geom => {
-                if let Geom = other {
-                    Ordering::Equal
-                } else {
-                    Ordering::Greater
-                }
-            }
-        })
+        // The order stages run in. Compute pipelines only ever have the one stage, so its
+        // place doesn't matter
+        let rank = |s: &Self| match s {
+            Task => 0,
+            Mesh => 1,
+            Vert => 2,
+            Tesc => 3,
+            Tese => 4,
+            Geom => 5,
+            Frag => 6,
+            Comp => 7,
+        };
+        rank(self).partial_cmp(&rank(other))
     }
 }
 
-impl From<ShaderKind> for ShaderStage {
-    fn from(k: ShaderKind) -> Self {
+impl TryFrom<ShaderKind> for ShaderStage {
+    type Error = Error;
+    fn try_from(k: ShaderKind) -> Result<Self> {
         match k {
-            ShaderKind::Vertex => ShaderStage::Vert,
-            ShaderKind::Geometry => ShaderStage::Geom,
-            ShaderKind::Fragment => ShaderStage::Frag,
-            _ => panic!("Not Implemented - ShaderKind -> ShaderStage"),
+            ShaderKind::Vertex => Ok(ShaderStage::Vert),
+            ShaderKind::Geometry => Ok(ShaderStage::Geom),
+            ShaderKind::Fragment => Ok(ShaderStage::Frag),
+            ShaderKind::Compute => Ok(ShaderStage::Comp),
+            ShaderKind::TessControl => Ok(ShaderStage::Tesc),
+            ShaderKind::TessEvaluation => Ok(ShaderStage::Tese),
+            ShaderKind::Task => Ok(ShaderStage::Task),
+            ShaderKind::Mesh => Ok(ShaderStage::Mesh),
+            _ => Err(Error::Unsupported(format!("Shader kind {:?}", k))),
         }
     }
 }
 
-impl From<spirv_cross::spirv::ExecutionModel> for ShaderStage {
-    fn from(s: spirv_cross::spirv::ExecutionModel) -> Self {
+/// spirv_cross 0.15's `ExecutionModel` stops at `Kernel`, so there are no task or mesh arms;
+/// `reflect::reflect_stage` reads those from the module itself.
+impl TryFrom<spirv_cross::spirv::ExecutionModel> for ShaderStage {
+    type Error = Error;
+    fn try_from(s: spirv_cross::spirv::ExecutionModel) -> Result<Self> {
         use spirv_cross::spirv::ExecutionModel::*;
         use ShaderStage::*;
         match s {
-            Vertex => Vert,
-            Geometry => Geom,
-            Fragment => Frag,
-            _ => panic!("Not Implemented :: {:?} -> ShaderStage", s),
+            Vertex => Ok(Vert),
+            Geometry => Ok(Geom),
+            Fragment => Ok(Frag),
+            GlCompute => Ok(Comp),
+            TessellationControl => Ok(Tesc),
+            TessellationEvaluation => Ok(Tese),
+            _ => Err(Error::Unsupported(format!("Execution model {:?}", s))),
         }
     }
 }
@@ -85,6 +112,11 @@ impl Into<ShaderKind> for ShaderStage {
             ShaderStage::Vert => ShaderKind::Vertex,
             ShaderStage::Geom => ShaderKind::Geometry,
             ShaderStage::Frag => ShaderKind::Fragment,
+            ShaderStage::Comp => ShaderKind::Compute,
+            ShaderStage::Tesc => ShaderKind::TessControl,
+            ShaderStage::Tese => ShaderKind::TessEvaluation,
+            ShaderStage::Task => ShaderKind::Task,
+            ShaderStage::Mesh => ShaderKind::Mesh,
         }
     }
 }
@@ -95,17 +127,28 @@ impl Into<ShaderStageFlags> for ShaderStage {
             ShaderStage::Vert => ShaderStageFlags::VERTEX,
             ShaderStage::Geom => ShaderStageFlags::GEOMETRY,
             ShaderStage::Frag => ShaderStageFlags::FRAGMENT,
+            ShaderStage::Comp => ShaderStageFlags::COMPUTE,
+            ShaderStage::Tesc => ShaderStageFlags::TESSELLATION_CONTROL,
+            ShaderStage::Tese => ShaderStageFlags::TESSELLATION_EVALUATION,
+            ShaderStage::Task => ShaderStageFlags::TASK_NV,
+            ShaderStage::Mesh => ShaderStageFlags::MESH_NV,
         }
     }
 }
 
-impl From<&str> for ShaderStage {
-    fn from(s: &str) -> Self {
+impl FromStr for ShaderStage {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
         match &s.to_lowercase()[..] {
-            "vert" => ShaderStage::Vert,
-            "frag" => ShaderStage::Frag,
-            "geom" => ShaderStage::Geom,
-            _ => panic!("Not Recognized: {}", s),
+            "vert" => Ok(ShaderStage::Vert),
+            "frag" => Ok(ShaderStage::Frag),
+            "geom" => Ok(ShaderStage::Geom),
+            "comp" => Ok(ShaderStage::Comp),
+            "tesc" => Ok(ShaderStage::Tesc),
+            "tese" => Ok(ShaderStage::Tese),
+            "task" => Ok(ShaderStage::Task),
+            "mesh" => Ok(ShaderStage::Mesh),
+            _ => Err(Error::Unsupported(format!("Shader stage {:?}", s))),
         }
     }
 }
@@ -114,6 +157,8 @@ pub struct MetaShader {
     pub stage: ShaderStage,
     pub entry: String,
     pub bin: Vec<u32>,
+    /// The GLSL file this was compiled from, for reloading.
+    pub source: Option<PathBuf>,
 }
 
 impl MetaShader {
@@ -122,68 +167,80 @@ impl MetaShader {
         path: &str,
         entry: &str,
         opt: Option<&CompileOptions>,
-    ) -> Self {
-        let stage: ShaderStage = path.split_at(path.rfind('.').unwrap() + 1).1.into();
+    ) -> Result<Self> {
+        // Stage comes from the extension, as in `shader.vert`
+        let stage: ShaderStage = path.rsplit('.').next().unwrap_or("").parse()?;
         let bin = {
             let name = path.split_at(path.rfind('/').unwrap_or(0)).1;
-            let src = std::fs::read_to_string(path).unwrap();
-            match compiler.compile_into_spirv(&src, stage.into(), name, entry, opt) {
-                Ok(b) => b,
-                Err(e) => panic!("{}", e),
-            }
-            .as_binary()
-            .to_vec()
+            let src = std::fs::read_to_string(path)?;
+            compiler
+                .compile_into_spirv(&src, stage.into(), name, entry, opt)
+                .map_err(|e| Error::ShaderCompile {
+                    name: path.to_string(),
+                    diagnostics: e.to_string(),
+                })?
+                .as_binary()
+                .to_vec()
         };
-        Self {
+        Ok(Self {
             stage,
             entry: entry.to_string(),
             bin,
-        }
+            source: Some(PathBuf::from(path)),
+        })
     }
 
     pub fn new_chain(
         compiler: &mut Compiler,
         info: Vec<(&str, &str)>,
         opt: Option<&CompileOptions>,
-    ) -> Vec<Self> {
+    ) -> Result<Vec<Self>> {
         info.iter()
             .map(|(path, entry)| MetaShader::new(compiler, path, entry, opt))
             .collect()
     }
 
-    fn build(self, dev: Device) -> ShaderArtifact {
-        ShaderArtifact {
+    fn build(self, dev: Device) -> Result<ShaderArtifact> {
+        let entry = CString::new(self.entry)
+            .map_err(|_| Error::Unsupported("Entry point name containing NUL".to_string()))?;
+        // Only vertex inputs come from outside the pipeline
+        let inputs = match self.stage {
+            ShaderStage::Vert => reflect_inputs(&self.bin)?,
+            _ => Vec::new(),
+        };
+        Ok(ShaderArtifact {
             module: unsafe {
                 dev.create_shader_module(&ShaderModuleCreateInfo::builder().code(&self.bin), None)
-            }
-            .unwrap(),
+            }?,
             dev,
             stage: self.stage,
-            entry: CString::new(self.entry).unwrap(),
-        }
+            entry,
+            inputs,
+            source: self.source,
+        })
     }
 
     pub fn build_chain(
         dev: Device,
         meta: Vec<MetaShader>,
         min_offset: u64,
-    ) -> (
+    ) -> Result<(
         HashMap<ShaderStage, ShaderArtifact>,
         DescPoolToken,
         HashMap<String, PushConstant>,
-    ) {
+    )> {
         let (pool, push_consts) = {
             let mut builder = DescPoolToken::builder(min_offset);
             for m in meta.iter() {
-                builder.add(&m.bin);
+                builder.add(&m.bin)?;
             }
-            builder.build(dev.clone())
+            builder.build(dev.clone())?
         };
-        (
-            HashMap::from_iter(meta.into_iter().map(|m| (m.stage, m.build(dev.clone())))),
-            pool,
-            push_consts,
-        )
+        let mut shaders = HashMap::new();
+        for m in meta {
+            shaders.insert(m.stage, m.build(dev.clone())?);
+        }
+        Ok((shaders, pool, push_consts))
     }
 }
 
@@ -192,6 +249,11 @@ pub struct ShaderArtifact {
     pub stage: ShaderStage,
     pub entry: CString,
     pub module: ShaderModule,
+    /// Reflected from vertex shaders, for checking against the pipeline's vertex layout; empty
+    /// for other stages.
+    pub inputs: Vec<StageInput>,
+    /// Copied from `MetaShader::source`.
+    pub source: Option<PathBuf>,
 }
 
 impl Drop for ShaderArtifact {
@@ -201,6 +263,12 @@ impl Drop for ShaderArtifact {
     }
 }
 
+impl DebugName for ShaderArtifact {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.module, name);
+    }
+}
+
 impl ShaderArtifact {
     pub fn create_info(&self) -> PipelineShaderStageCreateInfo {
         PipelineShaderStageCreateInfo::builder()
@@ -0,0 +1,189 @@
+use crate::Result;
+use ash::{version::DeviceV1_0, vk, Device};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+/// Marks a file as a Flint pipeline cache.
+const MAGIC: &[u8; 4] = b"FLPC";
+/// Magic, driver version, and the length of the Vulkan data that follows.
+const PREFIX_LEN: usize = 16;
+/// Header length, header version, vendor ID, device ID and cache UUID.
+const VK_HEADER_LEN: usize = 32;
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+/// The Vulkan cache data in `file`, if it was saved by this device and driver. Vulkan would
+/// ignore mismatched data itself, but some drivers don't check as carefully as they should.
+fn validate<'a>(
+    file: &'a [u8],
+    props: &vk::PhysicalDeviceProperties,
+) -> std::result::Result<&'a [u8], &'static str> {
+    if file.len() < PREFIX_LEN || &file[0..4] != MAGIC {
+        return Err("not a pipeline cache");
+    }
+    if read_u32(file, 4) != props.driver_version {
+        return Err("saved by another driver version");
+    }
+    let len = u64::from_le_bytes(file[8..16].try_into().unwrap()) as usize;
+    let data = &file[PREFIX_LEN..];
+    if data.len() != len || len < VK_HEADER_LEN {
+        return Err("truncated");
+    }
+    // Header fields are in host byte order, which is little endian everywhere Flint runs
+    if (read_u32(data, 0) as usize) < VK_HEADER_LEN
+        || read_u32(data, 4) != vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+    {
+        return Err("unknown header version");
+    }
+    if read_u32(data, 8) != props.vendor_id
+        || read_u32(data, 12) != props.device_id
+        || data[16..32] != props.pipeline_cache_uuid[..]
+    {
+        return Err("saved by another device");
+    }
+    Ok(data)
+}
+
+/// What `validate` expects: the prefix, then `data` as Vulkan gave it.
+fn file_contents(data: &[u8], props: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+    let mut file = Vec::with_capacity(PREFIX_LEN + data.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&props.driver_version.to_le_bytes());
+    file.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    file.extend_from_slice(data);
+    file
+}
+
+/// Creates a pipeline cache, seeded from `path` when it holds a cache saved for this device and
+/// driver by `save_pipeline_cache`. Missing or stale files just give an empty cache.
+pub fn load_pipeline_cache(
+    dev: &Device,
+    props: &vk::PhysicalDeviceProperties,
+    path: Option<&Path>,
+) -> Result<vk::PipelineCache> {
+    let file = match path.map(fs::read) {
+        Some(Ok(file)) => file,
+        Some(Err(e)) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Couldn't read pipeline cache: {}", e);
+            }
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    let data = if file.is_empty() {
+        &[][..]
+    } else {
+        validate(&file, props).unwrap_or_else(|e| {
+            log::warn!("Discarding pipeline cache: {}", e);
+            &[]
+        })
+    };
+    let info = vk::PipelineCacheCreateInfo::builder().initial_data(data);
+    Ok(unsafe { dev.create_pipeline_cache(&info, None)? })
+}
+
+/// Writes `cache` to `path` for `load_pipeline_cache`. The file is replaced only once the new
+/// one is complete.
+pub fn save_pipeline_cache(
+    dev: &Device,
+    props: &vk::PhysicalDeviceProperties,
+    cache: vk::PipelineCache,
+    path: &Path,
+) -> Result<()> {
+    let data = unsafe { dev.get_pipeline_cache_data(cache)? };
+    let file = file_contents(&data, props);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, &file)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn props() -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties {
+            driver_version: 7,
+            vendor_id: 0x10de,
+            device_id: 0x1b80,
+            pipeline_cache_uuid: [3; 16],
+            ..Default::default()
+        }
+    }
+
+    /// A Vulkan cache header for `props`, followed by some cache data.
+    fn vk_data(props: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(VK_HEADER_LEN as u32).to_le_bytes());
+        data.extend_from_slice(
+            &(vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_le_bytes(),
+        );
+        data.extend_from_slice(&props.vendor_id.to_le_bytes());
+        data.extend_from_slice(&props.device_id.to_le_bytes());
+        data.extend_from_slice(&props.pipeline_cache_uuid);
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = vk_data(&props());
+        let file = file_contents(&data, &props());
+        assert_eq!(validate(&file, &props()), Ok(&data[..]));
+    }
+
+    #[test]
+    fn wrong_magic() {
+        let mut file = file_contents(&vk_data(&props()), &props());
+        file[0] = b'X';
+        assert_eq!(validate(&file, &props()), Err("not a pipeline cache"));
+        assert_eq!(validate(&file[..8], &props()), Err("not a pipeline cache"));
+    }
+
+    #[test]
+    fn driver_mismatch() {
+        let file = file_contents(&vk_data(&props()), &props());
+        let updated = vk::PhysicalDeviceProperties {
+            driver_version: 8,
+            ..props()
+        };
+        assert_eq!(
+            validate(&file, &updated),
+            Err("saved by another driver version")
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let file = file_contents(&vk_data(&props()), &props());
+        assert_eq!(
+            validate(&file[..file.len() - 1], &props()),
+            Err("truncated")
+        );
+        let file = file_contents(&vk_data(&props())[..VK_HEADER_LEN - 1], &props());
+        assert_eq!(validate(&file, &props()), Err("truncated"));
+    }
+
+    #[test]
+    fn device_mismatch() {
+        let file = file_contents(&vk_data(&props()), &props());
+        let other_device = vk::PhysicalDeviceProperties {
+            device_id: 0x1b81,
+            ..props()
+        };
+        let other_uuid = vk::PhysicalDeviceProperties {
+            pipeline_cache_uuid: [4; 16],
+            ..props()
+        };
+        assert_eq!(
+            validate(&file, &other_device),
+            Err("saved by another device")
+        );
+        assert_eq!(validate(&file, &other_uuid), Err("saved by another device"));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::debug::{DebugName, DebugNamer};
+use crate::shader::*;
+use crate::{Error, Result};
+use ash::{version::DeviceV1_0, vk, Device};
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+pub struct ComputePipeToken {
+    dev: Device,
+    pub desc_pool: DescPoolToken,
+    pub pipe: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    /// The shader's `local_size`, for `dispatch_for`.
+    pub local_size: [u32; 3],
+    #[allow(dead_code)] // this exists just to keep the artifact from being dropped
+    shader: ShaderArtifact,
+}
+
+impl Debug for ComputePipeToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ComputePipeToken")
+            .field("desc_pool", &self.desc_pool)
+            .field("pipe", &self.pipe)
+            .field("layout", &self.layout)
+            .field("local_size", &self.local_size)
+            .finish()
+    }
+}
+
+impl Drop for ComputePipeToken {
+    fn drop(&mut self) {
+        eprintln!("Dropping ComputePipeToken");
+        unsafe {
+            self.dev.destroy_pipeline(self.pipe, None);
+            self.dev.destroy_pipeline_layout(self.layout, None);
+        }
+    }
+}
+
+impl DebugName for ComputePipeToken {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.pipe, name);
+        namer.name(self.layout, &format!("{} layout", name));
+        self.desc_pool.set_name(namer, name);
+        self.shader
+            .set_name(namer, &format!("{} {:?}", name, self.shader.stage));
+    }
+}
+
+impl ComputePipeToken {
+    /// Compiles `meta`, which has to be a compute shader, with descriptor sets and push
+    /// constants reflected from it.
+    pub fn from_meta(
+        dev: Device,
+        meta: MetaShader,
+        min_offset: u64,
+        cache: vk::PipelineCache,
+    ) -> Result<(Self, HashMap<String, PushConstant>)> {
+        if meta.stage != ShaderStage::Comp {
+            return Err(Error::Unsupported(format!(
+                "{:?} shader in a compute pipeline",
+                meta.stage
+            )));
+        }
+        let local_size = reflect_local_size(&meta.bin)?;
+        let (mut shaders, pool, push_consts) =
+            MetaShader::build_chain(dev.clone(), vec![meta], min_offset)?;
+        let shader = shaders.remove(&ShaderStage::Comp).unwrap();
+        let mut res = Self::build(dev, pool, push_consts.values().collect(), shader, cache)?;
+        res.local_size = local_size;
+        Ok((res, push_consts))
+    }
+
+    pub fn build(
+        dev: Device,
+        pool: DescPoolToken,
+        push_consts: Vec<&PushConstant>,
+        shader: ShaderArtifact,
+        cache: vk::PipelineCache,
+    ) -> Result<Self> {
+        let layout = {
+            let layouts = pool.sets.iter().map(|desc| desc.layout).collect::<Vec<_>>();
+            let p_consts = push_consts
+                .iter()
+                .map(|push| push.range)
+                .collect::<Vec<_>>();
+            let info = vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&layouts)
+                .push_constant_ranges(&p_consts);
+            unsafe { dev.create_pipeline_layout(&info, None) }?
+        };
+        let mut res = ComputePipeToken {
+            dev,
+            desc_pool: pool,
+            pipe: vk::Pipeline::null(),
+            layout,
+            local_size: [1, 1, 1],
+            shader,
+        };
+        let info = vk::ComputePipelineCreateInfo::builder()
+            .stage(res.shader.create_info())
+            .layout(layout)
+            .build();
+        res.pipe = unsafe {
+            res.dev
+                .create_compute_pipelines(cache, &[info], None)
+                .map(|mut pipes| pipes.remove(0))
+                .map_err(|(_, e)| Error::from(e))?
+        };
+        Ok(res)
+    }
+
+    pub fn bind_sets(&self, buf: vk::CommandBuffer) {
+        // Shaders using only push constants, or nothing, have no sets, and binding none is invalid
+        if self.desc_pool.sets.is_empty() {
+            return;
+        }
+        unsafe {
+            self.dev.cmd_bind_descriptor_sets(
+                buf,
+                vk::PipelineBindPoint::COMPUTE,
+                self.layout,
+                0,
+                &self
+                    .desc_pool
+                    .sets
+                    .iter()
+                    .map(|s| s.set)
+                    .collect::<Vec<_>>(),
+                &[],
+            );
+        }
+    }
+
+    pub fn bind(&self, buf: vk::CommandBuffer) {
+        unsafe {
+            self.dev
+                .cmd_bind_pipeline(buf, vk::PipelineBindPoint::COMPUTE, self.pipe)
+        };
+        self.bind_sets(buf);
+    }
+
+    /// Runs `groups` workgroups; the pipeline must be bound.
+    pub fn dispatch(&self, buf: vk::CommandBuffer, groups: [u32; 3]) {
+        unsafe { self.dev.cmd_dispatch(buf, groups[0], groups[1], groups[2]) };
+    }
+
+    /// Runs enough workgroups to cover `invocations` in each dimension, rounding up; the shader
+    /// has to ignore the extra invocations.
+    pub fn dispatch_for(&self, buf: vk::CommandBuffer, invocations: [u32; 3]) {
+        let groups = |i: usize| {
+            let l = self.local_size[i];
+            invocations[i] / l + (invocations[i] % l != 0) as u32
+        };
+        self.dispatch(buf, [groups(0), groups(1), groups(2)]);
+    }
+
+    /// Reads the group counts from a `VkDispatchIndirectCommand` at `offset` in `args`, which
+    /// needs INDIRECT_BUFFER usage.
+    pub fn dispatch_indirect(
+        &self,
+        buf: vk::CommandBuffer,
+        args: vk::Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        unsafe { self.dev.cmd_dispatch_indirect(buf, args, offset) };
+    }
+}
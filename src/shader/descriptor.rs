@@ -1,5 +1,7 @@
 use crate::buffer::*;
+use crate::debug::{DebugName, DebugNamer};
 use crate::shader::*;
+use crate::{Error, Result};
 use ash::{
     version::DeviceV1_0,
     vk,
@@ -47,7 +49,7 @@ impl DescField {
         block_type: &spirv::Type,
         res: &spirv::Resource,
         min_offset: u64,
-    ) -> HashMap<String, Self>
+    ) -> Result<HashMap<String, Self>>
     where
         T: spirv::Target,
         spirv::Ast<T>: spirv::Compile<T> + spirv::Parse<T>,
@@ -58,12 +60,12 @@ impl DescField {
             //dbg!(ast.get_declared_struct_size(res.base_type_id).unwrap());
             for (i, type_id) in member_types.iter().enumerate() {
                 fields.insert(
-                    ast.get_member_name(res.base_type_id, i as _).unwrap(),
-                    Self::new(ast, res, *type_id, i, min_offset),
+                    ast.get_member_name(res.base_type_id, i as _)?,
+                    Self::new(ast, res, *type_id, i, min_offset)?,
                 );
             }
         }
-        fields
+        Ok(fields)
     }
 
     fn new<T>(
@@ -72,21 +74,20 @@ impl DescField {
         type_id: u32,
         index: usize,
         min_offset: u64,
-    ) -> Self
+    ) -> Result<Self>
     where
         T: spirv::Target,
         spirv::Ast<T>: spirv::Compile<T> + spirv::Parse<T>,
     {
         //dbg!(ast.get_member_decoration(block_id, index as _, spirv::Decoration::));
-        let (ty, count) = cross_to_ash(&ast.get_type(type_id).unwrap());
+        let (ty, count) = cross_to_ash(&ast.get_type(type_id)?)?;
         //dbg!(ast.get_declared_struct_member_size(res.type_id, index as _));
-        let mut offset = u64::from(
-            ast.get_member_decoration(res.base_type_id, index as _, spirv::Decoration::Offset)
-                .unwrap(),
-        );
-        let size = ast
-            .get_declared_struct_member_size(res.type_id, index as _)
-            .unwrap();
+        let mut offset = u64::from(ast.get_member_decoration(
+            res.base_type_id,
+            index as _,
+            spirv::Decoration::Offset,
+        )?);
+        let size = ast.get_declared_struct_member_size(res.type_id, index as _)?;
         // Offset must be multiple of min_offset
         let modulo = offset % min_offset;
         if modulo != 0 {
@@ -94,13 +95,13 @@ impl DescField {
         }
         //dbg!(ast.get_member_decoration(res.base_type_id, index as _, spirv::Decoration::MatrixStride));
         //dbg!(offset);
-        DescField {
+        Ok(DescField {
             index,
             offset,
             size: size.into(),
             ty,
             count,
-        }
+        })
     }
 }
 
@@ -121,10 +122,13 @@ impl Descriptor {
         &self,
         dev: Device,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
-    ) -> Buffer {
+    ) -> Result<Buffer> {
         use Buffer::*;
         if self.fields.is_empty() {
-            panic!("Not Implemented: Buffer from non-struct descriptor");
+            Err(Error::Unsupported(format!(
+                "Buffer from non-struct descriptor {}",
+                self.name
+            )))
         // Raw(BufToken::with_size(
         //     dev,
         //     vk::BufferUsageFlags::UNIFORM_BUFFER,
@@ -133,7 +137,7 @@ impl Descriptor {
         //     self.size,
         // ))
         } else {
-            Struct(BufStruct::from_fields(
+            Ok(Struct(BufStruct::from_fields(
                 dev,
                 vk::BufferUsageFlags::UNIFORM_BUFFER,
                 vk::SharingMode::EXCLUSIVE,
@@ -141,7 +145,7 @@ impl Descriptor {
                 self.fields
                     .iter()
                     .map(|(id, field)| (id.clone(), field.offset, field.size)),
-            ))
+            )?))
         }
     }
 
@@ -150,29 +154,25 @@ impl Descriptor {
         stage: ShaderStageFlags,
         res: &spirv::Resource,
         min_offset: vk::DeviceSize,
-    ) -> Self
+    ) -> Result<Self>
     where
         T: spirv::Target,
         spirv::Ast<T>: spirv::Compile<T> + spirv::Parse<T>,
     {
         // TODO :: Repack UBOs to optimize memory (Because min_offset might not be a factor of the sum of sizes of UBO fields)
-        let ty = ast.get_type(res.type_id).unwrap();
-        let (desc_type, count) = cross_to_ash(&ty);
-        let set = ast
-            .get_decoration(res.id, spirv::Decoration::DescriptorSet)
-            .unwrap();
-        Descriptor {
+        let ty = ast.get_type(res.type_id)?;
+        let (desc_type, count) = cross_to_ash(&ty)?;
+        let set = ast.get_decoration(res.id, spirv::Decoration::DescriptorSet)?;
+        Ok(Descriptor {
             //size: size.into(),
             set,
             name: res.name.clone(),
-            binding: ast
-                .get_decoration(res.id, spirv::Decoration::Binding)
-                .unwrap(),
+            binding: ast.get_decoration(res.id, spirv::Decoration::Binding)?,
             ty: desc_type,
             count,
             stage,
-            fields: DescField::from_desc_res(&ast, &ty, &res, min_offset),
-        }
+            fields: DescField::from_desc_res(&ast, &ty, &res, min_offset)?,
+        })
     }
 
     pub fn size(&self) -> vk::DescriptorPoolSize {
@@ -200,7 +200,7 @@ impl Descriptor {
     }
 }
 
-pub fn cross_to_ash(c: &spirv::Type) -> (DescriptorType, usize) {
+pub fn cross_to_ash(c: &spirv::Type) -> Result<(DescriptorType, usize)> {
     use spirv::Type::*;
     //dbg!(c);
     let mut res = match c {
@@ -223,11 +223,11 @@ pub fn cross_to_ash(c: &spirv::Type) -> (DescriptorType, usize) {
         | UByte { array }
         | Short { array }
         | UShort { array } => (DescriptorType::UNIFORM_BUFFER, array.len()),
-        _ => unimplemented!(),
+        _ => return Err(Error::Unsupported(format!("Descriptor of type {:?}", c))),
     };
     //dbg!(&res);
     res.1 = std::cmp::max(res.1, 1);
-    res
+    Ok(res)
 }
 
 #[derive(Debug)]
@@ -275,14 +275,14 @@ impl PushConstant {
         stage: ShaderStageFlags,
         res: &spirv::Resource,
         min_offset: vk::DeviceSize,
-    ) -> Self
+    ) -> Result<Self>
     where
         T: spirv::Target,
         spirv::Ast<T>: spirv::Compile<T> + spirv::Parse<T>,
     {
-        let ty = ast.get_type(res.type_id).unwrap();
-        let fields = DescField::from_desc_res(&ast, &ty, &res, min_offset);
-        Self {
+        let ty = ast.get_type(res.type_id)?;
+        let fields = DescField::from_desc_res(&ast, &ty, &res, min_offset)?;
+        Ok(Self {
             range: vk::PushConstantRange {
                 stage_flags: stage,
                 size: fields
@@ -291,7 +291,7 @@ impl PushConstant {
                 offset: 0, // TODO :: Demagic this
             },
             fields,
-        }
+        })
     }
 }
 
@@ -302,15 +302,12 @@ pub struct DescPoolBuilder {
 }
 
 impl DescPoolBuilder {
-    pub fn add(&mut self, bin: &[u32]) -> &mut Self {
+    pub fn add(&mut self, bin: &[u32]) -> Result<&mut Self> {
         let module = spirv::Module::from_words(bin);
-        let ast = spirv::Ast::<glsl::Target>::parse(&module).unwrap();
+        let ast = spirv::Ast::<glsl::Target>::parse(&module)?;
 
-        let stage: ShaderStageFlags = {
-            let native: ShaderStage = ast.get_entry_points().unwrap()[0].execution_model.into();
-            native.into()
-        };
-        let resources = ast.get_shader_resources().unwrap();
+        let stage: ShaderStageFlags = reflect_stage(bin)?.into();
+        let resources = ast.get_shader_resources()?;
         //dbg!(module.enumerate_descriptor_sets(None).unwrap());
         //dbg!(&resources);
         //panic!("Pause");
@@ -324,25 +321,26 @@ impl DescPoolBuilder {
             .chain(resources.storage_buffers.iter())
             .chain(resources.storage_images.iter())
         {
-            let desc = Descriptor::new(&ast, stage, res, self.min_offset);
-            self.data
-                .entry(desc.set)
-                .or_insert_with(Vec::new)
-                .push(desc);
+            let desc = Descriptor::new(&ast, stage, res, self.min_offset)?;
+            let descs = self.data.entry(desc.set).or_insert_with(Vec::new);
+            // A descriptor used by several stages is one binding visible to all of them
+            match descs.iter_mut().find(|d| d.name == desc.name) {
+                Some(existing) => existing.stage |= desc.stage,
+                None => descs.push(desc),
+            }
         }
 
         for push in resources.push_constant_buffers {
-            let p = PushConstant::new(&ast, stage, &push, self.min_offset);
-            dbg!(&p);
+            let p = PushConstant::new(&ast, stage, &push, self.min_offset)?;
             self.push_consts.insert(push.name, p);
         }
 
         //dbg!(&self.data);
 
-        self
+        Ok(self)
     }
 
-    pub fn build(self, dev: Device) -> (DescPoolToken, HashMap<String, PushConstant>) {
+    pub fn build(self, dev: Device) -> Result<(DescPoolToken, HashMap<String, PushConstant>)> {
         let pool = unsafe {
             let sizes = self
                 .data
@@ -353,59 +351,52 @@ impl DescPoolBuilder {
             let info = vk::DescriptorPoolCreateInfo::builder()
                 .pool_sizes(&sizes)
                 .max_sets(self.data.len() as u32);
-            dev.create_descriptor_pool(&info, None).unwrap()
+            dev.create_descriptor_pool(&info, None)?
+        };
+        // Owns the pool and each layout as soon as it exists, so errors below don't leak them
+        let mut res = DescPoolToken {
+            dev: dev.clone(),
+            pool,
+            sets: Vec::new(),
         };
 
-        // don't feel like explaining this; good luck
-        let sets = unsafe {
-            let layouts = self
-                .data
-                .into_iter()
-                .map(|(_set_id, descs)| {
-                    (
-                        dev.create_descriptor_set_layout(
-                            &vk::DescriptorSetLayoutCreateInfo::builder()
-                                .bindings(
-                                    &descs
-                                        .iter()
-                                        .cloned()
-                                        .map(std::convert::Into::into)
-                                        .collect::<Vec<DescriptorSetLayoutBinding>>(),
-                                )
-                                .build(),
-                            None,
+        for (_set_id, descs) in self.data {
+            let layout = unsafe {
+                dev.create_descriptor_set_layout(
+                    &vk::DescriptorSetLayoutCreateInfo::builder()
+                        .bindings(
+                            &descs
+                                .iter()
+                                .cloned()
+                                .map(std::convert::Into::into)
+                                .collect::<Vec<DescriptorSetLayoutBinding>>(),
                         )
-                        .unwrap(),
-                        HashMap::from_iter(
-                            descs.into_iter().map(|desc| (desc.name.to_string(), desc)),
-                        ),
-                    )
-                })
-                .collect::<Vec<_>>();
+                        .build(),
+                    None,
+                )?
+            };
+            res.sets.push(SetToken {
+                dev: dev.clone(),
+                set: DescriptorSet::null(),
+                layout,
+                descriptors: HashMap::from_iter(
+                    descs.into_iter().map(|desc| (desc.name.to_string(), desc)),
+                ),
+            });
+        }
+
+        let sets = unsafe {
             dev.allocate_descriptor_sets(
                 &DescriptorSetAllocateInfo::builder()
                     .descriptor_pool(pool)
-                    .set_layouts(
-                        &layouts
-                            .iter()
-                            .cloned()
-                            .map(|(layout, _d)| layout)
-                            .collect::<Vec<_>>()[..],
-                    )
+                    .set_layouts(&res.sets.iter().map(|s| s.layout).collect::<Vec<_>>()[..])
                     .build(),
-            )
-            .unwrap()
-            .into_iter()
-            .zip(layouts.into_iter())
-            .map(|(set, (layout, descriptors))| SetToken {
-                dev: dev.clone(),
-                set,
-                layout,
-                descriptors,
-            })
-            .collect::<Vec<_>>()
+            )?
         };
-        (DescPoolToken { dev, pool, sets }, self.push_consts)
+        for (token, set) in res.sets.iter_mut().zip(sets.into_iter()) {
+            token.set = set;
+        }
+        Ok((res, self.push_consts))
     }
 }
 
@@ -435,7 +426,21 @@ impl Drop for SetToken {
     }
 }
 
+impl DebugName for SetToken {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.set, name);
+        namer.name(self.layout, &format!("{} layout", name));
+    }
+}
+
 impl SetToken {
+    /// Names of the reflected descriptors in this set, in binding order.
+    pub fn descriptor_names(&self) -> Vec<&str> {
+        let mut descs = self.descriptors.values().collect::<Vec<_>>();
+        descs.sort_by_key(|d| d.binding);
+        descs.iter().map(|d| &d.name[..]).collect()
+    }
+
     pub fn make_writes(&self, info: &mut [(&str, DescWriteInfo)]) -> Vec<vk::WriteDescriptorSet> {
         info.iter_mut()
             .filter_map(|(name, info)| {
@@ -474,6 +479,19 @@ impl Drop for DescPoolToken {
     }
 }
 
+impl DebugName for DescPoolToken {
+    /// Sets are named after `name` plus the descriptors they hold.
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.pool, name);
+        for set in &self.sets {
+            set.set_name(
+                namer,
+                &format!("{} ({})", name, set.descriptor_names().join(", ")),
+            );
+        }
+    }
+}
+
 impl DescPoolToken {
     pub fn builder(min_offset: u64) -> DescPoolBuilder {
         DescPoolBuilder {
@@ -486,19 +504,13 @@ impl DescPoolToken {
     pub fn make_buffers(
         &self,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
-    ) -> HashMap<String, Buffer> {
-        HashMap::from_iter(
-            self.sets
-                .iter()
-                .flat_map(|s| s.descriptors.iter())
-                .filter_map(|(id, desc)| {
-                    if desc.fields.is_empty() {
-                        None
-                    } else {
-                        Some((id.clone(), desc.make_buffer(self.dev.clone(), mem_prop)))
-                    }
-                }),
-        )
+    ) -> Result<HashMap<String, Buffer>> {
+        self.sets
+            .iter()
+            .flat_map(|s| s.descriptors.iter())
+            .filter(|(_id, desc)| !desc.fields.is_empty())
+            .map(|(id, desc)| Ok((id.clone(), desc.make_buffer(self.dev.clone(), mem_prop)?)))
+            .collect()
     }
 
     pub fn update_desc_sets(&self, mut info: Vec<(&str, DescWriteInfo)>) {
@@ -1,4 +1,6 @@
+use crate::debug::{DebugName, DebugNamer};
 use crate::shader::*;
+use crate::{Error, Result};
 
 use crate::vertex::*;
 use ash::vk::RenderPass;
@@ -11,9 +13,178 @@ use ash::{
     },
     Device,
 };
+use shaderc::{CompileOptions, Compiler};
 use std::collections::HashMap;
 use std::fmt::Debug;
 
+/// Constant and slope-scaled depth offsets, e.g. against shadow acne.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DepthBias {
+    pub constant: f32,
+    pub clamp: f32,
+    pub slope: f32,
+}
+
+/// The fixed-function state of a graphics pipeline. The default is what every pipeline used
+/// to get: filled triangle lists with back faces culled, a LESS_OR_EQUAL depth test that
+/// writes, and one unblended color attachment.
+#[derive(Debug, Clone)]
+pub struct PipelineDesc {
+    pub topology: vk::PrimitiveTopology,
+    pub primitive_restart: bool,
+    /// Vertices per patch, with tessellation shaders; set both with `patches`.
+    pub patch_control_points: u32,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub polygon_mode: vk::PolygonMode,
+    /// Wider than 1.0 needs the `wide_lines` feature.
+    pub line_width: f32,
+    pub depth_bias: Option<DepthBias>,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare_op: vk::CompareOp,
+    /// Front and back face stencil ops; None leaves the stencil test off.
+    pub stencil: Option<(vk::StencilOpState, vk::StencilOpState)>,
+    /// One per color attachment of the subpass.
+    pub blend: Vec<vk::PipelineColorBlendAttachmentState>,
+    /// On top of the viewport and scissor, which are always dynamic.
+    pub dynamic_states: Vec<vk::DynamicState>,
+    pub vertex: VertexLayout,
+}
+
+impl Default for PipelineDesc {
+    fn default() -> Self {
+        Self {
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            primitive_restart: false,
+            patch_control_points: 3,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            polygon_mode: vk::PolygonMode::FILL,
+            line_width: 1.0,
+            depth_bias: None,
+            depth_test: true,
+            depth_write: true,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            stencil: None,
+            blend: vec![PipelineDesc::opaque_blend()],
+            dynamic_states: Vec::new(),
+            vertex: VertexLayout::of::<Vertex>(),
+        }
+    }
+}
+
+impl PipelineDesc {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Writes the color as is.
+    pub fn opaque_blend() -> vk::PipelineColorBlendAttachmentState {
+        vk::PipelineColorBlendAttachmentState {
+            blend_enable: 0,
+            src_color_blend_factor: vk::BlendFactor::ONE,
+            dst_color_blend_factor: vk::BlendFactor::ZERO,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+            alpha_blend_op: vk::BlendOp::ADD,
+            color_write_mask: vk::ColorComponentFlags::all(),
+        }
+    }
+
+    /// Non-premultiplied "over" blending.
+    pub fn alpha_blend() -> vk::PipelineColorBlendAttachmentState {
+        vk::PipelineColorBlendAttachmentState {
+            blend_enable: 1,
+            src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+            dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            ..Self::opaque_blend()
+        }
+    }
+
+    pub fn additive_blend() -> vk::PipelineColorBlendAttachmentState {
+        vk::PipelineColorBlendAttachmentState {
+            blend_enable: 1,
+            src_color_blend_factor: vk::BlendFactor::ONE,
+            dst_color_blend_factor: vk::BlendFactor::ONE,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE,
+            ..Self::opaque_blend()
+        }
+    }
+
+    /// Alpha blended and depth tested, without writing depth, so draw it after opaque geometry,
+    /// back to front.
+    pub fn transparent() -> Self {
+        Self {
+            depth_write: false,
+            blend: vec![Self::alpha_blend()],
+            ..Default::default()
+        }
+    }
+
+    /// Only the edges of triangles are drawn; needs the `fill_mode_non_solid` feature.
+    pub fn wireframe() -> Self {
+        Self {
+            polygon_mode: vk::PolygonMode::LINE,
+            cull_mode: vk::CullModeFlags::NONE,
+            ..Default::default()
+        }
+    }
+
+    pub fn double_sided() -> Self {
+        Self {
+            cull_mode: vk::CullModeFlags::NONE,
+            ..Default::default()
+        }
+    }
+
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Patch lists of `control_points` vertices each, as tessellation shaders need.
+    pub fn patches(mut self, control_points: u32) -> Self {
+        self.topology = vk::PrimitiveTopology::PATCH_LIST;
+        self.patch_control_points = control_points;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn depth_bias(mut self, bias: DepthBias) -> Self {
+        self.depth_bias = Some(bias);
+        self
+    }
+
+    pub fn blend(mut self, blend: Vec<vk::PipelineColorBlendAttachmentState>) -> Self {
+        self.blend = blend;
+        self
+    }
+
+    pub fn dynamic_state(mut self, state: vk::DynamicState) -> Self {
+        self.dynamic_states.push(state);
+        self
+    }
+
+    /// Reads vertex buffers laid out as `V`, rather than the built-in `Vertex`.
+    pub fn vertices<V: VertInfo>(self) -> Self {
+        self.vertex_layout(VertexLayout::of::<V>())
+    }
+
+    pub fn vertex_layout(mut self, layout: VertexLayout) -> Self {
+        self.vertex = layout;
+        self
+    }
+}
+
 pub struct PipeToken {
     dev: Device,
     pub desc_pool: DescPoolToken,
@@ -23,6 +194,110 @@ pub struct PipeToken {
     #[allow(dead_code)] // this exists just to keep artifacts from being dropped
     shaders: Vec<ShaderArtifact>,
     shader_info: Vec<vk::PipelineShaderStageCreateInfo>,
+    pub desc: PipelineDesc,
+    /// Also used by `recreate`; may be null.
+    pub cache: vk::PipelineCache,
+    /// The layout's push constant ranges, for checking reloaded shaders against.
+    push_ranges: Vec<vk::PushConstantRange>,
+}
+
+/// What a descriptor's part of a layout, and the buffers made for it, depend on: its set, name,
+/// binding, type, count and stages, and its fields' names, offsets and sizes.
+type DescSignature = (u32, String, u32, i32, usize, u32, Vec<(String, u64, u64)>);
+
+/// What a pipeline layout depends on: each descriptor's signature, and each push constant
+/// range's offset, size and stages.
+type LayoutSignature = (Vec<DescSignature>, Vec<(u32, u32, u32)>);
+
+fn layout_signature<'a>(
+    descs: impl Iterator<Item = &'a Descriptor>,
+    push_ranges: impl Iterator<Item = &'a vk::PushConstantRange>,
+) -> LayoutSignature {
+    let mut descs = descs
+        .map(|d| {
+            let mut fields = d
+                .fields
+                .iter()
+                .map(|(name, f)| (name.clone(), f.offset, f.size))
+                .collect::<Vec<_>>();
+            fields.sort();
+            (
+                d.set,
+                d.name.clone(),
+                d.binding,
+                d.ty.as_raw(),
+                d.count,
+                d.stage.as_raw(),
+                fields,
+            )
+        })
+        .collect::<Vec<_>>();
+    descs.sort();
+    let mut ranges = push_ranges
+        .map(|r| (r.offset, r.size, r.stage_flags.as_raw()))
+        .collect::<Vec<_>>();
+    ranges.sort();
+    (descs, ranges)
+}
+
+/// Fails unless the reloaded shaders' descriptors and push constants fit the existing layout and
+/// the buffers made for it.
+fn check_layout(old: LayoutSignature, new: LayoutSignature) -> Result<()> {
+    if old != new {
+        return Err(Error::Unsupported(
+            "Reloading shaders with different descriptors or push constants".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn check_stages(desc: &PipelineDesc, stages: &[ShaderStage]) -> Result<()> {
+    use ShaderStage::*;
+    let has = |stage| stages.contains(&stage);
+    let problem = if has(Comp) {
+        Some("Compute shaders go in a ComputePipeToken")
+    } else if has(Mesh) && (has(Vert) || has(Tesc) || has(Tese) || has(Geom)) {
+        Some("Mesh pipelines can't have vertex, tessellation or geometry shaders")
+    } else if !has(Mesh) && has(Task) {
+        Some("Task shaders need a mesh shader")
+    } else if !has(Mesh) && !has(Vert) {
+        Some("Graphics pipelines need a vertex or mesh shader")
+    } else if has(Tesc) != has(Tese) {
+        Some("Tessellation needs both control and evaluation shaders")
+    } else if has(Tesc) && desc.topology != vk::PrimitiveTopology::PATCH_LIST {
+        Some("Tessellation needs patch lists; see PipelineDesc::patches")
+    } else {
+        None
+    };
+    match problem {
+        Some(problem) => Err(Error::Unsupported(problem.to_string())),
+        None => Ok(()),
+    }
+}
+
+fn check_vertex_inputs(
+    desc: &PipelineDesc,
+    shaders: &HashMap<ShaderStage, ShaderArtifact>,
+) -> Result<()> {
+    if let Some(vert) = shaders.get(&ShaderStage::Vert) {
+        let mismatches = vertex_input_mismatches(&desc.vertex, &vert.inputs);
+        if !mismatches.is_empty() {
+            return Err(Error::VertexInputs(mismatches));
+        }
+    }
+    Ok(())
+}
+
+fn stage_info(
+    shaders: &HashMap<ShaderStage, ShaderArtifact>,
+) -> Vec<PipelineShaderStageCreateInfo> {
+    // Vulkan takes the stages in any order, but the map's isn't stable. Sorting by the stage
+    // rank lists them in the order they run, so the create info is the same on every build.
+    let mut sort = shaders.iter().collect::<Vec<_>>();
+    sort.sort_by(|(s, _a), (os, _oa)| s.cmp(os));
+    sort.into_iter()
+        .map(|(_s, a)| a.create_info())
+        .collect::<Vec<_>>()
 }
 
 impl Debug for PipeToken {
@@ -31,6 +306,7 @@ impl Debug for PipeToken {
             .field("desc_pool", &self.desc_pool)
             .field("pipe", &self.pipe)
             .field("layout", &self.layout)
+            .field("desc", &self.desc)
             .finish()
     }
 }
@@ -45,6 +321,17 @@ impl Drop for PipeToken {
     }
 }
 
+impl DebugName for PipeToken {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.pipe, name);
+        namer.name(self.layout, &format!("{} layout", name));
+        self.desc_pool.set_name(namer, name);
+        for shader in &self.shaders {
+            shader.set_name(namer, &format!("{} {:?}", name, shader.stage));
+        }
+    }
+}
+
 impl PipeToken {
     pub fn bind_sets(&self, buf: CommandBuffer) {
         unsafe {
@@ -72,18 +359,116 @@ impl PipeToken {
         };
     }
 
-    pub fn recreate(&mut self, renderpass: RenderPass, viewport: Viewport, scissors: Rect2D) {
+    pub fn recreate(
+        &mut self,
+        renderpass: RenderPass,
+        viewport: Viewport,
+        scissors: Rect2D,
+        samples: vk::SampleCountFlags,
+    ) -> Result<()> {
         unsafe {
             self.dev.destroy_pipeline(self.pipe, None);
         }
-        self.pipe = Self::make_pipeline(
+        self.pipe = Pipeline::null();
+        self.pipe = self.remake(renderpass, viewport, scissors, samples)?;
+        Ok(())
+    }
+
+    /// A new pipeline from the same shaders and description, for another render pass. This one
+    /// is left alone; the caller swaps the result in.
+    pub fn remake(
+        &self,
+        renderpass: RenderPass,
+        viewport: Viewport,
+        scissors: Rect2D,
+        samples: vk::SampleCountFlags,
+    ) -> Result<Pipeline> {
+        Self::make_pipeline(
             &self.dev,
             viewport,
             scissors,
             &self.shader_info,
             self.layout,
             renderpass,
+            samples,
+            &self.desc,
+            self.cache,
+        )
+    }
+
+    /// The files this pipeline's shaders were compiled from.
+    pub fn sources(&self) -> impl Iterator<Item = &std::path::Path> {
+        self.shaders.iter().filter_map(|s| s.source.as_deref())
+    }
+
+    /// Recompiles every stage from its `ShaderArtifact::source` and switches to a pipeline made
+    /// from the new code, returning the old one to be destroyed once no frame uses it. The new
+    /// shaders have to fit the existing layout, with the same descriptors, used from the same
+    /// stages with the same block layouts, and push constants; on any error, this pipeline is
+    /// left as it was.
+    pub fn reload(
+        &mut self,
+        compiler: &mut Compiler,
+        opt: Option<&CompileOptions>,
+        renderpass: RenderPass,
+        viewport: Viewport,
+        scissors: Rect2D,
+        samples: vk::SampleCountFlags,
+    ) -> Result<Pipeline> {
+        let mut sources = self
+            .shaders
+            .iter()
+            .map(|s| (s.stage, s.source.clone(), s.entry.to_string_lossy()))
+            .collect::<Vec<_>>();
+        sources.sort_by_key(|(stage, _, _)| *stage);
+        let meta = sources
+            .into_iter()
+            .map(|(stage, source, entry)| match source {
+                Some(path) => MetaShader::new(compiler, &path.to_string_lossy(), &entry, opt),
+                None => Err(Error::Unsupported(format!(
+                    "Reloading a {:?} shader that wasn't compiled from a file",
+                    stage
+                ))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut builder = DescPoolToken::builder(1);
+        for m in &meta {
+            builder.add(&m.bin)?;
+        }
+        let old = layout_signature(
+            self.desc_pool
+                .sets
+                .iter()
+                .flat_map(|s| s.descriptors.values()),
+            self.push_ranges.iter(),
+        );
+        let new = layout_signature(
+            builder.data.values().flatten(),
+            builder.push_consts.values().map(|p| &p.range),
         );
+        check_layout(old, new)?;
+
+        let mut shaders = HashMap::new();
+        for m in meta {
+            shaders.insert(m.stage, m.build(self.dev.clone())?);
+        }
+        check_vertex_inputs(&self.desc, &shaders)?;
+        let shader_info = stage_info(&shaders);
+        let pipe = Self::make_pipeline(
+            &self.dev,
+            viewport,
+            scissors,
+            &shader_info,
+            self.layout,
+            renderpass,
+            samples,
+            &self.desc,
+            self.cache,
+        )?;
+        self.shaders = shaders.into_iter().map(|(_stage, shd)| shd).collect();
+        self.shader_info = shader_info;
+        Ok(std::mem::replace(&mut self.pipe, pipe))
     }
 
     pub fn build(
@@ -93,49 +478,58 @@ impl PipeToken {
         renderpass: RenderPass,
         viewport: Viewport,
         scissors: Rect2D,
+        samples: vk::SampleCountFlags,
+        desc: PipelineDesc,
+        cache: vk::PipelineCache,
         shaders: HashMap<ShaderStage, ShaderArtifact>,
-    ) -> Self {
+    ) -> Result<Self> {
         //println!("Begin pipetoken build");
+        check_stages(&desc, &shaders.keys().copied().collect::<Vec<_>>())?;
+        check_vertex_inputs(&desc, &shaders)?;
         //println!("Building layouts...");
+        let p_consts = push_consts
+            .iter()
+            .map(|push| push.range)
+            .collect::<Vec<_>>();
         let layout = {
             let layouts = pool.sets.iter().map(|desc| desc.layout).collect::<Vec<_>>();
-            let p_consts = push_consts
-                .iter()
-                .map(|push| push.range)
-                .collect::<Vec<_>>();
             let info = PipelineLayoutCreateInfo::builder()
                 .set_layouts(&layouts)
                 .push_constant_ranges(&p_consts);
-            unsafe { dev.create_pipeline_layout(&info, None) }.unwrap()
+            unsafe { dev.create_pipeline_layout(&info, None) }?
         };
 
         //panic!("Pause");
         //dbg!(layout);
         //println!("Building shader info...");
-        let shader_info = {
-            // TODO - Find out whether sorting this is necessary
-            let mut sort = shaders.iter().collect::<Vec<_>>();
-            sort.sort_by(|(s, _a), (os, _oa)| s.cmp(os));
-            sort.into_iter()
-                .map(|(_s, a)| a.create_info())
-                .collect::<Vec<_>>()
-        };
+        let shader_info = stage_info(&shaders);
         //dbg!(&shader_info);
         //println!("Building vertex info...");
-        let pipe = Self::make_pipeline(&dev, viewport, scissors, &shader_info, layout, renderpass);
-
-        //panic!("Pause");
-
-        PipeToken {
+        let mut res = PipeToken {
             dev: dev.clone(),
             desc_pool: pool,
             //push_consts,
-            pipe,
+            pipe: Pipeline::null(),
             layout,
             shaders: shaders.into_iter().map(|(_stage, shd)| shd).collect(),
             shader_info,
-        }
+            desc,
+            cache,
+            push_ranges: p_consts,
+        };
         //panic!("Pause");
+        res.pipe = Self::make_pipeline(
+            &dev,
+            viewport,
+            scissors,
+            &res.shader_info,
+            layout,
+            renderpass,
+            samples,
+            &res.desc,
+            cache,
+        )?;
+        Ok(res)
     }
 
     fn make_pipeline(
@@ -145,25 +539,36 @@ impl PipeToken {
         shader_info: &[PipelineShaderStageCreateInfo],
         layout: PipelineLayout,
         renderpass: RenderPass,
-    ) -> Pipeline {
-        let (v_attr, v_bind) = (Vertex::attr_descs(), Vertex::bind_descs());
-        let v_input_state = Vertex::input_state_info(&v_attr, &v_bind);
+        samples: vk::SampleCountFlags,
+        desc: &PipelineDesc,
+        cache: vk::PipelineCache,
+    ) -> Result<Pipeline> {
+        let v_input_state = desc.vertex.input_state_info();
         //dbg!(unsafe{*v_input_state.p_vertex_binding_descriptions});
-        let v_asm_state = Vertex::asm_state_info();
+        let v_asm_state = vk::PipelineInputAssemblyStateCreateInfo {
+            topology: desc.topology,
+            primitive_restart_enable: desc.primitive_restart as _,
+            ..Default::default()
+        };
         //println!("Building other stuff...");
         let viewport_state = PipelineViewportStateCreateInfo::builder()
             .scissors(&[scissors])
             .viewports(&[viewport])
             .build();
+        let bias = desc.depth_bias.unwrap_or_default();
         let raster_state = vk::PipelineRasterizationStateCreateInfo {
-            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
-            line_width: 1.0,
-            polygon_mode: vk::PolygonMode::FILL,
-            cull_mode: vk::CullModeFlags::BACK,
+            front_face: desc.front_face,
+            line_width: desc.line_width,
+            polygon_mode: desc.polygon_mode,
+            cull_mode: desc.cull_mode,
+            depth_bias_enable: desc.depth_bias.is_some() as _,
+            depth_bias_constant_factor: bias.constant,
+            depth_bias_clamp: bias.clamp,
+            depth_bias_slope_factor: bias.slope,
             ..Default::default()
         };
         let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1)
+            .rasterization_samples(samples)
             .build();
         let noop_depth = vk::StencilOpState {
             fail_op: vk::StencilOp::KEEP,
@@ -172,33 +577,36 @@ impl PipeToken {
             compare_op: vk::CompareOp::ALWAYS,
             ..Default::default()
         };
+        let (front, back) = desc.stencil.unwrap_or((noop_depth, noop_depth));
         let depth_state = vk::PipelineDepthStencilStateCreateInfo {
-            depth_test_enable: 1,
-            depth_write_enable: 1,
-            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
-            front: noop_depth,
-            back: noop_depth,
+            depth_test_enable: desc.depth_test as _,
+            depth_write_enable: desc.depth_write as _,
+            depth_compare_op: desc.depth_compare_op,
+            stencil_test_enable: desc.stencil.is_some() as _,
+            front,
+            back,
             max_depth_bounds: 1.0,
             ..Default::default()
         };
-        let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState {
-            blend_enable: 0,
-            src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
-            dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
-            color_blend_op: vk::BlendOp::ADD,
-            src_alpha_blend_factor: vk::BlendFactor::ZERO,
-            dst_alpha_blend_factor: vk::BlendFactor::ZERO,
-            alpha_blend_op: vk::BlendOp::ADD,
-            color_write_mask: vk::ColorComponentFlags::all(),
-        }];
         let blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
             .logic_op(vk::LogicOp::CLEAR)
-            .attachments(&color_blend_attachment_states)
+            .attachments(&desc.blend)
             .build();
-        let dyn_state_arr = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let mut dyn_state_arr = vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        for state in &desc.dynamic_states {
+            if !dyn_state_arr.contains(state) {
+                dyn_state_arr.push(*state);
+            }
+        }
         let dyn_state = vk::PipelineDynamicStateCreateInfo::builder()
             .dynamic_states(&dyn_state_arr)
             .build();
+        let tess_state = vk::PipelineTessellationStateCreateInfo::builder()
+            .patch_control_points(desc.patch_control_points)
+            .build();
+        let tessellated = shader_info
+            .iter()
+            .any(|s| s.stage == vk::ShaderStageFlags::TESSELLATION_CONTROL);
 
         //dbg!(dyn_state);
 
@@ -214,17 +622,112 @@ impl PipeToken {
             .color_blend_state(&blend_state)
             .dynamic_state(&dyn_state)
             .layout(layout)
-            .render_pass(renderpass)
-            .build();
+            .render_pass(renderpass);
+        let pipe_info = if tessellated {
+            pipe_info.tessellation_state(&tess_state)
+        } else {
+            pipe_info
+        }
+        .build();
 
         //panic!("Pause");
 
         //dbg!(pipe_info);
         //println!("Creating pipeline...");
         unsafe {
-            dev.create_graphics_pipelines(vk::PipelineCache::null(), &[pipe_info], None)
-                .unwrap()
-                .remove(0)
+            dev.create_graphics_pipelines(cache, &[pipe_info], None)
+                .map(|mut pipes| pipes.remove(0))
+                .map_err(|(_, e)| e.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ShaderStage::*;
+
+    #[test]
+    fn allowed_stage_sets() {
+        let desc = PipelineDesc::default();
+        assert!(check_stages(&desc, &[Vert, Frag]).is_ok());
+        assert!(check_stages(&desc, &[Vert, Geom, Frag]).is_ok());
+        assert!(check_stages(&desc, &[Task, Mesh, Frag]).is_ok());
+        let patches = PipelineDesc::default().patches(3);
+        assert!(check_stages(&patches, &[Vert, Tesc, Tese, Frag]).is_ok());
+    }
+
+    #[test]
+    fn rejected_stage_sets() {
+        let desc = PipelineDesc::default();
+        let patches = PipelineDesc::default().patches(3);
+        let rejected = [
+            (&desc, &[Vert, Frag, Comp][..]),
+            (&desc, &[Vert, Task, Frag][..]),
+            (&desc, &[Task, Frag][..]),
+            (&desc, &[Mesh, Vert, Frag][..]),
+            (&desc, &[Frag][..]),
+            (&patches, &[Vert, Tesc, Frag][..]),
+            (&patches, &[Vert, Tese, Frag][..]),
+            (&desc, &[Vert, Tesc, Tese, Frag][..]),
+        ];
+        for (desc, stages) in &rejected {
+            match check_stages(desc, stages) {
+                Err(Error::Unsupported(_)) => (),
+                res => panic!("{:?} gave {:?}", stages, res.map_err(|e| e.to_string())),
+            }
         }
     }
+
+    fn ubo(stage: vk::ShaderStageFlags, fields: &[(&str, u64, u64)]) -> Descriptor {
+        Descriptor {
+            set: 0,
+            binding: 0,
+            name: "ubo".to_string(),
+            ty: vk::DescriptorType::UNIFORM_BUFFER,
+            count: 1,
+            stage,
+            fields: fields
+                .iter()
+                .enumerate()
+                .map(|(index, &(name, offset, size))| {
+                    let field = DescField {
+                        index,
+                        offset,
+                        size,
+                        ty: vk::DescriptorType::UNIFORM_BUFFER,
+                        count: 1,
+                    };
+                    (name.to_string(), field)
+                })
+                .collect(),
+        }
+    }
+
+    fn check(old: &Descriptor, new: &Descriptor) -> Result<()> {
+        let range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            offset: 0,
+            size: 64,
+        };
+        check_layout(
+            layout_signature(std::iter::once(old), std::iter::once(&range)),
+            layout_signature(std::iter::once(new), std::iter::once(&range)),
+        )
+    }
+
+    // `reload` checks the layout before touching the pipeline, so these keep the old one
+    #[test]
+    fn reload_rejects_layout_changes() {
+        let vert = vk::ShaderStageFlags::VERTEX;
+        let old = ubo(vert, &[("model", 0, 64), ("view", 64, 64)]);
+        assert!(check(&old, &ubo(vert, &[("view", 64, 64), ("model", 0, 64)])).is_ok());
+        // Grown block
+        assert!(check(&old, &ubo(vert, &[("model", 0, 64), ("view", 64, 128)])).is_err());
+        // Reordered block
+        assert!(check(&old, &ubo(vert, &[("view", 0, 64), ("model", 64, 64)])).is_err());
+        // Used from a stage the set layout doesn't include
+        let both = vert | vk::ShaderStageFlags::FRAGMENT;
+        assert!(check(&old, &ubo(both, &[("model", 0, 64), ("view", 64, 64)])).is_err());
+    }
 }
@@ -0,0 +1,516 @@
+use crate::shader::ShaderStage;
+use crate::vertex::VertexLayout;
+use crate::{Error, Result};
+use ash::vk;
+use spirv_cross::{glsl, spirv};
+use std::collections::HashMap;
+
+/// A `layout(location = N) in` variable of a shader.
+#[derive(Debug, Clone)]
+pub struct StageInput {
+    pub name: String,
+    pub location: u32,
+    /// The natural vertex format for the variable's type, e.g. R32G32B32_SFLOAT for a `vec3`.
+    pub format: vk::Format,
+    /// Bytes of `format`.
+    pub size: u32,
+    /// Locations the variable takes: 2 for 64-bit vectors of more than two components,
+    /// otherwise 1.
+    pub locations: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scalar {
+    Float,
+    SInt,
+    UInt,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SpvType {
+    Scalar(Scalar, u32),
+    /// Component type and count.
+    Vector(u32, u32),
+    /// Column type and count.
+    Matrix(u32, u32),
+}
+
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+
+/// The stage of a module's first entry point. This is read from the instructions, since
+/// spirv_cross doesn't know the task and mesh execution models.
+pub fn reflect_stage(bin: &[u32]) -> Result<ShaderStage> {
+    use ShaderStage::*;
+    // Skip the header
+    let mut i = 5;
+    while i < bin.len() {
+        let (count, op) = ((bin[i] >> 16) as usize, bin[i] & 0xffff);
+        if count == 0 || i + count > bin.len() {
+            break;
+        }
+        if op == OP_ENTRY_POINT && count > 1 {
+            return match bin[i + 1] {
+                0 => Ok(Vert),
+                1 => Ok(Tesc),
+                2 => Ok(Tese),
+                3 => Ok(Geom),
+                4 => Ok(Frag),
+                5 => Ok(Comp),
+                5267 => Ok(Task),
+                5268 => Ok(Mesh),
+                model => Err(Error::Unsupported(format!("Execution model {}", model))),
+            };
+        }
+        i += count;
+    }
+    Err(Error::Unsupported("Shader without entry point".to_string()))
+}
+
+/// The numeric types declared in a module, by result id. spirv_cross doesn't give vector sizes,
+/// so they're read from the instructions directly.
+fn numeric_types(bin: &[u32]) -> HashMap<u32, SpvType> {
+    let mut types = HashMap::new();
+    // Skip the header
+    let mut i = 5;
+    while i < bin.len() {
+        let (count, op) = ((bin[i] >> 16) as usize, bin[i] & 0xffff);
+        if count == 0 || i + count > bin.len() {
+            break;
+        }
+        let args = &bin[i + 1..i + count];
+        match (op, args) {
+            (OP_TYPE_INT, &[id, width, 1]) => {
+                types.insert(id, SpvType::Scalar(Scalar::SInt, width));
+            }
+            (OP_TYPE_INT, &[id, width, _]) => {
+                types.insert(id, SpvType::Scalar(Scalar::UInt, width));
+            }
+            (OP_TYPE_FLOAT, &[id, width, ..]) => {
+                types.insert(id, SpvType::Scalar(Scalar::Float, width));
+            }
+            (OP_TYPE_VECTOR, &[id, component, n]) => {
+                types.insert(id, SpvType::Vector(component, n));
+            }
+            (OP_TYPE_MATRIX, &[id, column, n]) => {
+                types.insert(id, SpvType::Matrix(column, n));
+            }
+            _ => (),
+        }
+        i += count;
+    }
+    types
+}
+
+fn scalar_format(scalar: Scalar, width: u32, n: u32) -> Option<(vk::Format, u32)> {
+    use vk::Format as F;
+    let formats = match (scalar, width) {
+        (Scalar::Float, 32) => [
+            F::R32_SFLOAT,
+            F::R32G32_SFLOAT,
+            F::R32G32B32_SFLOAT,
+            F::R32G32B32A32_SFLOAT,
+        ],
+        (Scalar::SInt, 32) => [
+            F::R32_SINT,
+            F::R32G32_SINT,
+            F::R32G32B32_SINT,
+            F::R32G32B32A32_SINT,
+        ],
+        (Scalar::UInt, 32) => [
+            F::R32_UINT,
+            F::R32G32_UINT,
+            F::R32G32B32_UINT,
+            F::R32G32B32A32_UINT,
+        ],
+        (Scalar::Float, 64) => [
+            F::R64_SFLOAT,
+            F::R64G64_SFLOAT,
+            F::R64G64B64_SFLOAT,
+            F::R64G64B64A64_SFLOAT,
+        ],
+        (Scalar::SInt, 64) => [
+            F::R64_SINT,
+            F::R64G64_SINT,
+            F::R64G64B64_SINT,
+            F::R64G64B64A64_SINT,
+        ],
+        (Scalar::UInt, 64) => [
+            F::R64_UINT,
+            F::R64G64_UINT,
+            F::R64G64B64_UINT,
+            F::R64G64B64A64_UINT,
+        ],
+        (Scalar::Float, 16) => [
+            F::R16_SFLOAT,
+            F::R16G16_SFLOAT,
+            F::R16G16B16_SFLOAT,
+            F::R16G16B16A16_SFLOAT,
+        ],
+        _ => return None,
+    };
+    formats.get(n as usize - 1).map(|&f| (f, width / 8 * n))
+}
+
+/// How many locations an attribute or input of `format` takes. 64-bit vectors of three or four
+/// components don't fit in one.
+pub fn format_locations(format: vk::Format) -> u32 {
+    use vk::Format as F;
+    match format {
+        F::R64G64B64_SFLOAT
+        | F::R64G64B64_SINT
+        | F::R64G64B64_UINT
+        | F::R64G64B64A64_SFLOAT
+        | F::R64G64B64A64_SINT
+        | F::R64G64B64A64_UINT => 2,
+        _ => 1,
+    }
+}
+
+/// The format and size of one column of `ty`, and how many columns it has. Each column takes
+/// `format_locations` of its format.
+fn type_format(types: &HashMap<u32, SpvType>, ty: u32) -> Option<((vk::Format, u32), u32)> {
+    match *types.get(&ty)? {
+        SpvType::Scalar(scalar, width) => Some((scalar_format(scalar, width, 1)?, 1)),
+        SpvType::Vector(component, n) => match *types.get(&component)? {
+            SpvType::Scalar(scalar, width) => Some((scalar_format(scalar, width, n)?, 1)),
+            _ => None,
+        },
+        SpvType::Matrix(column, n) => Some((type_format(types, column)?.0, n)),
+    }
+}
+
+/// The non-builtin inputs of a SPIR-V module, by location. Matrices give one input per column.
+/// Only the first location of an array input is listed. 64-bit vectors of more than two
+/// components take two locations, so whatever follows them starts two locations on.
+pub fn reflect_inputs(bin: &[u32]) -> Result<Vec<StageInput>> {
+    let module = spirv::Module::from_words(bin);
+    let ast = spirv::Ast::<glsl::Target>::parse(&module)?;
+    let types = numeric_types(bin);
+    let mut res = Vec::new();
+    for input in ast.get_shader_resources()?.stage_inputs {
+        let location = ast.get_decoration(input.id, spirv::Decoration::Location)?;
+        let ((format, size), count) = type_format(&types, input.base_type_id)
+            .ok_or_else(|| Error::Unsupported(format!("Type of shader input {}", input.name)))?;
+        let locations = format_locations(format);
+        for i in 0..count {
+            res.push(StageInput {
+                name: if count == 1 {
+                    input.name.clone()
+                } else {
+                    format!("{}[{}]", input.name, i)
+                },
+                location: location + i * locations,
+                format,
+                size,
+                locations,
+            });
+        }
+    }
+    res.sort_by_key(|i| i.location);
+    Ok(res)
+}
+
+/// The workgroup size of a compute shader's first entry point.
+pub fn reflect_local_size(bin: &[u32]) -> Result<[u32; 3]> {
+    let module = spirv::Module::from_words(bin);
+    let ast = spirv::Ast::<glsl::Target>::parse(&module)?;
+    let entry = ast
+        .get_entry_points()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Unsupported("Shader without entry point".to_string()))?;
+    let size = entry.work_group_size;
+    Ok([size.x.max(1), size.y.max(1), size.z.max(1)])
+}
+
+/// How a vertex shader sees an attribute of this format, and its bit width.
+fn numeric_class(format: vk::Format) -> Option<(Scalar, u32)> {
+    use vk::Format as F;
+    Some(match format {
+        F::R64_SFLOAT | F::R64G64_SFLOAT | F::R64G64B64_SFLOAT | F::R64G64B64A64_SFLOAT => {
+            (Scalar::Float, 64)
+        }
+        F::R64_SINT | F::R64G64_SINT | F::R64G64B64_SINT | F::R64G64B64A64_SINT => {
+            (Scalar::SInt, 64)
+        }
+        F::R64_UINT | F::R64G64_UINT | F::R64G64B64_UINT | F::R64G64B64A64_UINT => {
+            (Scalar::UInt, 64)
+        }
+        F::R8_SINT
+        | F::R8G8_SINT
+        | F::R8G8B8_SINT
+        | F::R8G8B8A8_SINT
+        | F::R16_SINT
+        | F::R16G16_SINT
+        | F::R16G16B16_SINT
+        | F::R16G16B16A16_SINT
+        | F::R32_SINT
+        | F::R32G32_SINT
+        | F::R32G32B32_SINT
+        | F::R32G32B32A32_SINT => (Scalar::SInt, 32),
+        F::R8_UINT
+        | F::R8G8_UINT
+        | F::R8G8B8_UINT
+        | F::R8G8B8A8_UINT
+        | F::R16_UINT
+        | F::R16G16_UINT
+        | F::R16G16B16_UINT
+        | F::R16G16B16A16_UINT
+        | F::R32_UINT
+        | F::R32G32_UINT
+        | F::R32G32B32_UINT
+        | F::R32G32B32A32_UINT
+        | F::A2B10G10R10_UINT_PACK32 => (Scalar::UInt, 32),
+        F::UNDEFINED => return None,
+        // Everything else, normalized, scaled or float, is read as float
+        _ => (Scalar::Float, 32),
+    })
+}
+
+/// Every way `layout` fails to feed `inputs`, or an empty list if it's fine. Attributes the
+/// shader doesn't read are allowed, as are component count differences, which Vulkan fills
+/// or drops.
+pub fn vertex_input_mismatches(layout: &VertexLayout, inputs: &[StageInput]) -> Vec<String> {
+    let mut res = Vec::new();
+    let attr_at = |location| layout.attributes.iter().find(|a| a.location == location);
+    for input in inputs {
+        let attr = match attr_at(input.location) {
+            Some(attr) => attr,
+            None => {
+                res.push(format!(
+                    "{} at location {} has no vertex attribute",
+                    input.name, input.location
+                ));
+                continue;
+            }
+        };
+        if numeric_class(attr.format) != numeric_class(input.format) {
+            res.push(format!(
+                "{} at location {} is {:?}, but the attribute is {:?}",
+                input.name, input.location, input.format, attr.format
+            ));
+        }
+        // The attribute has to fill the input's second location, or another one has to
+        if input.locations > format_locations(attr.format) && attr_at(input.location + 1).is_none()
+        {
+            res.push(format!(
+                "{} at location {} has no vertex attribute",
+                input.name,
+                input.location + 1
+            ));
+        }
+    }
+    for attr in &layout.attributes {
+        if format_locations(attr.format) > 1 && attr_at(attr.location + 1).is_some() {
+            res.push(format!(
+                "Attribute at location {} also takes location {}, which has another attribute",
+                attr.location,
+                attr.location + 1
+            ));
+        }
+    }
+    for attr in &layout.attributes {
+        if !layout.bindings.iter().any(|b| b.binding == attr.binding) {
+            res.push(format!(
+                "Attribute at location {} uses binding {}, which isn't declared",
+                attr.location, attr.binding
+            ));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module of `instructions`, each an opcode followed by its operands.
+    fn module(instructions: &[&[u32]]) -> Vec<u32> {
+        let mut words = vec![0x0723_0203, 0x0001_0000, 0, 100, 0];
+        for instruction in instructions {
+            words.push((instruction.len() as u32) << 16 | instruction[0]);
+            words.extend_from_slice(&instruction[1..]);
+        }
+        words
+    }
+
+    fn input(location: u32, format: vk::Format) -> StageInput {
+        StageInput {
+            name: format!("in{}", location),
+            location,
+            format,
+            size: 0,
+            locations: format_locations(format),
+        }
+    }
+
+    fn entry_point(model: u32) -> Vec<u32> {
+        // OpEntryPoint model %1 "main"
+        module(&[&[OP_ENTRY_POINT, model, 1, u32::from_le_bytes(*b"main"), 0]])
+    }
+
+    #[test]
+    fn stages_from_execution_models() {
+        let models = [
+            (0, ShaderStage::Vert),
+            (1, ShaderStage::Tesc),
+            (2, ShaderStage::Tese),
+            (3, ShaderStage::Geom),
+            (4, ShaderStage::Frag),
+            (5, ShaderStage::Comp),
+            (5267, ShaderStage::Task),
+            (5268, ShaderStage::Mesh),
+        ];
+        for &(model, stage) in &models {
+            assert_eq!(reflect_stage(&entry_point(model)).unwrap(), stage);
+        }
+        // Kernel
+        assert!(reflect_stage(&entry_point(6)).is_err());
+    }
+
+    #[test]
+    fn broken_instructions_end_the_search() {
+        assert!(reflect_stage(&module(&[])).is_err());
+        assert!(reflect_stage(&[]).is_err());
+        let mut zero_length = module(&[&[OP_TYPE_FLOAT, 1, 32]]);
+        zero_length[5] = 0;
+        zero_length.extend(entry_point(4)[5..].iter());
+        assert!(reflect_stage(&zero_length).is_err());
+        let mut truncated = entry_point(4);
+        truncated.pop();
+        assert!(reflect_stage(&truncated).is_err());
+    }
+
+    #[test]
+    fn matrix_columns_take_a_location_each() {
+        let types = numeric_types(&module(&[
+            &[OP_TYPE_FLOAT, 1, 32],
+            &[OP_TYPE_VECTOR, 2, 1, 4],
+            &[OP_TYPE_MATRIX, 3, 2, 4],
+            &[OP_TYPE_INT, 4, 32, 1],
+            &[OP_TYPE_VECTOR, 5, 4, 2],
+        ]));
+        let vec4 = (vk::Format::R32G32B32A32_SFLOAT, 16);
+        assert_eq!(type_format(&types, 2), Some((vec4, 1)));
+        assert_eq!(type_format(&types, 3), Some((vec4, 4)));
+        assert_eq!(
+            type_format(&types, 5),
+            Some(((vk::Format::R32G32_SINT, 8), 1))
+        );
+        assert_eq!(type_format(&types, 6), None);
+    }
+
+    #[test]
+    fn wide_doubles_take_two_locations() {
+        let types = numeric_types(&module(&[
+            &[OP_TYPE_FLOAT, 1, 64],
+            &[OP_TYPE_VECTOR, 2, 1, 3],
+            &[OP_TYPE_VECTOR, 3, 1, 2],
+        ]));
+        let ((dvec3, _), _) = type_format(&types, 2).unwrap();
+        assert_eq!(dvec3, vk::Format::R64G64B64_SFLOAT);
+        assert_eq!(format_locations(dvec3), 2);
+        let ((dvec2, _), _) = type_format(&types, 3).unwrap();
+        assert_eq!(format_locations(dvec2), 1);
+
+        let inputs = [
+            input(0, vk::Format::R64G64B64_SFLOAT),
+            input(2, vk::Format::R32_SFLOAT),
+        ];
+        let layout = VertexLayout::new()
+            .binding(0, 28, vk::VertexInputRate::VERTEX)
+            .attribute(0, 0, vk::Format::R64G64B64_SFLOAT, 0)
+            .attribute(2, 0, vk::Format::R32_SFLOAT, 24);
+        assert!(vertex_input_mismatches(&layout, &inputs).is_empty());
+
+        let clashing = VertexLayout::new()
+            .binding(0, 28, vk::VertexInputRate::VERTEX)
+            .attribute(0, 0, vk::Format::R64G64B64_SFLOAT, 0)
+            .attribute(1, 0, vk::Format::R32_SFLOAT, 24);
+        assert_eq!(
+            vertex_input_mismatches(&clashing, &inputs[..1]),
+            vec![
+                "Attribute at location 0 also takes location 1, which has another attribute"
+                    .to_string()
+            ]
+        );
+
+        let short = VertexLayout::new()
+            .binding(0, 16, vk::VertexInputRate::VERTEX)
+            .attribute(0, 0, vk::Format::R64G64_SFLOAT, 0);
+        assert_eq!(
+            vertex_input_mismatches(&short, &inputs[..1]),
+            vec!["in0 at location 1 has no vertex attribute".to_string()]
+        );
+    }
+
+    #[test]
+    fn numeric_classes() {
+        assert_eq!(
+            numeric_class(vk::Format::R8G8B8A8_UNORM),
+            numeric_class(vk::Format::R32_SFLOAT)
+        );
+        assert_eq!(
+            numeric_class(vk::Format::R16G16_SINT),
+            Some((Scalar::SInt, 32))
+        );
+        assert_eq!(
+            numeric_class(vk::Format::A2B10G10R10_UINT_PACK32),
+            Some((Scalar::UInt, 32))
+        );
+        assert_eq!(
+            numeric_class(vk::Format::R64G64_SFLOAT),
+            Some((Scalar::Float, 64))
+        );
+        assert_eq!(
+            numeric_class(vk::Format::R64_UINT),
+            Some((Scalar::UInt, 64))
+        );
+        assert_eq!(
+            numeric_class(vk::Format::R64G64B64A64_SINT),
+            Some((Scalar::SInt, 64))
+        );
+        assert_eq!(numeric_class(vk::Format::UNDEFINED), None);
+    }
+
+    #[test]
+    fn matching_layout_has_no_mismatches() {
+        let layout = VertexLayout::new()
+            .binding(0, 20, vk::VertexInputRate::VERTEX)
+            .attribute(0, 0, vk::Format::R32G32B32_SFLOAT, 0)
+            .attribute(1, 0, vk::Format::R8G8B8A8_UNORM, 12)
+            // Not read by the shader
+            .attribute(2, 0, vk::Format::R32_UINT, 16);
+        let inputs = [
+            input(0, vk::Format::R32G32B32A32_SFLOAT),
+            input(1, vk::Format::R32G32B32A32_SFLOAT),
+        ];
+        assert!(vertex_input_mismatches(&layout, &inputs).is_empty());
+    }
+
+    #[test]
+    fn mismatches_are_listed() {
+        let layout = VertexLayout::new()
+            .binding(0, 16, vk::VertexInputRate::VERTEX)
+            .attribute(0, 0, vk::Format::R32G32B32_SFLOAT, 0)
+            .attribute(1, 1, vk::Format::R32_SFLOAT, 0);
+        let inputs = [
+            input(0, vk::Format::R32G32B32_SINT),
+            input(1, vk::Format::R32_SFLOAT),
+            input(2, vk::Format::R32G32_SFLOAT),
+        ];
+        assert_eq!(
+            vertex_input_mismatches(&layout, &inputs),
+            vec![
+                "in0 at location 0 is R32G32B32_SINT, but the attribute is R32G32B32_SFLOAT"
+                    .to_string(),
+                "in2 at location 2 has no vertex attribute".to_string(),
+                "Attribute at location 1 uses binding 1, which isn't declared".to_string(),
+            ]
+        );
+    }
+}
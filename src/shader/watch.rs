@@ -0,0 +1,82 @@
+use crate::shader::PipeToken;
+use crate::Result;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+/// Watches shader sources for changes, to pass to `SwapToken::reload_shaders` between frames.
+pub struct ShaderWatcher {
+    watcher: RecommendedWatcher,
+    events: Receiver<DebouncedEvent>,
+    /// Canonical paths of the watched files.
+    files: HashSet<PathBuf>,
+    dirs: HashSet<PathBuf>,
+}
+
+impl std::fmt::Debug for ShaderWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ShaderWatcher")
+            .field("files", &self.files)
+            .finish()
+    }
+}
+
+impl ShaderWatcher {
+    /// Changes are reported once a file has been left alone for `delay`, so editors that save
+    /// in several steps only trigger one reload.
+    pub fn new(delay: Duration) -> Result<Self> {
+        let (tx, events) = channel();
+        Ok(Self {
+            watcher: notify::watcher(tx, delay)?,
+            events,
+            files: HashSet::new(),
+            dirs: HashSet::new(),
+        })
+    }
+
+    pub fn watch<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = fs::canonicalize(path)?;
+        // Watching the directory keeps working when an editor saves by replacing the file
+        if let Some(dir) = path.parent() {
+            if !self.dirs.contains(dir) {
+                self.watcher.watch(dir, RecursiveMode::NonRecursive)?;
+                self.dirs.insert(dir.to_path_buf());
+            }
+        }
+        self.files.insert(path);
+        Ok(())
+    }
+
+    /// Watches every source file of `pipe`.
+    pub fn watch_pipe(&mut self, pipe: &PipeToken) -> Result<()> {
+        for source in pipe.sources() {
+            self.watch(source)?;
+        }
+        Ok(())
+    }
+
+    /// The watched files that changed since the last call, without blocking.
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        let mut res = Vec::new();
+        for event in self.events.try_iter() {
+            let path = match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Rename(_, path) => path,
+                DebouncedEvent::Error(e, path) => {
+                    log::warn!("Shader watcher error on {:?}: {}", path, e);
+                    continue;
+                }
+                _ => continue,
+            };
+            let path = fs::canonicalize(&path).unwrap_or(path);
+            if self.files.contains(&path) && !res.contains(&path) {
+                res.push(path);
+            }
+        }
+        res
+    }
+}
@@ -0,0 +1,313 @@
+use crate::command::CmdPool;
+use crate::debug::{DebugName, DebugNamer};
+use crate::Result;
+use ash::{version::DeviceV1_0, vk, Device};
+
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+/// What one in-flight frame records into and synchronizes on.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub cmd_buf: vk::CommandBuffer,
+    /// Signaled when the swapchain image acquired for this frame is ready.
+    pub image_available: vk::Semaphore,
+    /// Signaled when this frame's commands finish; wait on it before presenting.
+    pub render_finished: vk::Semaphore,
+    /// Signaled when this frame's submission completes, so its resources can be reused.
+    pub in_flight: vk::Fence,
+}
+
+/// A ring of `Frame`s so the CPU can record one frame while the GPU works on earlier ones.
+///
+/// Per frame: `wait`, acquire an image with `current().image_available`, `record`, `submit`,
+/// present waiting on `current().render_finished`, then `advance`.
+pub struct FrameSync {
+    dev: Device,
+    cmd_pool: CmdPool,
+    frames: Vec<Frame>,
+    index: usize,
+}
+
+impl Drop for FrameSync {
+    fn drop(&mut self) {
+        eprintln!("Dropping FrameSync");
+        unsafe {
+            self.dev.device_wait_idle().unwrap();
+            for frame in self.frames.drain(0..) {
+                self.dev.destroy_semaphore(frame.image_available, None);
+                self.dev.destroy_semaphore(frame.render_finished, None);
+                self.dev.destroy_fence(frame.in_flight, None);
+            }
+        }
+    }
+}
+
+impl DebugName for FrameSync {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        self.cmd_pool.set_name(namer, name);
+        for (i, frame) in self.frames.iter().enumerate() {
+            namer.name(
+                frame.image_available,
+                &format!("{}[{}] image available", name, i),
+            );
+            namer.name(
+                frame.render_finished,
+                &format!("{}[{}] render finished", name, i),
+            );
+            namer.name(frame.in_flight, &format!("{}[{}] in flight", name, i));
+        }
+    }
+}
+
+impl FrameSync {
+    pub fn new(dev: Device, queue_fam: u32, frames_in_flight: usize) -> Result<Self> {
+        let frames_in_flight = std::cmp::max(frames_in_flight, 1);
+        let mut res = Self {
+            cmd_pool: CmdPool::new(dev.clone(), queue_fam, frames_in_flight as u32)?,
+            dev,
+            frames: Vec::with_capacity(frames_in_flight),
+            index: 0,
+        };
+        for &cmd_buf in &res.cmd_pool.buffers {
+            unsafe {
+                let image_available = res
+                    .dev
+                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+                let render_finished = match res
+                    .dev
+                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
+                {
+                    Ok(s) => s,
+                    Err(e) => {
+                        res.dev.destroy_semaphore(image_available, None);
+                        return Err(e.into());
+                    }
+                };
+                // Starts signaled so the first wait on each frame returns immediately
+                let info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+                let in_flight = match res.dev.create_fence(&info, None) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        res.dev.destroy_semaphore(image_available, None);
+                        res.dev.destroy_semaphore(render_finished, None);
+                        return Err(e.into());
+                    }
+                };
+                res.frames.push(Frame {
+                    cmd_buf,
+                    image_available,
+                    render_finished,
+                    in_flight,
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The slot being recorded, in `0..frames_in_flight()`. Use it to pick per-frame resources
+    /// such as uniform buffers.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn current(&self) -> &Frame {
+        &self.frames[self.index]
+    }
+
+    /// Blocks until the GPU is done with the current slot's previous submission.
+    pub fn wait(&self) -> Result<()> {
+        unsafe {
+            self.dev
+                .wait_for_fences(&[self.current().in_flight], true, std::u64::MAX)?;
+        }
+        Ok(())
+    }
+
+    /// Records the current slot's command buffer. Call `wait` first.
+    pub fn record<F: FnOnce(&Device, vk::CommandBuffer)>(&self, f: F) -> Result<()> {
+        self.cmd_pool
+            .record(self.index, vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT, f)
+    }
+
+    /// Submits the current slot's command buffer without blocking. It waits on
+    /// `image_available` at `wait_stage` and signals `render_finished` and `in_flight`. If the
+    /// submit fails, the slot is abandoned as with `abandon`, so `wait` doesn't block on it
+    /// forever.
+    pub fn submit(&self, queue: vk::Queue, wait_stage: vk::PipelineStageFlags) -> Result<()> {
+        let frame = self.current();
+        unsafe {
+            self.dev.reset_fences(&[frame.in_flight])?;
+        }
+        let res = self.cmd_pool.submit_with_fence(
+            queue,
+            &[wait_stage],
+            &[frame.image_available],
+            &[frame.render_finished],
+            &[self.index],
+            frame.in_flight,
+        );
+        if res.is_err() {
+            // A failed submit leaves the semaphore signaled and the fence unsignaled; if this
+            // fails too, the device is most likely lost and the first error says more
+            let _ = self.abandon(queue);
+        }
+        res
+    }
+
+    /// Gives up on the current slot's frame after an image was acquired with its
+    /// `image_available` but before `submit`, e.g. because recording failed. The command buffer
+    /// is reset and an empty batch waits on the semaphore, so the slot can be used again after
+    /// `wait`.
+    pub fn abandon(&self, queue: vk::Queue) -> Result<()> {
+        let frame = self.current();
+        unsafe {
+            self.dev
+                .reset_command_buffer(frame.cmd_buf, vk::CommandBufferResetFlags::empty())?;
+            self.dev.reset_fences(&[frame.in_flight])?;
+        }
+        self.cmd_pool.submit_with_fence(
+            queue,
+            &[vk::PipelineStageFlags::TOP_OF_PIPE],
+            &[frame.image_available],
+            &[],
+            &[],
+            frame.in_flight,
+        )
+    }
+
+    /// Moves on to the next slot.
+    pub fn advance(&mut self) {
+        self.index = (self.index + 1) % self.frames.len();
+    }
+}
+
+/// One `vkCmdPipelineBarrier`, for ordering work within a command buffer, e.g. a compute pass
+/// writing a buffer that a draw then reads.
+#[derive(Debug, Clone, Default)]
+pub struct Barrier {
+    pub src_stages: vk::PipelineStageFlags,
+    pub dst_stages: vk::PipelineStageFlags,
+    pub memory: Vec<vk::MemoryBarrier>,
+    pub buffers: Vec<vk::BufferMemoryBarrier>,
+    pub images: Vec<vk::ImageMemoryBarrier>,
+}
+
+impl Barrier {
+    /// Work in `dst_stages` after the barrier waits for work in `src_stages` before it.
+    pub fn new(src_stages: vk::PipelineStageFlags, dst_stages: vk::PipelineStageFlags) -> Self {
+        Self {
+            src_stages,
+            dst_stages,
+            ..Default::default()
+        }
+    }
+
+    /// Compute shader writes, read by later draws as vertices, indices, indirect arguments or
+    /// in shaders.
+    pub fn compute_to_graphics() -> Self {
+        Self::new(
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::DRAW_INDIRECT
+                | vk::PipelineStageFlags::VERTEX_INPUT
+                | vk::PipelineStageFlags::VERTEX_SHADER
+                | vk::PipelineStageFlags::FRAGMENT_SHADER,
+        )
+    }
+
+    /// Draws' writes, read by a later compute shader.
+    pub fn graphics_to_compute() -> Self {
+        Self::new(
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+        )
+    }
+
+    /// One compute shader's writes, read by the next dispatch.
+    pub fn compute_to_compute() -> Self {
+        Self::new(
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+        )
+    }
+
+    /// Covers every resource; simpler, if coarser, than listing buffers.
+    pub fn memory(mut self, src_access: vk::AccessFlags, dst_access: vk::AccessFlags) -> Self {
+        self.memory.push(
+            vk::MemoryBarrier::builder()
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .build(),
+        );
+        self
+    }
+
+    /// The whole of `buffer`.
+    pub fn buffer(
+        mut self,
+        buffer: vk::Buffer,
+        src_access: vk::AccessFlags,
+        dst_access: vk::AccessFlags,
+    ) -> Self {
+        self.buffers.push(
+            vk::BufferMemoryBarrier::builder()
+                .buffer(buffer)
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .size(vk::WHOLE_SIZE)
+                .build(),
+        );
+        self
+    }
+
+    /// Every mip level and layer of `image`, moved from `old_layout` to `new_layout`.
+    pub fn image(
+        mut self,
+        image: vk::Image,
+        aspect: vk::ImageAspectFlags,
+        layouts: (vk::ImageLayout, vk::ImageLayout),
+        src_access: vk::AccessFlags,
+        dst_access: vk::AccessFlags,
+    ) -> Self {
+        self.images.push(
+            vk::ImageMemoryBarrier::builder()
+                .image(image)
+                .old_layout(layouts.0)
+                .new_layout(layouts.1)
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .subresource_range(
+                    vk::ImageSubresourceRange::builder()
+                        .aspect_mask(aspect)
+                        .level_count(vk::REMAINING_MIP_LEVELS)
+                        .layer_count(vk::REMAINING_ARRAY_LAYERS)
+                        .build(),
+                )
+                .build(),
+        );
+        self
+    }
+
+    pub fn record(&self, dev: &Device, cmd_buf: vk::CommandBuffer) {
+        unsafe {
+            dev.cmd_pipeline_barrier(
+                cmd_buf,
+                self.src_stages,
+                self.dst_stages,
+                vk::DependencyFlags::empty(),
+                &self.memory,
+                &self.buffers,
+                &self.images,
+            );
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::buffer::*;
+use crate::debug::{DebugName, DebugNamer};
 use crate::*;
 use ash::{version::DeviceV1_0, vk, Device};
 
@@ -20,6 +21,14 @@ impl Drop for Texture {
     }
 }
 
+impl DebugName for Texture {
+    fn set_name(&self, namer: &DebugNamer, name: &str) {
+        namer.name(self.image, name);
+        namer.name(self.view, &format!("{} view", name));
+        namer.name(self.mem, &format!("{} memory", name));
+    }
+}
+
 impl Texture {
     pub fn from_path(
         dev: Device,
@@ -27,8 +36,8 @@ impl Texture {
         cmd_buf: vk::CommandBuffer,
         present_queue: vk::Queue,
         path: &str,
-    ) -> Self {
-        let image = image::open(path).unwrap().to_rgba();
+    ) -> Result<Self> {
+        let image = image::open(path)?.to_rgba();
         let dims = image.dimensions();
         let data = image.into_raw();
         let img_buf = BufToken::with_data(
@@ -37,7 +46,7 @@ impl Texture {
             vk::SharingMode::EXCLUSIVE,
             &mem_prop,
             &data,
-        );
+        )?;
         Self::from_buffer(dev, img_buf, mem_prop, cmd_buf, present_queue, dims)
     }
 
@@ -48,9 +57,16 @@ impl Texture {
         cmd_buf: vk::CommandBuffer,
         present_queue: vk::Queue,
         dims: (u32, u32),
-    ) -> Self {
+    ) -> Result<Self> {
         let format = vk::Format::R8G8B8A8_UNORM;
 
+        // Filled in as each part is created, so an error partway through drops what exists
+        let mut res = Self {
+            dev: dev.clone(),
+            mem: vk::DeviceMemory::null(),
+            image: vk::Image::null(),
+            view: vk::ImageView::null(),
+        };
         let texture_image = unsafe {
             let texture_create_info = vk::ImageCreateInfo {
                 image_type: vk::ImageType::TYPE_2D,
@@ -68,28 +84,27 @@ impl Texture {
                 sharing_mode: vk::SharingMode::EXCLUSIVE,
                 ..Default::default()
             };
-            dev.create_image(&texture_create_info, None).unwrap()
+            dev.create_image(&texture_create_info, None)?
         };
+        res.image = texture_image;
         let texture_memory = unsafe {
             let texture_allocate_info = {
                 let texture_memory_req = dev.get_image_memory_requirements(texture_image);
-                let texture_memory_index = find_memorytype_index(
-                    &texture_memory_req,
-                    mem_prop,
-                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
-                )
-                .unwrap();
+                let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+                let texture_memory_index =
+                    find_memorytype_index(&texture_memory_req, mem_prop, flags)
+                        .ok_or(Error::NoSuitableMemoryType(flags))?;
                 vk::MemoryAllocateInfo {
                     allocation_size: texture_memory_req.size,
                     memory_type_index: texture_memory_index,
                     ..Default::default()
                 }
             };
-            dev.allocate_memory(&texture_allocate_info, None).unwrap()
+            dev.allocate_memory(&texture_allocate_info, None)?
         };
+        res.mem = texture_memory;
         unsafe {
-            dev.bind_image_memory(texture_image, texture_memory, 0)
-                .unwrap();
+            dev.bind_image_memory(texture_image, texture_memory, 0)?;
             crate::command::record_submit_commandbuffer(
                 &dev,
                 cmd_buf,
@@ -163,34 +178,30 @@ impl Texture {
                         &[texture_barrier_end],
                     );
                 },
-            )
+            )?
         };
-        Self {
-            mem: texture_memory,
-            image: texture_image,
-            view: unsafe {
-                let tex_image_view_info = vk::ImageViewCreateInfo {
-                    view_type: vk::ImageViewType::TYPE_2D,
-                    format,
-                    components: vk::ComponentMapping {
-                        r: vk::ComponentSwizzle::R,
-                        g: vk::ComponentSwizzle::G,
-                        b: vk::ComponentSwizzle::B,
-                        a: vk::ComponentSwizzle::A,
-                    },
-                    subresource_range: vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        level_count: 1,
-                        layer_count: 1,
-                        ..Default::default()
-                    },
-                    image: texture_image,
+        res.view = unsafe {
+            let tex_image_view_info = vk::ImageViewCreateInfo {
+                view_type: vk::ImageViewType::TYPE_2D,
+                format,
+                components: vk::ComponentMapping {
+                    r: vk::ComponentSwizzle::R,
+                    g: vk::ComponentSwizzle::G,
+                    b: vk::ComponentSwizzle::B,
+                    a: vk::ComponentSwizzle::A,
+                },
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    level_count: 1,
+                    layer_count: 1,
                     ..Default::default()
-                };
-                dev.create_image_view(&tex_image_view_info, None).unwrap()
-            },
-            dev,
-        }
+                },
+                image: texture_image,
+                ..Default::default()
+            };
+            dev.create_image_view(&tex_image_view_info, None)?
+        };
+        Ok(res)
     }
 
     pub fn tex_info(&self, sampler: vk::Sampler) -> vk::DescriptorImageInfo {
@@ -1,22 +1,173 @@
+use crate::shader::StageInput;
 use ash::vk;
-use lightcycle::na::{Matrix4, Point2, Point3, Vector2};
-use std::mem;
+use lightcycle::na::{Matrix4, Point2, Point3, Vector2, Vector3, Vector4};
 use std::ops::Add;
 use std::ops::Mul;
 
 pub mod model;
 
+/// Usually derived; see `flint::VertInfo`.
 pub trait VertInfo {
     fn bind_descs() -> Vec<vk::VertexInputBindingDescription>;
     fn attr_descs() -> Vec<vk::VertexInputAttributeDescription>;
     fn input_state_info(
         attr: &[vk::VertexInputAttributeDescription],
         bind: &[vk::VertexInputBindingDescription],
-    ) -> vk::PipelineVertexInputStateCreateInfo;
-    fn asm_state_info() -> vk::PipelineInputAssemblyStateCreateInfo;
+    ) -> vk::PipelineVertexInputStateCreateInfo {
+        vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_attribute_descriptions(attr)
+            .vertex_binding_descriptions(bind)
+            .build()
+    }
+    fn asm_state_info() -> vk::PipelineInputAssemblyStateCreateInfo {
+        vk::PipelineInputAssemblyStateCreateInfo {
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            ..Default::default()
+        }
+    }
+}
+
+/// The format a field of this type has as a vertex attribute, for `#[derive(VertInfo)]`.
+pub trait VertexFormat {
+    const FORMAT: vk::Format;
+}
+
+macro_rules! vertex_formats {
+    ($($ty:ty => $format:ident),* $(,)?) => {
+        $(impl VertexFormat for $ty {
+            const FORMAT: vk::Format = vk::Format::$format;
+        })*
+    };
+}
+
+vertex_formats! {
+    f32 => R32_SFLOAT,
+    [f32; 2] => R32G32_SFLOAT,
+    [f32; 3] => R32G32B32_SFLOAT,
+    [f32; 4] => R32G32B32A32_SFLOAT,
+    Point2<f32> => R32G32_SFLOAT,
+    Point3<f32> => R32G32B32_SFLOAT,
+    Vector2<f32> => R32G32_SFLOAT,
+    Vector3<f32> => R32G32B32_SFLOAT,
+    Vector4<f32> => R32G32B32A32_SFLOAT,
+    u32 => R32_UINT,
+    [u32; 2] => R32G32_UINT,
+    [u32; 3] => R32G32B32_UINT,
+    [u32; 4] => R32G32B32A32_UINT,
+    i32 => R32_SINT,
+    [i32; 2] => R32G32_SINT,
+    [i32; 3] => R32G32B32_SINT,
+    [i32; 4] => R32G32B32A32_SINT,
+    // Bytes are most often colors, so they're read normalized to 0..1
+    [u8; 2] => R8G8_UNORM,
+    [u8; 4] => R8G8B8A8_UNORM,
+    [u16; 2] => R16G16_UNORM,
+    [u16; 4] => R16G16B16A16_UNORM,
+}
+
+/// The vertex buffer bindings and attributes a pipeline reads, for when they aren't known
+/// until runtime or come from more than one `VertInfo` type.
+#[derive(Debug, Clone, Default)]
+pub struct VertexLayout {
+    pub bindings: Vec<vk::VertexInputBindingDescription>,
+    pub attributes: Vec<vk::VertexInputAttributeDescription>,
+}
+
+impl VertexLayout {
+    /// No vertex input, e.g. for a fullscreen triangle made in the vertex shader.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn of<V: VertInfo>() -> Self {
+        Self {
+            bindings: V::bind_descs(),
+            attributes: V::attr_descs(),
+        }
+    }
+
+    pub fn binding(mut self, binding: u32, stride: u32, input_rate: vk::VertexInputRate) -> Self {
+        self.bindings.push(vk::VertexInputBindingDescription {
+            binding,
+            stride,
+            input_rate,
+        });
+        self
+    }
+
+    pub fn attribute(
+        mut self,
+        location: u32,
+        binding: u32,
+        format: vk::Format,
+        offset: u32,
+    ) -> Self {
+        self.attributes.push(vk::VertexInputAttributeDescription {
+            location,
+            binding,
+            format,
+            offset,
+        });
+        self
+    }
+
+    /// Adds `V`'s single binding as `binding`, stepping at `input_rate`. Its attribute
+    /// locations follow on from the ones already declared, so per-instance data declared after
+    /// a two-attribute vertex starts at location 2.
+    ///
+    /// Panics if `V` doesn't have exactly one binding. Derived `VertInfo` always does, so this
+    /// only catches hand-written impls.
+    pub fn append<V: VertInfo>(mut self, binding: u32, input_rate: vk::VertexInputRate) -> Self {
+        let base = self
+            .attributes
+            .iter()
+            .map(|a| a.location + 1)
+            .max()
+            .unwrap_or(0);
+        let binds = V::bind_descs();
+        assert!(
+            binds.len() == 1,
+            "Appended vertex types need exactly one binding"
+        );
+        self.bindings.push(vk::VertexInputBindingDescription {
+            binding,
+            input_rate,
+            ..binds[0]
+        });
+        self.attributes.extend(V::attr_descs().into_iter().map(|a| {
+            vk::VertexInputAttributeDescription {
+                location: base + a.location,
+                binding,
+                ..a
+            }
+        }));
+        self
+    }
+
+    /// Tightly packs the inputs a vertex shader reads into binding 0, in location order. See
+    /// `ShaderArtifact::inputs`.
+    pub fn from_inputs(inputs: &[StageInput]) -> Self {
+        let mut inputs = inputs.to_vec();
+        inputs.sort_by_key(|i| i.location);
+        let mut res = Self::new();
+        let mut offset = 0;
+        for input in inputs {
+            res = res.attribute(input.location, 0, input.format, offset);
+            offset += input.size;
+        }
+        res.binding(0, offset, vk::VertexInputRate::VERTEX)
+    }
+
+    /// Borrows the descriptions, so keep `self` alive while the result is used.
+    pub fn input_state_info(&self) -> vk::PipelineVertexInputStateCreateInfo {
+        vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_attribute_descriptions(&self.attributes)
+            .vertex_binding_descriptions(&self.bindings)
+            .build()
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, crate::VertInfo)]
 pub struct Vertex {
     pos: Point3<f32>,
     uv: Point2<f32>,
@@ -53,53 +204,6 @@ impl From<[f32; 5]> for Vertex {
 //     uv: [f32; 2],
 // }
 
-impl VertInfo for Vertex {
-    fn bind_descs() -> Vec<vk::VertexInputBindingDescription> {
-        vec![vk::VertexInputBindingDescription {
-            binding: 0,
-            stride: std::mem::size_of::<Self>() as u32,
-            input_rate: vk::VertexInputRate::VERTEX,
-        }]
-    }
-
-    fn attr_descs() -> Vec<vk::VertexInputAttributeDescription> {
-        vec![
-            vk::VertexInputAttributeDescription {
-                location: 0,
-                binding: 0,
-                // Format looks like a color, but it's actually a
-                // description of how the attribute is formatted.
-                // R32G32B32_SFLOAT because it's a Vec3 of f32
-                format: vk::Format::R32G32B32_SFLOAT,
-                offset: offset_of!(Self, pos) as u32,
-            },
-            vk::VertexInputAttributeDescription {
-                location: 1,
-                binding: 0,
-                format: vk::Format::R32G32_SFLOAT,
-                offset: offset_of!(Self, uv) as u32,
-            },
-        ]
-    }
-
-    fn input_state_info(
-        attr: &[vk::VertexInputAttributeDescription],
-        bind: &[vk::VertexInputBindingDescription],
-    ) -> vk::PipelineVertexInputStateCreateInfo {
-        vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_attribute_descriptions(attr)
-            .vertex_binding_descriptions(bind)
-            .build()
-    }
-
-    fn asm_state_info() -> vk::PipelineInputAssemblyStateCreateInfo {
-        vk::PipelineInputAssemblyStateCreateInfo {
-            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
-            ..Default::default()
-        }
-    }
-}
-
 #[rustfmt::skip]
 pub fn quad() -> [Vertex; 4] {
     [
@@ -115,3 +219,52 @@ pub const QUAD_INDICES: [u16; 6] = [
     0, 1, 2,
     2, 3, 0
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VertInfo;
+
+    #[repr(C)]
+    #[derive(VertInfo)]
+    struct Derived {
+        pos: [f32; 3],
+        #[vertex(skip)]
+        _pad: f32,
+        #[vertex(location = 3, format = "R8G8B8A8_SRGB")]
+        color: [u8; 4],
+        uv: [f32; 2],
+    }
+
+    #[test]
+    fn derive_follows_field_options() {
+        let binds = Derived::bind_descs();
+        assert_eq!(binds.len(), 1);
+        assert_eq!(binds[0].stride as usize, std::mem::size_of::<Derived>());
+        let attrs = Derived::attr_descs()
+            .into_iter()
+            .map(|a| (a.location, a.binding, a.format, a.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            attrs,
+            vec![
+                (0, 0, vk::Format::R32G32B32_SFLOAT, 0),
+                (3, 0, vk::Format::R8G8B8A8_SRGB, 16),
+                (4, 0, vk::Format::R32G32_SFLOAT, 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn append_follows_on_from_earlier_locations() {
+        let layout = VertexLayout::new()
+            .append::<Derived>(0, vk::VertexInputRate::VERTEX)
+            .append::<Derived>(1, vk::VertexInputRate::INSTANCE);
+        assert_eq!(layout.bindings[1].input_rate, vk::VertexInputRate::INSTANCE);
+        let second = layout.attributes[3..]
+            .iter()
+            .map(|a| (a.location, a.binding))
+            .collect::<Vec<_>>();
+        assert_eq!(second, vec![(5, 1), (8, 1), (9, 1)]);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::buffer::BufToken;
 use crate::vertex::Vertex;
+use crate::{Error, Result};
 use ash::version::DeviceV1_0;
 use ash::vk;
 use ash::Device;
@@ -7,8 +8,10 @@ use lightcycle::{na, volume::polyhedron::*};
 use na::{Point2, Point3};
 use std::path::Path;
 
-pub struct Model {
-    pub shape: Polyhedron<Vertex>,
+/// An indexed mesh on the GPU, with vertices of any `Copy` type; draw it with a pipeline whose
+/// `PipelineDesc::vertices` matches.
+pub struct Model<V = Vertex> {
+    pub shape: Polyhedron<V>,
     pub vert_buf: BufToken,
     pub ind_buf: BufToken,
 }
@@ -22,18 +25,18 @@ impl From<collada::Vertex> for Vertex {
     }
 }
 
-impl Model {
+impl<V: Copy> Model<V> {
     pub fn new(
         dev: Device,
         mem_prop: &vk::PhysicalDeviceMemoryProperties,
-        shape: Polyhedron<Vertex>,
-    ) -> Self {
-        let (vert_buf, ind_buf) = Self::hedron_buffers(dev, mem_prop, &shape);
-        Self {
+        shape: Polyhedron<V>,
+    ) -> Result<Self> {
+        let (vert_buf, ind_buf) = Self::hedron_buffers(dev, mem_prop, &shape)?;
+        Ok(Self {
             shape,
             vert_buf,
             ind_buf,
-        }
+        })
     }
 
     pub unsafe fn draw(&self, cmd_buffer: vk::CommandBuffer) {
@@ -43,10 +46,72 @@ impl Model {
         dev.cmd_draw_indexed(cmd_buffer, (self.shape.faces.len() * 3) as u32, 1, 0, 0, 1);
     }
 
-    pub fn load(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties, path: &Path) -> Self {
+    /// Draws `count` instances, with per-instance data bound at `instance_binding`.
+    pub unsafe fn draw_instanced(
+        &self,
+        cmd_buffer: vk::CommandBuffer,
+        instance_binding: u32,
+        instances: &BufToken,
+        count: u32,
+    ) {
+        let dev = &self.vert_buf.dev;
+        dev.cmd_bind_vertex_buffers(cmd_buffer, 0, &[self.vert_buf.buf], &[0]);
+        dev.cmd_bind_vertex_buffers(cmd_buffer, instance_binding, &[instances.buf], &[0]);
+        dev.cmd_bind_index_buffer(cmd_buffer, self.ind_buf.buf, 0, vk::IndexType::UINT16);
+        dev.cmd_draw_indexed(
+            cmd_buffer,
+            (self.shape.faces.len() * 3) as u32,
+            count,
+            0,
+            0,
+            0,
+        );
+    }
+
+    fn hedron_buffers(
+        dev: Device,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        hedron: &Polyhedron<V>,
+    ) -> Result<(BufToken, BufToken)> {
+        Ok((
+            // Vertex Buffer
+            BufToken::with_data(
+                dev.clone(),
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+                vk::SharingMode::EXCLUSIVE,
+                mem_prop,
+                &hedron.points,
+            )?,
+            // Index
+            BufToken::with_data(
+                dev,
+                vk::BufferUsageFlags::INDEX_BUFFER,
+                vk::SharingMode::EXCLUSIVE,
+                mem_prop,
+                &hedron.faces,
+            )?,
+        ))
+    }
+}
+
+impl Model<Vertex> {
+    pub fn load(
+        dev: Device,
+        mem_prop: &vk::PhysicalDeviceMemoryProperties,
+        path: &Path,
+    ) -> Result<Self> {
         use collada::{document, PrimitiveElement};
-        let doc = document::ColladaDocument::from_path(path).unwrap();
-        let mut obj = doc.get_obj_set().unwrap().objects.remove(0);
+        let missing = |what: &str| Error::Model(format!("{}: no {}", path.display(), what));
+        let doc = document::ColladaDocument::from_path(path)
+            .map_err(|e| Error::Model(format!("{}: {}", path.display(), e)))?;
+        let mut objects = doc
+            .get_obj_set()
+            .ok_or_else(|| missing("object set"))?
+            .objects;
+        if objects.is_empty() {
+            return Err(missing("objects"));
+        }
+        let mut obj = objects.remove(0);
         let mut points: Vec<Vertex> = obj
             .vertices
             .drain(0..)
@@ -57,14 +122,40 @@ impl Model {
             .drain(0..)
             .map(|tex| [tex.x as _, tex.y as _])
             .collect();
-        let faces: Vec<[u16; 3]> = match obj.geometry.remove(0).mesh.remove(0) {
-            PrimitiveElement::Polylist(_) => panic!("Not Implemented"),
+        let mesh = obj
+            .geometry
+            .first_mut()
+            .and_then(|g| {
+                if g.mesh.is_empty() {
+                    None
+                } else {
+                    Some(g.mesh.remove(0))
+                }
+            })
+            .ok_or_else(|| missing("mesh"))?;
+        // Every index is checked against `points` below, so this keeps them all in u16's range
+        if points.len() > u16::max_value() as usize + 1 {
+            return Err(Error::Model(format!(
+                "{}: too many vertices for u16 indices",
+                path.display()
+            )));
+        }
+        let faces: Vec<[u16; 3]> = match mesh {
+            PrimitiveElement::Polylist(_) => {
+                return Err(Error::Unsupported(format!(
+                    "{}: polylist primitives",
+                    path.display()
+                )))
+            }
             PrimitiveElement::Triangles(mut t) => {
                 let mut res = Vec::new();
                 for (a, b, c) in t.vertices.drain(0..) {
                     for (vert, tex, _) in &[&a, &b, &c] {
+                        let out_of_range =
+                            || Error::Model(format!("{}: index out of range", path.display()));
+                        let point = points.get_mut(*vert).ok_or_else(out_of_range)?;
                         if let Some(i) = tex {
-                            points[*vert].uv = uv[*i].into();
+                            point.uv = (*uv.get(*i).ok_or_else(out_of_range)?).into();
                         }
                     }
                     res.push([a.0 as _, b.0 as _, c.0 as _]);
@@ -74,8 +165,11 @@ impl Model {
         };
         Self::new(dev, mem_prop, Polyhedron { points, faces })
     }
+}
 
-    pub fn cube(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Self {
+/// Built-in shapes, for vertex types made from `[x, y, z, u, v]`.
+impl<V: Copy + From<[f32; 5]>> Model<V> {
+    pub fn cube(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Result<Self> {
         let shape = Polyhedron {
             points: vec![
                 // back
@@ -110,15 +204,10 @@ impl Model {
                 [3, 6, 5],
             ],
         };
-        let (vert_buf, ind_buf) = Self::hedron_buffers(dev, mem_prop, &shape);
-        Self {
-            shape,
-            vert_buf,
-            ind_buf,
-        }
+        Self::new(dev, mem_prop, shape)
     }
 
-    pub fn quad(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Self {
+    pub fn quad(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Result<Self> {
         let shape = Polyhedron {
             points: vec![
                 [0.5, 0.5, -0.5, 0.044, 0.0].into(),
@@ -128,15 +217,10 @@ impl Model {
             ],
             faces: vec![[0, 1, 2], [2, 3, 0]],
         };
-        let (vert_buf, ind_buf) = Self::hedron_buffers(dev, mem_prop, &shape);
-        Self {
-            shape,
-            vert_buf,
-            ind_buf,
-        }
+        Self::new(dev, mem_prop, shape)
     }
 
-    pub fn tri(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Self {
+    pub fn tri(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Result<Self> {
         let shape = Polyhedron {
             points: vec![
                 [0.5, -0.5, -0.5, 0.044, 0.0].into(),
@@ -145,15 +229,10 @@ impl Model {
             ],
             faces: vec![[0, 1, 2]],
         };
-        let (vert_buf, ind_buf) = Self::hedron_buffers(dev, mem_prop, &shape);
-        Self {
-            shape,
-            vert_buf,
-            ind_buf,
-        }
+        Self::new(dev, mem_prop, shape)
     }
 
-    pub fn ramp_q(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Self {
+    pub fn ramp_q(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Result<Self> {
         let shape = Polyhedron {
             points: vec![
                 [0.5, 0.5, -0.5, 0.044, 0.0].into(),
@@ -163,15 +242,10 @@ impl Model {
             ],
             faces: vec![[0, 1, 2], [2, 3, 0]],
         };
-        let (vert_buf, ind_buf) = Self::hedron_buffers(dev, mem_prop, &shape);
-        Self {
-            shape,
-            vert_buf,
-            ind_buf,
-        }
+        Self::new(dev, mem_prop, shape)
     }
 
-    pub fn ramp_t(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Self {
+    pub fn ramp_t(dev: Device, mem_prop: &vk::PhysicalDeviceMemoryProperties) -> Result<Self> {
         let shape = Polyhedron {
             points: vec![
                 [0.5, 0.5, -0.5, 0.044, 0.0].into(),
@@ -180,36 +254,6 @@ impl Model {
             ],
             faces: vec![[0, 1, 2]],
         };
-        let (vert_buf, ind_buf) = Self::hedron_buffers(dev, mem_prop, &shape);
-        Self {
-            shape,
-            vert_buf,
-            ind_buf,
-        }
-    }
-
-    fn hedron_buffers(
-        dev: Device,
-        mem_prop: &vk::PhysicalDeviceMemoryProperties,
-        hedron: &Polyhedron<Vertex>,
-    ) -> (BufToken, BufToken) {
-        (
-            // Vertex Buffer
-            BufToken::with_data(
-                dev.clone(),
-                vk::BufferUsageFlags::VERTEX_BUFFER,
-                vk::SharingMode::EXCLUSIVE,
-                mem_prop,
-                &hedron.points,
-            ),
-            // Index
-            BufToken::with_data(
-                dev,
-                vk::BufferUsageFlags::INDEX_BUFFER,
-                vk::SharingMode::EXCLUSIVE,
-                mem_prop,
-                &hedron.faces,
-            ),
-        )
+        Self::new(dev, mem_prop, shape)
     }
 }